@@ -0,0 +1,200 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address block such as `192.0.2.0/24` or `2001:db8::/32`. A bare
+/// address is a block of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    pub address: IpAddr,
+    pub len: u8,
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, len) = match value.split_once('/') {
+            Some((address, len)) => (address, Some(len)),
+            None => (value, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid address {address}"))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {value}"))?,
+            None => max_len,
+        };
+
+        Ok(Prefix { address, len })
+    }
+}
+
+impl Prefix {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // clients reaching a dual stack socket over IPv4 show up as ::ffff:a.b.c.d
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(prefix), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(prefix) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(prefix) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Who may do something: the clients in an address block, or whoever signs
+/// their requests with a TSIG key, written `key:<name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Grantee {
+    Clients(Prefix),
+    Key(String),
+}
+
+impl FromStr for Grantee {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("key:") {
+            Some(name) => Ok(Grantee::Key(
+                name.trim_end_matches('.').to_ascii_lowercase(),
+            )),
+            None => Ok(Grantee::Clients(value.parse()?)),
+        }
+    }
+}
+
+impl Grantee {
+    /// Whether a request from `client`, signed with the key named `key` if
+    /// any, is covered.
+    pub fn allows(&self, client: IpAddr, key: Option<&str>) -> bool {
+        match self {
+            Grantee::Clients(prefix) => prefix.contains(client),
+            Grantee::Key(name) => key == Some(name.as_str()),
+        }
+    }
+}
+
+/// What clients turned away get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeniedResponse {
+    Refused,
+    /// Nothing at all, which tells scanners less.
+    Drop,
+}
+
+impl FromStr for DeniedResponse {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "refused" => Ok(DeniedResponse::Refused),
+            "drop" => Ok(DeniedResponse::Drop),
+            _ => Err(format!("expected refused or drop, got {value}")),
+        }
+    }
+}
+
+/// Who may use the server, anyone when a list isn't given. Transfers and
+/// updates have the lists of their zones.
+#[derive(Debug, Clone)]
+pub struct Acls {
+    /// Who may query our zones and hosts.
+    pub query: Option<Vec<Grantee>>,
+    /// Who gets the rest answered by the upstreams.
+    pub recursion: Option<Vec<Grantee>>,
+    pub denied_response: DeniedResponse,
+}
+
+impl Acls {
+    pub fn allows_query(&self, client: IpAddr, key: Option<&str>) -> bool {
+        allows(&self.query, client, key)
+    }
+
+    pub fn allows_recursion(&self, client: IpAddr, key: Option<&str>) -> bool {
+        allows(&self.query, client, key) && allows(&self.recursion, client, key)
+    }
+}
+
+fn allows(grantees: &Option<Vec<Grantee>>, client: IpAddr, key: Option<&str>) -> bool {
+    grantees.as_ref().map_or(true, |grantees| {
+        grantees.iter().any(|g| g.allows(client, key))
+    })
+}
+
+/// Parses a comma separated list of prefixes and `key:<name>` entries.
+pub fn parse_grantees(value: &str) -> Result<Vec<Grantee>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|grantee| !grantee.is_empty())
+        .map(Grantee::from_str)
+        .collect()
+}
+
+#[test]
+fn test_prefix_contains() {
+    let prefix: Prefix = "192.0.2.0/24".parse().unwrap();
+    assert!(prefix.contains("192.0.2.77".parse().unwrap()));
+    assert!(prefix.contains("::ffff:192.0.2.1".parse().unwrap()));
+    assert!(!prefix.contains("192.0.3.1".parse().unwrap()));
+    assert!(!prefix.contains("2001:db8::1".parse().unwrap()));
+
+    let grantees = parse_grantees("127.0.0.1, 2001:db8::/32, 0.0.0.0/0").unwrap();
+    let allows = |i: usize, address: &str| grantees[i].allows(address.parse().unwrap(), None);
+    assert!(allows(0, "127.0.0.1"));
+    assert!(!allows(0, "127.0.0.2"));
+    assert!(allows(1, "2001:db8:ffff::1"));
+    assert!(allows(2, "198.51.100.1"));
+
+    assert!("10.0.0.0/33".parse::<Prefix>().is_err());
+    assert!("example.com".parse::<Prefix>().is_err());
+}
+
+#[test]
+fn test_grantees() {
+    let client: IpAddr = "192.0.2.1".parse().unwrap();
+    let grantees = parse_grantees("198.51.100.0/24, key:Transfer.Example.").unwrap();
+    assert_eq!(grantees[1], Grantee::Key("transfer.example".into()));
+
+    assert!(!grantees.iter().any(|g| g.allows(client, None)));
+    assert!(grantees
+        .iter()
+        .any(|g| g.allows(client, Some("transfer.example"))));
+    assert!(!grantees.iter().any(|g| g.allows(client, Some("other"))));
+    assert!(grantees[0].allows("198.51.100.9".parse().unwrap(), None));
+}
+
+#[test]
+fn test_acls() {
+    let acls = Acls {
+        query: Some(parse_grantees("192.0.2.0/24, 198.51.100.0/24").unwrap()),
+        recursion: Some(parse_grantees("192.0.2.0/24, key:resolver").unwrap()),
+        denied_response: "DROP".parse().unwrap(),
+    };
+    let client = |address: &str| address.parse::<IpAddr>().unwrap();
+
+    assert!(acls.allows_query(client("198.51.100.1"), None));
+    assert!(!acls.allows_recursion(client("198.51.100.1"), None));
+    assert!(acls.allows_recursion(client("192.0.2.1"), None));
+    // a key doesn't get past the query list
+    assert!(!acls.allows_recursion(client("203.0.113.1"), Some("resolver")));
+    assert!(!acls.allows_query(client("203.0.113.1"), None));
+    assert_eq!(acls.denied_response, DeniedResponse::Drop);
+
+    let open = Acls {
+        query: None,
+        recursion: None,
+        denied_response: DeniedResponse::Refused,
+    };
+    assert!(open.allows_recursion(client("203.0.113.1"), None));
+    assert!("ignore".parse::<DeniedResponse>().is_err());
+}
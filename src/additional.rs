@@ -0,0 +1,135 @@
+use crate::dns_record::{deserialize_name, serialize_record, DnsRecord, DnsType};
+use crate::hosts::Hosts;
+use crate::zone::Catalog;
+use crate::{serialize, DnsMsg};
+
+/// The largest UDP response for clients that don't advertise a bigger buffer (RFC 1035 4.2.1).
+pub const UDP_MAX_SIZE: usize = 512;
+
+/// The name an NS, MX or SRV record points to, the ones that trigger
+/// additional section processing (RFC 1035 section 3.3, RFC 2782).
+pub fn target_name(record: &DnsRecord) -> Option<String> {
+    let offset = match record.dns_type {
+        DnsType::NS => 0,
+        DnsType::MX => 2,
+        DnsType::Srv => 6,
+        _ => return None,
+    };
+
+    deserialize_name(&record.rd_data, offset)
+        .ok()
+        .map(|(name, _)| name)
+}
+
+/// Adds the A and AAAA records we know locally, from our zones or else the
+/// pinned hosts, for the targets of the answer and authority sections, skipping
+/// any that would push the message past `max_size`.
+pub fn add_additional(msg: &mut DnsMsg, catalog: &Catalog, hosts: &Hosts, max_size: usize) {
+    let targets: Vec<String> = msg
+        .answers
+        .iter()
+        .chain(&msg.authority)
+        .filter_map(target_name)
+        .collect();
+
+    let mut size = serialize(msg).len();
+    for target in targets {
+        let mut addresses = catalog.addresses(&target);
+        if addresses.is_empty() {
+            addresses = hosts.addresses(&target);
+        }
+        for record in addresses {
+            if msg.additional.contains(&record) {
+                continue;
+            }
+            let record_size = serialize_record(&record, true).len();
+            if size + record_size > max_size {
+                continue;
+            }
+            size += record_size;
+            msg.additional.push(record);
+        }
+    }
+    msg.header.additional_count = msg.additional.len() as u16;
+}
+
+#[test]
+fn test_add_additional() {
+    use crate::dns_header::{DnsHeader, OpCode, ResponseCode, QR};
+    use crate::dns_record::DnsClass;
+    use crate::zone::Zone;
+
+    let text = "$TTL 3600
+@       SOA ns1 hostmaster 1 7200 1800 1209600 300
+        NS  ns1
+        MX  10 mail
+        MX  20 backup.example.net.
+_sip._tcp SRV 0 5 5060 sip
+ns1     A   192.0.2.1
+mail    A   192.0.2.2
+        AAAA 2001:db8::2
+sip     A   192.0.2.3
+";
+    let records = crate::zone_file::parse_zone(text, "example.com", std::path::Path::new("."));
+    let catalog = Catalog::new(vec![Zone::new("example.com", records.unwrap()).unwrap()]);
+
+    let question = |name: &str, dns_type| DnsRecord {
+        name: name.into(),
+        dns_type,
+        dns_class: DnsClass::IN,
+        time_to_live: 0,
+        rd_length: 0,
+        rd_data: vec![],
+    };
+    let mut query = DnsMsg {
+        header: DnsHeader {
+            id: 1,
+            query: QR::Query,
+            op_code: OpCode::StandardQuery,
+            aa: false,
+            tc: false,
+            rd: false,
+            ra: false,
+            z: 0,
+            response_code: ResponseCode::NoError,
+            questions_count: 1,
+            answers_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        },
+        questions: vec![question("example.com", DnsType::MX)],
+        answers: vec![],
+        authority: vec![],
+        additional: vec![],
+    };
+
+    let hosts = Hosts::default();
+    let mut response = catalog.answer(&query).unwrap();
+    add_additional(&mut response, &catalog, &hosts, UDP_MAX_SIZE);
+    // ns1 glue, then both addresses of mail; backup.example.net isn't ours
+    assert_eq!(response.additional.len(), 3);
+    assert_eq!(response.additional[1].name, "mail.example.com");
+    assert_eq!(response.additional[2].dns_type, DnsType::Aaaa);
+
+    // unless it's pinned
+    let mut pinned = Hosts::new(60);
+    pinned.insert("backup.example.net", "198.51.100.25".parse().unwrap());
+    let mut response = catalog.answer(&query).unwrap();
+    add_additional(&mut response, &catalog, &pinned, UDP_MAX_SIZE);
+    assert_eq!(response.additional.len(), 4);
+    assert_eq!(response.additional[3].name, "backup.example.net");
+
+    query.questions = vec![question("_sip._tcp.example.com", DnsType::Srv)];
+    let mut response = catalog.answer(&query).unwrap();
+    add_additional(&mut response, &catalog, &hosts, UDP_MAX_SIZE);
+    assert!(response
+        .additional
+        .iter()
+        .any(|r| r.name == "sip.example.com"));
+
+    // nothing is added past the size budget
+    let mut response = catalog.answer(&query).unwrap();
+    let budget = serialize(&response).len();
+    add_additional(&mut response, &catalog, &hosts, budget);
+    assert_eq!(serialize(&response).len(), budget);
+}
@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::{debug, info, warn};
+
+use crate::dns_header::{DnsHeader, ResponseCode, QR};
+use crate::dns_record::{DnsClass, DnsRecord, DnsType};
+use crate::server::Server;
+use crate::DnsMsg;
+
+/// The TTL of the null addresses blocked names get.
+const BLOCKED_TTL: i32 = 60;
+
+/// Names hosts format lists map to loopback addresses for the machine's own
+/// use, never meant to be blocked.
+const HOSTS_FILE_NAMES: [&str; 7] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+/// How blocked names are answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockResponse {
+    NxDomain,
+    NoData,
+    /// 0.0.0.0 for A queries and :: for AAAA ones, no data for anything else.
+    Null,
+    Refused,
+}
+
+impl FromStr for BlockResponse {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            "nodata" => Ok(BlockResponse::NoData),
+            "null" | "0.0.0.0" => Ok(BlockResponse::Null),
+            "refused" => Ok(BlockResponse::Refused),
+            _ => Err(format!(
+                "expected nxdomain, nodata, null or refused, got {value}"
+            )),
+        }
+    }
+}
+
+/// A line of a blocklist.
+#[derive(Debug, PartialEq, Eq)]
+enum Rule {
+    Block(String),
+    /// AdBlock exceptions, `@@||name^`.
+    Allow(String),
+}
+
+/// Reads a line in any of the formats blocklists come in: hosts files
+/// (`0.0.0.0 name...`), plain names, and AdBlock rules (`||name^`). AdBlock
+/// rules with options or paths, which don't apply to DNS, are left out.
+fn parse_rules(line: &str) -> Vec<Rule> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return vec![];
+    }
+
+    let (exception, line) = match line.strip_prefix("@@") {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let names = match fields.as_slice() {
+        [name] => {
+            let name = name.strip_prefix("||").unwrap_or(name);
+            vec![name.strip_suffix('^').unwrap_or(name)]
+        }
+        // hosts format, every name after the address
+        [address, names @ ..] if address.parse::<std::net::IpAddr>().is_ok() => names.to_vec(),
+        _ => vec![],
+    };
+
+    names
+        .into_iter()
+        .map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+        .filter(|name| {
+            let valid = name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            !name.is_empty() && valid && !HOSTS_FILE_NAMES.contains(&name.as_str())
+        })
+        .map(|name| {
+            if exception {
+                Rule::Allow(name)
+            } else {
+                Rule::Block(name)
+            }
+        })
+        .collect()
+}
+
+/// Names blocked along with everything below them, unless allowed.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    blocked: HashSet<String>,
+    allowed: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Reads the `blocklists` and the `allowlists`, whose every entry, in any of
+    /// the formats, is an exception.
+    pub fn load(blocklists: &[PathBuf], allowlists: &[PathBuf]) -> anyhow::Result<Blocklist> {
+        let mut blocklist = Blocklist::default();
+        for (paths, is_allowlist) in [(blocklists, false), (allowlists, true)] {
+            for path in paths {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                blocklist.add_rules(&text, is_allowlist);
+            }
+        }
+
+        Ok(blocklist)
+    }
+
+    fn add_rules(&mut self, text: &str, is_allowlist: bool) {
+        for rule in text.lines().flat_map(parse_rules) {
+            match rule {
+                Rule::Block(name) if !is_allowlist => self.blocked.insert(name),
+                Rule::Block(name) | Rule::Allow(name) => self.allowed.insert(name),
+            };
+        }
+    }
+
+    /// Whether `name` or any of its parents is blocked, and neither it nor
+    /// any of its parents is allowed.
+    pub fn is_blocked(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let suffixes: Vec<&str> = std::iter::successors(Some(name.as_str()), |name| {
+            name.split_once('.').map(|(_, parent)| parent)
+        })
+        .collect();
+
+        suffixes.iter().any(|suffix| self.blocked.contains(*suffix))
+            && !suffixes.iter().any(|suffix| self.allowed.contains(*suffix))
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocked.len()
+    }
+
+    /// The `response` to `query` when it asks about a blocked name. The
+    /// whole query is blocked when any of its questions is, so another
+    /// question can't take a blocked name along.
+    pub fn answer(&self, query: &DnsMsg, response: BlockResponse) -> Option<DnsMsg> {
+        let blocked: Vec<&DnsRecord> = query
+            .questions
+            .iter()
+            .filter(|question| self.is_blocked(&question.name))
+            .collect();
+        if blocked.is_empty() {
+            return None;
+        }
+        for question in &blocked {
+            debug!("blocked {} {:?}", question.name, question.dns_type);
+        }
+
+        let null_address = |question: &&DnsRecord| {
+            let rd_data = match question.dns_type {
+                DnsType::A => Ipv4Addr::UNSPECIFIED.octets().to_vec(),
+                DnsType::Aaaa => Ipv6Addr::UNSPECIFIED.octets().to_vec(),
+                _ => return None,
+            };
+            Some(DnsRecord {
+                name: question.name.clone(),
+                dns_type: question.dns_type,
+                dns_class: DnsClass::IN,
+                time_to_live: BLOCKED_TTL,
+                rd_length: rd_data.len() as u16,
+                rd_data,
+            })
+        };
+        let (response_code, answers) = match response {
+            BlockResponse::NxDomain => (ResponseCode::NameError, vec![]),
+            BlockResponse::Refused => (ResponseCode::Refused, vec![]),
+            BlockResponse::NoData => (ResponseCode::NoError, vec![]),
+            BlockResponse::Null => (
+                ResponseCode::NoError,
+                blocked.iter().filter_map(null_address).collect(),
+            ),
+        };
+
+        Some(DnsMsg {
+            header: DnsHeader {
+                query: QR::Response,
+                aa: false,
+                tc: false,
+                ra: true,
+                z: 0,
+                response_code,
+                answers_count: answers.len() as u16,
+                authority_count: 0,
+                additional_count: 0,
+                ..query.header
+            },
+            questions: query.questions.clone(),
+            answers,
+            authority: vec![],
+            additional: vec![],
+        })
+    }
+}
+
+/// Reloads the lists the settings name every `interval`, keeping the ones
+/// we have when they can't be read.
+pub fn reload(server: Arc<Server>, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+        let settings = server.settings();
+        if settings.blocklists.is_empty() {
+            continue;
+        }
+        match Blocklist::load(&settings.blocklists, &settings.allowlists) {
+            Ok(blocklist) => {
+                let mut current = server.blocklist.write().unwrap();
+                // a reloaded configuration brings lists of its own
+                if Arc::ptr_eq(&settings, &server.settings()) {
+                    info!("reloaded blocklists, {} names blocked", blocklist.len());
+                    *current = blocklist;
+                }
+            }
+            Err(e) => warn!("Failed to reload blocklists: {e:#}"),
+        }
+    }
+}
+
+#[test]
+fn test_blocklist() {
+    let mut blocklist = Blocklist::default();
+    blocklist.add_rules(
+        "# hosts format\n\
+         127.0.0.1 localhost\n\
+         0.0.0.0 ads.example.com\n\
+         0.0.0.0 ads.example.net banner.example.net  # several names\n\
+         ::1 ip6-localhost\n\
+         tracker.example.net  # plain\n\
+         ! AdBlock\n\
+         [Adblock Plus 2.0]\n\
+         ||Malware.Example.org^\n\
+         @@||safe.malware.example.org^\n\
+         ||example.info^$third-party\n\
+         ||example.info/banner\n",
+        false,
+    );
+    blocklist.add_rules("cdn.tracker.example.net\n", true);
+
+    assert_eq!(blocklist.len(), 5);
+    assert!(blocklist.is_blocked("ads.example.com"));
+    assert!(blocklist.is_blocked("sub.ADS.example.com."));
+    assert!(!blocklist.is_blocked("example.com"));
+    assert!(blocklist.is_blocked("ads.example.net"));
+    assert!(blocklist.is_blocked("banner.example.net"));
+    assert!(!blocklist.is_blocked("localhost"));
+    assert!(blocklist.is_blocked("tracker.example.net"));
+    assert!(!blocklist.is_blocked("cdn.tracker.example.net"));
+    assert!(!blocklist.is_blocked("img.cdn.tracker.example.net"));
+    assert!(blocklist.is_blocked("a.malware.example.org"));
+    assert!(!blocklist.is_blocked("safe.malware.example.org"));
+    assert!(!blocklist.is_blocked("example.info"));
+
+    let answer = |name: &str, dns_type: DnsType, response: &str| {
+        let mut query =
+            crate::deserialize(&[0, 5, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1]).unwrap();
+        query.questions[0].name = name.into();
+        query.questions[0].dns_type = dns_type;
+        blocklist.answer(&query, response.parse().unwrap())
+    };
+    let blocked =
+        |dns_type: DnsType, response: &str| answer("ads.example.com", dns_type, response).unwrap();
+
+    let response = blocked(DnsType::A, "nxdomain");
+    assert_eq!(response.header.id, 5);
+    assert_eq!(response.header.response_code, ResponseCode::NameError);
+    let response = blocked(DnsType::A, "refused");
+    assert_eq!(response.header.response_code, ResponseCode::Refused);
+    assert!(blocked(DnsType::A, "nodata").answers.is_empty());
+    assert_eq!(blocked(DnsType::A, "null").answers[0].rd_data, vec![0; 4]);
+    assert_eq!(
+        blocked(DnsType::Aaaa, "0.0.0.0").answers[0].rd_data,
+        vec![0; 16]
+    );
+    assert!(blocked(DnsType::MX, "null").answers.is_empty());
+    assert!(answer("example.com", DnsType::A, "null").is_none());
+
+    // a second question doesn't get a blocked name past
+    let mut query =
+        crate::deserialize(&[0, 5, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1]).unwrap();
+    query.questions[0].name = "example.com".into();
+    let mut second = query.questions[0].clone();
+    second.name = "ads.example.com".into();
+    query.questions.push(second);
+    let response = blocklist.answer(&query, BlockResponse::Null).unwrap();
+    assert_eq!(response.questions.len(), 2);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].name, "ads.example.com");
+}
@@ -0,0 +1,166 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use tokio::signal::unix::{signal, SignalKind};
+use toml::{Table, Value};
+use tracing::{error, info};
+
+/// The command line options a TOML configuration file stands for. Its keys
+/// are the long options without their dashes, which tables prefix with their
+/// name: `listen = "0.0.0.0:53"` is `--listen 0.0.0.0:53` and `rrl` table's
+/// `slip = 1` is `--rrl-slip 1`. Arrays give an option once for each item,
+/// and a table named after an option gives it once for each key as
+/// `<key>=<value>`, so that `zone` table's `"example.com" = "example.zone"`
+/// is `--zone example.com=example.zone`. `true` gives a flag, `false`
+/// leaves it out.
+pub fn file_args(path: &Path, command: &clap::Command) -> anyhow::Result<Vec<OsString>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let table: Table = text
+        .parse()
+        .with_context(|| format!("parsing {}", path.display()))?;
+
+    let mut args = vec![];
+    table_args(&table, "", command, &mut args)?;
+
+    Ok(args.into_iter().map(OsString::from).collect())
+}
+
+fn table_args(
+    table: &Table,
+    prefix: &str,
+    command: &clap::Command,
+    args: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    for (key, value) in table {
+        let name = format!("{prefix}{}", key.replace('_', "-"));
+        let option = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()));
+        let Some(option) = option else {
+            match value {
+                Value::Table(table) => table_args(table, &format!("{name}-"), command, args)?,
+                _ => bail!("unknown option {name}"),
+            }
+            continue;
+        };
+        if name == "config" {
+            bail!("a configuration file can't name another one");
+        }
+
+        match value {
+            Value::Boolean(set) if !option.get_action().takes_values() => {
+                if *set {
+                    args.push(format!("--{name}"));
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    args.push(format!("--{name}={}", scalar(&name, item)?));
+                }
+            }
+            Value::Table(entries) => {
+                for (key, value) in entries {
+                    let values = match value {
+                        Value::Array(items) => items.iter().collect(),
+                        value => vec![value],
+                    };
+                    for value in values {
+                        args.push(format!("--{name}={key}={}", scalar(&name, value)?));
+                    }
+                }
+            }
+            value => args.push(format!("--{name}={}", scalar(&name, value)?)),
+        }
+    }
+
+    Ok(())
+}
+
+/// The command line form of a single `value` of the option `name`.
+fn scalar(name: &str, value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        _ => bail!("{name} takes strings, numbers or lists of them"),
+    }
+}
+
+/// Calls `reload` on every SIGHUP, the configuration in use staying put
+/// when it fails.
+pub fn reload_on_hangup(reload: impl Fn() -> anyhow::Result<()>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the reload runtime");
+
+    runtime.block_on(async move {
+        let mut hangups = signal(SignalKind::hangup()).expect("Failed to watch for SIGHUP");
+        while hangups.recv().await.is_some() {
+            match reload() {
+                Ok(()) => info!("reloaded the configuration"),
+                Err(e) => error!("Failed to reload the configuration, keeping it as it was: {e:#}"),
+            }
+        }
+    });
+}
+
+#[test]
+fn test_file_args() {
+    use clap::{Arg, ArgAction, Command};
+
+    let command = Command::new("test")
+        .arg(Arg::new("listen").long("listen"))
+        .arg(Arg::new("zone").long("zone").action(ArgAction::Append))
+        .arg(Arg::new("rrl-slip").long("rrl-slip"))
+        .arg(
+            Arg::new("rrl-log-only")
+                .long("rrl-log-only")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("allow-query")
+                .long("allow-query")
+                .action(ArgAction::Append),
+        )
+        .arg(Arg::new("config").long("config"));
+    let path = std::env::temp_dir().join(format!("test_file_args_{}.toml", std::process::id()));
+    let args = |text: &str| {
+        std::fs::write(&path, text).unwrap();
+        file_args(&path, &command).map(|args| {
+            args.into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect::<Vec<_>>()
+        })
+    };
+
+    assert_eq!(
+        args(
+            "listen = \"0.0.0.0:53\"\n\
+             allow_query = [\"10.0.0.0/8\", \"key:ops\"]\n\
+             [rrl]\n\
+             slip = 1\n\
+             log-only = true\n\
+             [zone]\n\
+             \"example.com\" = \"example.zone\"\n"
+        )
+        .unwrap(),
+        [
+            "--allow-query=10.0.0.0/8",
+            "--allow-query=key:ops",
+            "--listen=0.0.0.0:53",
+            "--rrl-log-only",
+            "--rrl-slip=1",
+            "--zone=example.com=example.zone",
+        ]
+    );
+    assert!(args("[rrl]\nlog-only = false\n").unwrap().is_empty());
+    assert!(args("lisen = \"0.0.0.0:53\"\n").is_err());
+    assert!(args("config = \"other.toml\"\n").is_err());
+    assert!(args("listen = [[1]]\n").is_err());
+    assert!(args("listen = ").is_err());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -37,6 +37,8 @@ pub enum OpCode {
     StandardQuery = 0,
     InverseQuery = 1,
     ServerStatus = 2,
+    Notify = 4,
+    Update = 5,
     NotImplemented,
 }
 
@@ -46,6 +48,8 @@ impl From<u8> for OpCode {
             0 => Self::StandardQuery,
             1 => Self::InverseQuery,
             2 => Self::ServerStatus,
+            4 => Self::Notify,
+            5 => Self::Update,
             _ => Self::NotImplemented,
         }
     }
@@ -59,6 +63,15 @@ pub enum ResponseCode {
     NameError = 3,
     NotImplemented = 4,
     Refused = 5,
+    YXDomain = 6,
+    YXRRSet = 7,
+    NXRRSet = 8,
+    NotAuth = 9,
+    NotZone = 10,
+    // TSIG errors, which only fit in the TSIG record and never in the header
+    BadSig = 16,
+    BadKey = 17,
+    BadTime = 18,
 }
 
 impl From<u8> for ResponseCode {
@@ -70,11 +83,38 @@ impl From<u8> for ResponseCode {
             3 => Self::NameError,
             4 => Self::NotImplemented,
             5 => Self::Refused,
-            _ => unimplemented!("Response code for {value} isn't implemented "),
+            6 => Self::YXDomain,
+            7 => Self::YXRRSet,
+            8 => Self::NXRRSet,
+            9 => Self::NotAuth,
+            10 => Self::NotZone,
+            16 => Self::BadSig,
+            17 => Self::BadKey,
+            18 => Self::BadTime,
+            // the unassigned ones tell us only that something went wrong
+            _ => Self::ServerFailure,
         }
     }
 }
 
+/// The mnemonic of a header's response code, or its number if it has none.
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".into(),
+        1 => "FORMERR".into(),
+        2 => "SERVFAIL".into(),
+        3 => "NXDOMAIN".into(),
+        4 => "NOTIMP".into(),
+        5 => "REFUSED".into(),
+        6 => "YXDOMAIN".into(),
+        7 => "YXRRSET".into(),
+        8 => "NXRRSET".into(),
+        9 => "NOTAUTH".into(),
+        10 => "NOTZONE".into(),
+        other => other.to_string(),
+    }
+}
+
 pub fn serialize_header(msg: &DnsHeader) -> [u8; 12] {
     let mut bytes = [0; 12];
 
@@ -187,4 +227,7 @@ fn test_serialize_deserialize() {
     let recv_msg = deserialize_header(&my_res);
 
     assert_eq!(msg, recv_msg);
+
+    let unassigned = deserialize_header(&[0, 7, 0, 11, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(unassigned.response_code, ResponseCode::ServerFailure);
 }
@@ -1,8 +1,8 @@
-use crate::utils;
+use anyhow::bail;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum DnsType {
-    A = 1,
+    A,
     NS,
     MD, //
     MF,
@@ -18,12 +18,24 @@ pub enum DnsType {
     Minfo,
     MX,
     Txt,
+    Aaaa,
+    Srv,
+
+    // EDNS pseudo record, only ever in the additional section.
+    Opt,
+
+    // Transaction signatures, only ever the last record of a message.
+    Tsig,
 
     // Types that appear only in question part of a query.
-    Axfr = 252,
+    Ixfr,
+    Axfr,
     Mailb,
     Maila,
     AllRecords,
+
+    // Any other type, its RDATA kept as it came.
+    Unknown(u16),
 }
 
 impl From<u16> for DnsType {
@@ -45,23 +57,64 @@ impl From<u16> for DnsType {
             14 => DnsType::Minfo,
             15 => DnsType::MX,
             16 => DnsType::Txt,
+            28 => DnsType::Aaaa,
+            33 => DnsType::Srv,
+            41 => DnsType::Opt,
+            250 => DnsType::Tsig,
+            251 => DnsType::Ixfr,
             252 => DnsType::Axfr,
             253 => DnsType::Mailb,
             254 => DnsType::Maila,
             255 => DnsType::AllRecords,
-            _ => unreachable!("Couldn't find DNS type for value: {value}"),
+            _ => DnsType::Unknown(value),
+        }
+    }
+}
+
+impl From<DnsType> for u16 {
+    fn from(dns_type: DnsType) -> Self {
+        match dns_type {
+            DnsType::A => 1,
+            DnsType::NS => 2,
+            DnsType::MD => 3,
+            DnsType::MF => 4,
+            DnsType::Cname => 5,
+            DnsType::Soa => 6,
+            DnsType::MB => 7,
+            DnsType::MG => 8,
+            DnsType::MR => 9,
+            DnsType::Null => 10,
+            DnsType::Wks => 11,
+            DnsType::Ptr => 12,
+            DnsType::Hinfo => 13,
+            DnsType::Minfo => 14,
+            DnsType::MX => 15,
+            DnsType::Txt => 16,
+            DnsType::Aaaa => 28,
+            DnsType::Srv => 33,
+            DnsType::Opt => 41,
+            DnsType::Tsig => 250,
+            DnsType::Ixfr => 251,
+            DnsType::Axfr => 252,
+            DnsType::Mailb => 253,
+            DnsType::Maila => 254,
+            DnsType::AllRecords => 255,
+            DnsType::Unknown(value) => value,
         }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum DnsClass {
-    IN = 1,
+    IN,
     CS,
     CH,
     HS,
-    // Any Class only appear in question section of a Query
-    AnyClass = 255,
+    // None and Any classes only appear in dynamic updates and questions
+    NoneClass,
+    AnyClass,
+    // Any other value, like the UDP payload size an OPT record carries here
+    Unknown(u16),
 }
 
 impl From<u16> for DnsClass {
@@ -71,8 +124,23 @@ impl From<u16> for DnsClass {
             2 => DnsClass::CS,
             3 => DnsClass::CH,
             4 => DnsClass::HS,
+            254 => DnsClass::NoneClass,
             255 => DnsClass::AnyClass,
-            _ => unreachable!("Couldn't find DNS Class for value: {value}"),
+            _ => DnsClass::Unknown(value),
+        }
+    }
+}
+
+impl From<DnsClass> for u16 {
+    fn from(dns_class: DnsClass) -> Self {
+        match dns_class {
+            DnsClass::IN => 1,
+            DnsClass::CS => 2,
+            DnsClass::CH => 3,
+            DnsClass::HS => 4,
+            DnsClass::NoneClass => 254,
+            DnsClass::AnyClass => 255,
+            DnsClass::Unknown(value) => value,
         }
     }
 }
@@ -87,103 +155,230 @@ pub struct DnsRecord {
     pub rd_data: Vec<u8>,
 }
 
-pub fn serialize_record(record: &DnsRecord) -> Vec<u8> {
-    let mut bytes: Vec<u8> = Vec::new();
-
-    for label in record.name.split(".") {
-        let len = label.len() as u8;
-        let content = label.as_bytes();
-        bytes.push(len);
-        bytes.extend_from_slice(content);
-    }
-    bytes.push(0);
+pub fn serialize_record(record: &DnsRecord, have_rd: bool) -> Vec<u8> {
+    let mut bytes = serialize_name(&record.name);
 
     let serialize_u16 = |bytes: &mut Vec<u8>, value: u16| {
         bytes.extend_from_slice(&[(value >> 8) as u8, value as u8])
     };
 
-    serialize_u16(&mut bytes, record.dns_type as u16);
-    serialize_u16(&mut bytes, record.dns_class as u16);
-    // // TTL 32bits
-    if record.time_to_live > 0 {
-        bytes.extend_from_slice(&[
-            (record.time_to_live >> 24) as u8,
-            (record.time_to_live >> 16) as u8,
-            (record.time_to_live >> 8) as u8,
-            record.time_to_live as u8,
-        ]);
-    }
+    serialize_u16(&mut bytes, record.dns_type.into());
+    serialize_u16(&mut bytes, record.dns_class.into());
 
-    // // RDLENGTH 16 bits
-    if record.rd_length > 0 {
-        serialize_u16(&mut bytes, record.rd_length);
+    // questions stop at the class, resource records carry TTL and RDATA
+    if have_rd {
+        bytes.extend_from_slice(&record.time_to_live.to_be_bytes());
+        serialize_u16(&mut bytes, record.rd_data.len() as u16);
         bytes.extend(&record.rd_data);
     }
 
     bytes
 }
 
-pub fn deserialize_record(bytes: &[u8], mut begin: usize, have_rd: bool) -> (DnsRecord, usize) {
-    let mut end;
-    let mut name;
+/// Whether `name` fits in a message: labels of at most 63 bytes and at most
+/// 255 bytes in all once encoded, as RFC 1035 allows.
+pub fn is_valid_name(name: &str) -> bool {
+    let labels = name.split('.').filter(|label| !label.is_empty());
+    labels.clone().all(|label| label.len() <= 63)
+        && labels.map(|label| label.len() + 1).sum::<usize>() < 255
+}
+
+/// Encodes a domain name as a sequence of labels without compression.
+/// The root is the empty string and a trailing dot is ignored.
+pub fn serialize_name(name: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(name.len() + 2);
+
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+
+    bytes
+}
+
+/// The name and type of the first question of the raw `message`, if it has any.
+pub fn raw_question(message: &[u8]) -> Option<(String, DnsType)> {
+    match message.get(4..6) {
+        Some([0, 0]) | None => return None,
+        Some(_) => {}
+    }
+    let (name, end) = deserialize_name(message, 12).ok()?;
+    Some((name, DnsType::from(read_u16(message, end).ok()?)))
+}
+
+/// The big endian `u16` at `index`, or an error past the end of `bytes`.
+fn read_u16(bytes: &[u8], index: usize) -> anyhow::Result<u16> {
+    match bytes.get(index..index + 2) {
+        Some(&[high, low]) => Ok(u16::from_be_bytes([high, low])),
+        _ => bail!("message ends in the middle of a field"),
+    }
+}
 
-    let size = bytes[begin - 1] as usize;
-    (begin, end, name) = deserialize_label(bytes, begin, begin + size);
+/// Decodes the domain name starting at `index`, following compression pointers.
+/// Returns the name and the index right after it in the original message.
+pub fn deserialize_name(bytes: &[u8], index: usize) -> anyhow::Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut index = index;
+    let mut end = None;
+    let mut length = 1;
+    // a pointer must always point backwards, bounding the jumps guards against loops
+    let mut jumps = 0;
+
+    loop {
+        let Some(&size) = bytes.get(index) else {
+            bail!("message ends in the middle of a name");
+        };
+        let size = size as usize;
+        if size & 0b1100_0000 == 0b1100_0000 {
+            if end.is_none() {
+                end = Some(index + 2);
+            }
+            jumps += 1;
+            if jumps >= 128 {
+                bail!("too many compression pointers in name");
+            }
+            index = (read_u16(bytes, index)? & 0b0011111111111111) as usize;
+            continue;
+        }
+
+        if size == 0 {
+            break;
+        }
+        if size > 63 {
+            bail!("unsupported label type in name");
+        }
+        length += 1 + size;
+        if length > 255 {
+            bail!("name longer than 255 bytes");
+        }
 
-    while begin != end {
-        let label;
-        (begin, end, label) = deserialize_label(bytes, begin, end);
-        name = format!("{name}.{label}");
+        let Some(label) = bytes.get(index + 1..index + 1 + size) else {
+            bail!("message ends in the middle of a name");
+        };
+        labels.push(String::from_utf8_lossy(label).to_string());
+        index += 1 + size;
     }
-    let dns_type_value = utils::double_u8_to_u16(bytes, end);
+
+    Ok((labels.join("."), end.unwrap_or(index + 1)))
+}
+
+pub fn deserialize_record(
+    bytes: &[u8],
+    begin: usize,
+    have_rd: bool,
+) -> anyhow::Result<(DnsRecord, usize)> {
+    // `begin` points one byte past the start of the record
+    let (name, mut end) = deserialize_name(bytes, begin - 1)?;
+
+    let dns_type_value = read_u16(bytes, end)?;
     end += 2;
-    let dns_class_value = utils::double_u8_to_u16(bytes, end);
+    let dns_class_value = read_u16(bytes, end)?;
     end += 2;
+    let dns_type: DnsType = dns_type_value.into();
 
     let (rd_length, rd_data, ttl) = if have_rd {
-        let ttl = utils::bytes_to_i32(bytes, end);
+        let Some(ttl) = bytes.get(end..end + 4) else {
+            bail!("message ends in the middle of a record");
+        };
+        let ttl = i32::from_be_bytes(ttl.try_into()?);
         end += 4;
-        let rd_length = utils::double_u8_to_u16(bytes, end);
+        let rd_length = read_u16(bytes, end)?;
         end += 2;
-        let rd_data = bytes[end..end + rd_length as usize].to_vec();
+        let rd_data = decompress_rd_data(bytes, dns_type, end, rd_length as usize)?;
         end += rd_length as usize;
 
-        (rd_length, rd_data, ttl)
+        (rd_data.len() as u16, rd_data, ttl)
     } else {
         (0, Vec::new(), 0)
     };
 
-    (
+    Ok((
         DnsRecord {
             name,
-            dns_type: dns_class_value.into(),
-            dns_class: dns_type_value.into(),
+            dns_type,
+            dns_class: dns_class_value.into(),
             time_to_live: ttl,
             rd_length,
             rd_data,
         },
         end,
-    )
-}
-
-fn deserialize_label(bytes: &[u8], begin: usize, end: usize) -> (usize, usize, String) {
-    let mut begin = begin;
-    let mut end = end;
-    let pointer_bytes = utils::double_u8_to_u16(bytes, begin - 1);
-    let is_a_pointer = pointer_bytes & (0b11 << 14) != 0;
-    if is_a_pointer {
-        let offset = (pointer_bytes & 0b0011111111111111) as usize;
-        begin = offset + 1;
-        let size = bytes[begin - 1] as usize;
-        end = begin + size;
+    ))
+}
+
+/// Copies the RDATA at `begin`, expanding compressed domain names so the
+/// record no longer depends on the message it was read from.
+fn decompress_rd_data(
+    bytes: &[u8],
+    dns_type: DnsType,
+    begin: usize,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let end = begin + len;
+    let Some(raw) = bytes.get(begin..end) else {
+        bail!("RDATA runs past the end of the message");
+    };
+    // dynamic updates deleting a whole RRset carry no RDATA at all
+    if len == 0 {
+        return Ok(vec![]);
     }
+    // (fixed bytes before the names, number of names, fixed bytes after them)
+    let (prefix, names, suffix) = match dns_type {
+        DnsType::NS
+        | DnsType::MD
+        | DnsType::MF
+        | DnsType::Cname
+        | DnsType::MB
+        | DnsType::MG
+        | DnsType::MR
+        | DnsType::Ptr => (0, 1, 0),
+        DnsType::MX => (2, 1, 0),
+        DnsType::Srv => (6, 1, 0),
+        DnsType::Minfo => (0, 2, 0),
+        DnsType::Soa => (0, 2, 20),
+        _ => return Ok(raw.to_vec()),
+    };
+
+    let Some(fixed) = raw.get(..prefix) else {
+        bail!("RDATA too short for its type");
+    };
+    let mut rd_data = fixed.to_vec();
+    let mut index = begin + prefix;
+    for _ in 0..names {
+        let name;
+        (name, index) = deserialize_name(bytes, index)?;
+        rd_data.extend(serialize_name(&name));
+    }
+    match bytes.get(index..index + suffix) {
+        Some(fixed) if index + suffix == end => rd_data.extend_from_slice(fixed),
+        _ => bail!("RDATA length doesn't match its contents"),
+    }
+
+    Ok(rd_data)
+}
 
-    let sub_string = String::from_utf8_lossy(&bytes[begin..end]).to_string();
-    let begin = end + 1;
-    let size = bytes[end] as usize;
-    let end = begin + size;
+/// Whether `rd_data` is laid out the way `dns_type` says. The RDATA of
+/// types without a known layout always is.
+pub fn is_well_formed(dns_type: DnsType, rd_data: &[u8]) -> bool {
+    // TXT and HINFO hold length prefixed strings, HINFO exactly two
+    let strings = || {
+        let mut count = 0;
+        let mut rest = rd_data;
+        while let Some((&len, tail)) = rest.split_first() {
+            rest = tail.get(len as usize..)?;
+            count += 1;
+        }
+        Some(count)
+    };
 
-    (begin, end, sub_string)
+    match dns_type {
+        DnsType::A => rd_data.len() == 4,
+        DnsType::Aaaa => rd_data.len() == 16,
+        DnsType::Txt => strings().is_some_and(|count| count > 0),
+        DnsType::Hinfo => strings() == Some(2),
+        DnsType::Null | DnsType::Wks | DnsType::Unknown(_) => true,
+        _ => !rd_data.is_empty() && decompress_rd_data(rd_data, dns_type, 0, rd_data.len()).is_ok(),
+    }
 }
 
 #[test]
@@ -191,14 +386,24 @@ fn test_deserialize() {
     let response = [
         12, 99, 111, 100, 101, 99, 114, 97, 102, 116, 101, 114, 115, 2, 105, 111, 0, 0, 1, 0, 1,
     ];
-    let (record, _) = deserialize_record(&response, 1, false);
-
-
+    let (record, _) = deserialize_record(&response, 1, false).unwrap();
 
     assert_eq!("codecrafters.io".to_string(), record.name);
     assert_eq!(DnsType::A, record.dns_type);
     assert_eq!(DnsClass::IN, record.dns_class);
     println!("record: {record:?}");
+
+    // labels past 63 bytes and names past 255 don't fit the wire format
+    let mut long_label = vec![64];
+    long_label.extend([b'a'; 64]);
+    long_label.extend([0, 0, 1, 0, 1]);
+    assert!(deserialize_record(&long_label, 1, false).is_err());
+    let mut long_name = [[63].as_slice(), &[b'a'; 63]].concat().repeat(4);
+    long_name.extend([0, 0, 1, 0, 1]);
+    assert!(deserialize_record(&long_name, 1, false).is_err());
+    assert!(is_valid_name(&vec!["a".repeat(63); 3].join(".")));
+    assert!(!is_valid_name(&vec!["a".repeat(63); 4].join(".")));
+    assert!(!is_valid_name(&"a".repeat(64)));
 }
 
 #[test]
@@ -217,7 +422,7 @@ fn test_serialize() {
         time_to_live: 0,
     };
 
-    let result = serialize_record(&record);
+    let result = serialize_record(&record, false);
 
     assert_eq!(result, response);
 }
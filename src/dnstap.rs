@@ -0,0 +1,414 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+use crate::server::Transport;
+use crate::upstream::Endpoint;
+
+/// The Frame Streams content type of dnstap data frames.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// What the server calls itself in the frames.
+const VERSION: &str = concat!("codecrafters-dns-server ", env!("CARGO_PKG_VERSION"));
+
+/// How many frames wait for the writer before new ones are dropped, so a
+/// slow collector never holds up a query.
+const QUEUE_LEN: usize = 10_000;
+
+/// How long the writer waits before connecting to the socket again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Frame Streams control frame types and fields
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_READY: u32 = 4;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+static DNSTAP: OnceLock<Dnstap> = OnceLock::new();
+
+thread_local! {
+    /// Whether the client query the thread is answering is logged, the
+    /// queries it forwards for it going along.
+    static SAMPLED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Where the frames are written: a collector listening on a Unix socket,
+/// spoken to with the bidirectional handshake, or a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Socket(PathBuf),
+    File(PathBuf),
+}
+
+/// The dnstap message types (dnstap.proto's `Message.Type`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    AuthQuery = 1,
+    AuthResponse = 2,
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// One message of a query or response, with both sides of the exchange.
+#[derive(Debug, Clone)]
+pub struct Message<'a> {
+    pub message_type: MessageType,
+    /// dnstap.proto's `SocketProtocol`.
+    pub protocol: u32,
+    pub query_address: Option<SocketAddr>,
+    pub response_address: Option<SocketAddr>,
+    pub query_time: SystemTime,
+    pub query_message: Option<&'a [u8]>,
+    pub response_time: Option<SystemTime>,
+    pub response_message: Option<&'a [u8]>,
+}
+
+/// Hands dnstap frames over to a writer thread.
+#[derive(Debug)]
+struct Dnstap {
+    frames: SyncSender<Vec<u8>>,
+    /// One client query out of every `sample` is logged.
+    sample: u64,
+    queries: AtomicU64,
+}
+
+/// Starts logging one client query out of every `sample` to `output`.
+pub fn start(output: Output, sample: u64) -> anyhow::Result<()> {
+    let (frames, receiver) = mpsc::sync_channel(QUEUE_LEN);
+    match output {
+        Output::File(path) => {
+            let file =
+                File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+            std::thread::spawn(move || write_file(file, receiver));
+        }
+        Output::Socket(path) => {
+            std::thread::spawn(move || write_socket(path, receiver));
+        }
+    }
+
+    let dnstap = Dnstap {
+        frames,
+        sample: sample.max(1),
+        queries: AtomicU64::new(0),
+    };
+    DNSTAP
+        .set(dnstap)
+        .map_err(|_| anyhow::anyhow!("dnstap already started"))
+}
+
+/// Decides whether the client query the thread starts answering is logged.
+pub fn sample_query() -> bool {
+    let sampled = DNSTAP
+        .get()
+        .is_some_and(|dnstap| dnstap.queries.fetch_add(1, Ordering::Relaxed) % dnstap.sample == 0);
+    SAMPLED.with(|cell| cell.set(sampled));
+
+    sampled
+}
+
+/// Logs the raw `request` from `client` and its first raw `response`, as
+/// an authoritative one when we answered it from our zones.
+pub fn log_client(
+    request: &[u8],
+    response: Option<&[u8]>,
+    client: SocketAddr,
+    transport: Transport,
+    query_time: SystemTime,
+) {
+    if !SAMPLED.with(Cell::get) {
+        return;
+    }
+    let authoritative =
+        response.is_some_and(|response| response.get(2).is_some_and(|b| b & 0x04 != 0));
+    let (query_type, response_type) = match authoritative {
+        true => (MessageType::AuthQuery, MessageType::AuthResponse),
+        false => (MessageType::ClientQuery, MessageType::ClientResponse),
+    };
+    let protocol = match transport {
+        Transport::Udp => 1,
+        Transport::Tcp => 2,
+        Transport::Tls => 3,
+        Transport::Https => 4,
+        Transport::Quic => 7,
+    };
+
+    let query = Message {
+        message_type: query_type,
+        protocol,
+        query_address: Some(client),
+        response_address: None,
+        query_time,
+        query_message: Some(request),
+        response_time: None,
+        response_message: None,
+    };
+    send(&query);
+    if let Some(response) = response {
+        send(&Message {
+            message_type: response_type,
+            response_time: Some(SystemTime::now()),
+            response_message: Some(response),
+            ..query
+        });
+    }
+}
+
+/// Logs the raw `request` forwarded to `endpoint` and its raw `response`,
+/// if it answered, for a sampled client query.
+pub fn log_forwarder(
+    endpoint: &Endpoint,
+    request: &[u8],
+    response: Option<&[u8]>,
+    query_time: SystemTime,
+) {
+    if !SAMPLED.with(Cell::get) {
+        return;
+    }
+    let (protocol, address) = match endpoint {
+        Endpoint::Udp(address) => (1, address),
+        Endpoint::Tls { address, .. } => (3, address),
+        Endpoint::Https { address, .. } => (4, address),
+        Endpoint::Quic { address, .. } => (7, address),
+    };
+
+    let query = Message {
+        message_type: MessageType::ForwarderQuery,
+        protocol,
+        query_address: None,
+        response_address: Some(*address),
+        query_time,
+        query_message: Some(request),
+        response_time: None,
+        response_message: None,
+    };
+    send(&query);
+    if let Some(response) = response {
+        send(&Message {
+            message_type: MessageType::ForwarderResponse,
+            query_message: None,
+            response_time: Some(SystemTime::now()),
+            response_message: Some(response),
+            ..query
+        });
+    }
+}
+
+/// Queues `message`, dropping it if the writer has fallen behind.
+fn send(message: &Message) {
+    if let Some(dnstap) = DNSTAP.get() {
+        let _ = dnstap.frames.try_send(encode(message));
+    }
+}
+
+/// The `Dnstap` protobuf holding `message`.
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut inner = Vec::new();
+    put_varint_field(&mut inner, 1, message.message_type as u64);
+    let (query_address, response_address) = (message.query_address, message.response_address);
+    if let Some(address) = query_address.or(response_address) {
+        let family = match address.ip().to_canonical() {
+            IpAddr::V4(_) => 1,
+            IpAddr::V6(_) => 2,
+        };
+        put_varint_field(&mut inner, 2, family);
+    }
+    put_varint_field(&mut inner, 3, message.protocol as u64);
+    for (address, address_field, port_field) in [(query_address, 4, 6), (response_address, 5, 7)] {
+        if let Some(address) = address {
+            let ip = match address.ip().to_canonical() {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            put_bytes_field(&mut inner, address_field, &ip);
+            put_varint_field(&mut inner, port_field, address.port() as u64);
+        }
+    }
+    put_time(&mut inner, 8, 9, message.query_time);
+    if let Some(query) = message.query_message {
+        put_bytes_field(&mut inner, 10, query);
+    }
+    if let Some(time) = message.response_time {
+        put_time(&mut inner, 12, 13, time);
+    }
+    if let Some(response) = message.response_message {
+        put_bytes_field(&mut inner, 14, response);
+    }
+
+    let mut outer = Vec::new();
+    put_bytes_field(&mut outer, 2, VERSION.as_bytes());
+    put_bytes_field(&mut outer, 14, &inner);
+    // MESSAGE, the only type there is
+    put_varint_field(&mut outer, 15, 1);
+
+    outer
+}
+
+fn put_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn put_varint_field(bytes: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(bytes, field << 3);
+    put_varint(bytes, value);
+}
+
+fn put_bytes_field(bytes: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(bytes, field << 3 | 2);
+    put_varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+fn put_time(bytes: &mut Vec<u8>, sec_field: u64, nsec_field: u64, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    put_varint_field(bytes, sec_field, since_epoch.as_secs());
+    put_varint(bytes, nsec_field << 3 | 5);
+    bytes.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+}
+
+/// A Frame Streams control frame of `control_type` with the dnstap content type.
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    let len = 12 + CONTENT_TYPE.len() as u32;
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&control_type.to_be_bytes());
+    frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+    frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    frame.extend_from_slice(CONTENT_TYPE);
+
+    frame
+}
+
+/// Writes the frames coming in to `output` until the sending side goes,
+/// flushing whenever none are waiting.
+fn write_frames(output: &mut impl Write, frames: &Receiver<Vec<u8>>) -> io::Result<()> {
+    let mut output = BufWriter::new(output);
+    output.write_all(&control_frame(CONTROL_START))?;
+    output.flush()?;
+    while let Ok(mut frame) = frames.recv() {
+        loop {
+            output.write_all(&(frame.len() as u32).to_be_bytes())?;
+            output.write_all(&frame)?;
+            frame = match frames.try_recv() {
+                Ok(frame) => frame,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return output.flush(),
+            };
+        }
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+fn write_file(mut file: File, frames: Receiver<Vec<u8>>) {
+    if let Err(e) = write_frames(&mut file, &frames) {
+        warn!("Failed to write dnstap: {e}");
+    }
+}
+
+/// Keeps a connection to the collector at `path`, the frames sent while
+/// there is none being dropped.
+fn write_socket(path: PathBuf, frames: Receiver<Vec<u8>>) {
+    loop {
+        let result = UnixStream::connect(&path).and_then(|mut stream| {
+            handshake(&mut stream)?;
+            info!("sending dnstap to {}", path.display());
+            write_frames(&mut stream, &frames)
+        });
+        if let Err(e) = result {
+            warn!("Failed to send dnstap to {}: {e}", path.display());
+        }
+
+        std::thread::sleep(RECONNECT_DELAY);
+        while frames.try_recv().is_ok() {}
+    }
+}
+
+/// Offers the collector dnstap and waits for it to accept.
+fn handshake(stream: &mut UnixStream) -> io::Result<()> {
+    stream.write_all(&control_frame(CONTROL_READY))?;
+
+    let mut header = [0; 8];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+    let mut control = vec![0; len];
+    stream.read_exact(&mut control)?;
+    if header[..4] != [0; 4] || control.get(..4) != Some(&CONTROL_ACCEPT.to_be_bytes()[..]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "collector didn't accept the stream",
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_dnstap() {
+    use std::os::unix::net::UnixListener;
+
+    let query_time = UNIX_EPOCH + Duration::new(1_700_000_000, 5);
+    let message = Message {
+        message_type: MessageType::ClientQuery,
+        protocol: 1,
+        query_address: Some("192.0.2.1:5353".parse().unwrap()),
+        response_address: None,
+        query_time,
+        query_message: Some(&[0, 1]),
+        response_time: None,
+        response_message: None,
+    };
+    let encoded = encode(&message);
+    let inner_start = 2 + VERSION.len() + 2;
+    assert_eq!(encoded[inner_start - 2], 14 << 3 | 2);
+    assert_eq!(
+        &encoded[inner_start..inner_start + 13],
+        &[8, 5, 16, 1, 24, 1, 34, 4, 192, 0, 2, 1, 48]
+    );
+    assert!(encoded.ends_with(&[120, 1]));
+    let mut varint = Vec::new();
+    put_varint(&mut varint, 300);
+    assert_eq!(varint, [0xac, 0x02]);
+
+    // a collector accepting the stream, then reading the start frame and one data frame
+    let path = std::env::temp_dir().join(format!("test_dnstap_{}", std::process::id()));
+    let listener = UnixListener::bind(&path).unwrap();
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let writer_path = path.clone();
+    std::thread::spawn(move || write_socket(writer_path, receiver));
+    let (mut stream, _) = listener.accept().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let ready = control_frame(CONTROL_READY);
+    let mut received = vec![0; ready.len()];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(received, ready);
+    stream.write_all(&control_frame(CONTROL_ACCEPT)).unwrap();
+    let start = control_frame(CONTROL_START);
+    let mut received = vec![0; start.len()];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(received, start);
+
+    sender.send(encoded.clone()).unwrap();
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame).unwrap();
+    assert_eq!(frame, encoded);
+}
@@ -0,0 +1,227 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+use crate::server::{Server, Transport, TCP_MAX_SIZE};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// base64url, which the `dns` parameter of GET requests is encoded with. RFC
+/// 8484 leaves out the padding, some clients add it anyway.
+const DNS_PARAMETER: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Accepts DNS over HTTPS connections (RFC 8484), speaking HTTP/2 or
+/// HTTP/1.1 as the client's ALPN picks. Queries go to the same handler as
+/// the other listeners, on threads of their own since it blocks.
+pub fn serve_https(server: Arc<Server>, listener: TcpListener, config: Arc<ServerConfig>) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the HTTPS runtime");
+
+    runtime.block_on(async move {
+        listener
+            .set_nonblocking(true)
+            .expect("Failed to set up the HTTPS listener");
+        let listener = tokio::net::TcpListener::from_std(listener)
+            .expect("Failed to set up the HTTPS listener");
+        let acceptor = TlsAcceptor::from(config);
+
+        loop {
+            let (stream, client) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Error accepting HTTPS connection: {}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&server);
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Error on TLS handshake with {client}: {}", e);
+                        return;
+                    }
+                };
+                let service = service_fn(|request| respond(Arc::clone(&server), request, client));
+                if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    warn!("Error on HTTPS connection: {}", e);
+                }
+            });
+        }
+    });
+}
+
+async fn respond(
+    server: Arc<Server>,
+    request: Request<Incoming>,
+    client: SocketAddr,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let (parts, body) = request.into_parts();
+    let body = match Limited::new(body, TCP_MAX_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(status(StatusCode::PAYLOAD_TOO_LARGE)),
+    };
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let query = match dns_query(&parts.method, &parts.uri, content_type, &body) {
+        Ok(query) => query,
+        Err(code) => return Ok(status(code)),
+    };
+
+    let responses =
+        tokio::task::spawn_blocking(move || server.handle(&query, client, Transport::Https)).await;
+    let responses = match responses {
+        Ok(responses) => responses,
+        Err(e) => {
+            warn!("Failed to answer DNS over HTTPS query from {client}: {e}");
+            return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+    // zone transfers, which get more than one, need a DNS over TCP client, and
+    // clients turned away get none
+    let Some(response) = responses.into_iter().next() else {
+        return Ok(status(StatusCode::FORBIDDEN));
+    };
+
+    let mut builder = Response::builder()
+        .header(CONTENT_TYPE, DNS_MESSAGE)
+        .header(hyper::header::CONTENT_LENGTH, response.len());
+    if let Some(max_age) = max_age(&response) {
+        builder = builder.header(CACHE_CONTROL, format!("max-age={max_age}"));
+    }
+
+    Ok(builder.body(Full::new(Bytes::from(response))).unwrap())
+}
+
+fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = code;
+    response
+}
+
+/// The raw DNS query of a request to `/dns-query`: the base64url `dns`
+/// parameter of a GET, or the body of a POST.
+fn dns_query(
+    method: &Method,
+    uri: &hyper::Uri,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Vec<u8>, StatusCode> {
+    if uri.path() != "/dns-query" {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *method {
+        Method::GET => {
+            let parameter = uri
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("dns="))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            DNS_PARAMETER
+                .decode(parameter)
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+        }
+        Method::POST if content_type == Some(DNS_MESSAGE) => body.to_vec(),
+        Method::POST => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    // shorter than a header, and the handler would choke on it
+    if query.len() < 12 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(query)
+}
+
+/// How long HTTP caches may keep the raw DNS `response`: the lowest TTL of
+/// its answers, or of the records saying there's no answer such as an SOA.
+fn max_age(response: &[u8]) -> Option<u32> {
+    let response = crate::deserialize(response).ok()?;
+    let records = if response.answers.is_empty() {
+        &response.authority
+    } else {
+        &response.answers
+    };
+
+    records
+        .iter()
+        .map(|record| record.time_to_live.max(0) as u32)
+        .min()
+}
+
+#[test]
+fn test_dns_query() {
+    use crate::dns_record::{DnsClass, DnsRecord, DnsType};
+
+    // RFC 8484 section 4.1.1: www.example.com A, id 0
+    let query = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+    let raw = DNS_PARAMETER.decode(query).unwrap();
+    let get = |uri: &str| dns_query(&Method::GET, &uri.parse().unwrap(), None, &[]);
+
+    assert_eq!(get(&format!("/dns-query?dns={query}")), Ok(raw.clone()));
+    let padded = base64::engine::general_purpose::URL_SAFE.encode(&raw[..32]);
+    assert!(padded.ends_with('='));
+    assert_eq!(
+        get(&format!("/dns-query?ct&dns={padded}")),
+        Ok(raw[..32].to_vec())
+    );
+    assert_eq!(get("/dns-query?dns=!!"), Err(StatusCode::BAD_REQUEST));
+    assert_eq!(get("/dns-query?dns=AAAB"), Err(StatusCode::BAD_REQUEST));
+    assert_eq!(get("/dns-query"), Err(StatusCode::BAD_REQUEST));
+    assert_eq!(get(&format!("/?dns={query}")), Err(StatusCode::NOT_FOUND));
+
+    let uri = "/dns-query".parse().unwrap();
+    let post = |content_type| dns_query(&Method::POST, &uri, content_type, &raw);
+    assert_eq!(post(Some(DNS_MESSAGE)), Ok(raw.clone()));
+    assert_eq!(
+        post(Some("text/plain")),
+        Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    );
+    assert_eq!(
+        dns_query(&Method::PUT, &uri, Some(DNS_MESSAGE), &raw),
+        Err(StatusCode::METHOD_NOT_ALLOWED)
+    );
+
+    let mut response = crate::deserialize(&raw).unwrap();
+    assert_eq!(max_age(&crate::serialize(&response)), None);
+    for ttl in [300, 60, 3600] {
+        response.answers.push(DnsRecord {
+            name: "www.example.com".into(),
+            dns_type: DnsType::A,
+            dns_class: DnsClass::IN,
+            time_to_live: ttl,
+            rd_length: 4,
+            rd_data: vec![192, 0, 2, 1],
+        });
+    }
+    assert_eq!(max_age(&crate::serialize(&response)), Some(60));
+}
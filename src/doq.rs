@@ -0,0 +1,253 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connection, RecvStream, SendStream, VarInt};
+use rustls::server::ProducesTickets;
+use rustls::ServerConfig;
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::dns_header::OpCode;
+use crate::server::{Server, Transport, TCP_MAX_SIZE};
+
+/// The RFC 9250 error code connections sending malformed queries are closed with.
+const DOQ_PROTOCOL_ERROR: u32 = 2;
+
+/// The QUIC setup for DNS over QUIC from the TLS one, which must offer the
+/// `doq` ALPN protocol. Connections are closed once idle for `idle_timeout`.
+pub fn quic_config(
+    config: Arc<ServerConfig>,
+    idle_timeout: Duration,
+) -> anyhow::Result<quinn::ServerConfig> {
+    let mut config = (*config).clone();
+    // allows 0-RTT, which QUIC takes all or nothing
+    config.max_early_data_size = u32::MAX;
+    config.ticketer = Arc::new(NoTickets);
+    let crypto = QuicServerConfig::try_from(config).context("setting up QUIC")?;
+
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_idle_timeout(Some(idle_timeout.try_into().context("idle timeout")?))
+        .max_concurrent_uni_streams(VarInt::from_u32(0));
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+
+    Ok(config)
+}
+
+/// Leaves resumption to the session cache, rustls only taking 0-RTT data
+/// from sessions that can be resumed once.
+#[derive(Debug)]
+struct NoTickets;
+
+impl ProducesTickets for NoTickets {
+    fn enabled(&self) -> bool {
+        false
+    }
+
+    fn lifetime(&self) -> u32 {
+        0
+    }
+
+    fn encrypt(&self, _plain: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn decrypt(&self, _cipher: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Accepts DNS over QUIC connections (RFC 9250), each query coming on a
+/// stream of its own. Queries in 0-RTT data are answered right away, the
+/// rest, which can't be safely replayed, once the handshake is done.
+pub fn serve_quic(server: Arc<Server>, socket: UdpSocket, config: quinn::ServerConfig) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the QUIC runtime");
+
+    runtime.block_on(async move {
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )
+        .expect("Failed to set up the QUIC listener");
+
+        while let Some(incoming) = endpoint.accept().await {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let client = incoming.remote_address();
+                let connecting = match incoming.accept() {
+                    Ok(connecting) => connecting,
+                    Err(e) => {
+                        warn!("Error accepting QUIC connection from {client}: {}", e);
+                        return;
+                    }
+                };
+                let Ok((connection, accepted)) = connecting.into_0rtt() else {
+                    return;
+                };
+
+                let (done, handshake) = watch::channel(false);
+                tokio::spawn(async move {
+                    if accepted.await {
+                        let _ = done.send(true);
+                    }
+                });
+                serve_connection(server, connection, client, handshake).await;
+            });
+        }
+    });
+}
+
+async fn serve_connection(
+    server: Arc<Server>,
+    connection: Connection,
+    client: SocketAddr,
+    handshake: watch::Receiver<bool>,
+) {
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(quinn::ConnectionError::ApplicationClosed(_))
+            | Err(quinn::ConnectionError::TimedOut) => return,
+            Err(e) => {
+                warn!("Error on QUIC connection from {client}: {}", e);
+                return;
+            }
+        };
+
+        let server = Arc::clone(&server);
+        let connection = connection.clone();
+        let handshake = handshake.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_stream(server, send, recv, client, handshake).await {
+                warn!("Error on QUIC stream from {client}: {e:#}");
+                connection.close(VarInt::from_u32(DOQ_PROTOCOL_ERROR), b"");
+            }
+        });
+    }
+}
+
+async fn serve_stream(
+    server: Arc<Server>,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    client: SocketAddr,
+    mut handshake: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let message = recv.read_to_end(TCP_MAX_SIZE + 2).await?;
+    let query = unframe(&message)?.to_vec();
+    if OpCode::from((query[2] >> 3) & 0xf) != OpCode::StandardQuery {
+        handshake
+            .wait_for(|done| *done)
+            .await
+            .context("handshake failed")?;
+    }
+
+    let responses =
+        tokio::task::spawn_blocking(move || server.handle(&query, client, Transport::Quic)).await?;
+    // zone transfers answer on the one stream
+    for response in responses {
+        send.write_all(&(response.len() as u16).to_be_bytes())
+            .await?;
+        send.write_all(&response).await?;
+    }
+    send.finish()?;
+    let _ = send.stopped().await;
+
+    Ok(())
+}
+
+/// The DNS message of a stream, sent whole after its 2-byte length. Its id
+/// must be 0, the stream telling the answers apart.
+fn unframe(message: &[u8]) -> anyhow::Result<&[u8]> {
+    let Some((len, query)) = message.split_first_chunk::<2>() else {
+        anyhow::bail!("stream without a message");
+    };
+    if u16::from_be_bytes(*len) as usize != query.len() || query.len() < 12 {
+        anyhow::bail!("malformed message of {} bytes", query.len());
+    }
+    if query[..2] != [0, 0] {
+        anyhow::bail!("message id isn't 0");
+    }
+
+    Ok(query)
+}
+
+#[test]
+fn test_doq() {
+    use crate::upstream_tls::{QuicUpstream, TlsClients};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["dns.test".into()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("test_doq_cert_{}", std::process::id()));
+    let key_path = dir.join(format!("test_doq_key_{}", std::process::id()));
+    std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+    std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+    let config = crate::tls::server_config(&cert_path, &key_path, &[b"doq"]).unwrap();
+    let clients = TlsClients::new(Some(&cert_path), &[]).unwrap();
+    std::fs::remove_file(&cert_path).unwrap();
+    std::fs::remove_file(&key_path).unwrap();
+    let config = quic_config(config, Duration::from_secs(5)).unwrap();
+
+    // echoes each query with its id 0 and its header's last byte bumped
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let endpoint = quinn::Endpoint::new(
+                quinn::EndpointConfig::default(),
+                Some(config),
+                socket,
+                Arc::new(quinn::TokioRuntime),
+            )
+            .unwrap();
+            while let Some(incoming) = endpoint.accept().await {
+                tokio::spawn(async move {
+                    let Ok(connection) = incoming.await else {
+                        return;
+                    };
+                    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                        let message = recv.read_to_end(TCP_MAX_SIZE + 2).await.unwrap();
+                        let mut response = unframe(&message).unwrap().to_vec();
+                        response[11] += 1;
+                        send.write_all(&(response.len() as u16).to_be_bytes())
+                            .await
+                            .unwrap();
+                        send.write_all(&response).await.unwrap();
+                        send.finish().unwrap();
+                    }
+                });
+            }
+        });
+    });
+
+    // the second upstream resumes the first one's session, its query going in 0-RTT data
+    let upstream = Arc::new(QuicUpstream::new(address, "dns.test", &clients).unwrap());
+    let resumed = Arc::new(QuicUpstream::new(address, "dns.test", &clients).unwrap());
+    let timeout = Duration::from_secs(2);
+    for (upstream, id, last) in [(&upstream, 7, 1), (&upstream, 8, 2), (&resumed, 9, 3)] {
+        let mut query = vec![0, id, 1, 0, 0, 1, 0, 0, 0, 0, 0, last];
+        let response = upstream.forward(&query, timeout).unwrap();
+        query[11] += 1;
+        assert_eq!(response, query);
+    }
+    let other_name = Arc::new(QuicUpstream::new(address, "other.test", &clients).unwrap());
+    assert!(other_name.forward(&[0, 1, 1, 0], timeout).is_err());
+
+    assert!(unframe(&[0, 12, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]).is_ok());
+    assert!(unframe(&[0, 12, 0, 5, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]).is_err());
+    assert!(unframe(&[0, 13, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]).is_err());
+    assert!(unframe(&[0]).is_err());
+}
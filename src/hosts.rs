@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::dns_header::{DnsHeader, ResponseCode, QR};
+use crate::dns_record::{is_valid_name, serialize_name, DnsClass, DnsRecord, DnsType};
+use crate::DnsMsg;
+
+/// Names pinned to addresses by hosts files and static records, answered for
+/// A, AAAA and PTR queries without asking the upstream.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    ttl: i32,
+    /// Keyed by the lowercased name.
+    addresses: BTreeMap<String, Vec<IpAddr>>,
+    /// Keyed by the reverse name of the address, the first name given for it.
+    names: BTreeMap<String, String>,
+}
+
+impl Hosts {
+    pub fn new(ttl: u32) -> Hosts {
+        Hosts {
+            ttl: ttl.min(i32::MAX as u32) as i32,
+            ..Hosts::default()
+        }
+    }
+
+    /// Pins `name` to `address`. The first name pinned to an address is the
+    /// one its PTR query gets, like in hosts files.
+    pub fn insert(&mut self, name: &str, address: IpAddr) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let addresses = self.addresses.entry(name.clone()).or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+        self.names.entry(reverse_name(address)).or_insert(name);
+    }
+
+    /// Adds the entries of an `/etc/hosts` style file: an address followed by
+    /// its canonical name and aliases on each line, `#` starting a comment.
+    /// Lines with an address we can't read are skipped, as the C library does.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(address) = fields.next() else {
+                continue;
+            };
+            let Ok(address) = address.parse::<IpAddr>() else {
+                warn!(
+                    "{}:{}: skipping invalid address {address}",
+                    path.display(),
+                    number + 1
+                );
+                continue;
+            };
+            for name in fields {
+                if !is_valid_name(name) {
+                    warn!(
+                        "{}:{}: skipping invalid name {name}",
+                        path.display(),
+                        number + 1
+                    );
+                    continue;
+                }
+                self.insert(name, address);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The A and AAAA records pinned for `name`.
+    pub fn addresses(&self, name: &str) -> Vec<DnsRecord> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let Some(addresses) = self.addresses.get(&name) else {
+            return vec![];
+        };
+
+        addresses
+            .iter()
+            .map(|address| {
+                let (dns_type, rd_data) = match address {
+                    IpAddr::V4(address) => (DnsType::A, address.octets().to_vec()),
+                    IpAddr::V6(address) => (DnsType::Aaaa, address.octets().to_vec()),
+                };
+                self.record(&name, dns_type, rd_data)
+            })
+            .collect()
+    }
+
+    fn record(&self, name: &str, dns_type: DnsType, rd_data: Vec<u8>) -> DnsRecord {
+        DnsRecord {
+            name: name.to_string(),
+            dns_type,
+            dns_class: DnsClass::IN,
+            time_to_live: self.ttl,
+            rd_length: rd_data.len() as u16,
+            rd_data,
+        }
+    }
+
+    /// The response to an A, AAAA or PTR query for a pinned name or address,
+    /// which has no data rather than being forwarded when only the other
+    /// address family is pinned. `None` for anything else.
+    pub fn answer(&self, query: &DnsMsg) -> Option<DnsMsg> {
+        let [question] = query.questions.as_slice() else {
+            return None;
+        };
+        if !matches!(question.dns_class, DnsClass::IN | DnsClass::AnyClass) {
+            return None;
+        }
+        let name = question.name.trim_end_matches('.').to_ascii_lowercase();
+
+        let answers = match question.dns_type {
+            DnsType::A | DnsType::Aaaa if self.addresses.contains_key(&name) => self
+                .addresses(&name)
+                .into_iter()
+                .filter(|record| record.dns_type == question.dns_type)
+                .collect(),
+            DnsType::Ptr => {
+                let target = self.names.get(&name)?;
+                vec![self.record(&name, DnsType::Ptr, serialize_name(target))]
+            }
+            _ => return None,
+        };
+
+        Some(DnsMsg {
+            header: DnsHeader {
+                query: QR::Response,
+                aa: false,
+                tc: false,
+                ra: true,
+                z: 0,
+                response_code: ResponseCode::NoError,
+                answers_count: answers.len() as u16,
+                authority_count: 0,
+                additional_count: 0,
+                ..query.header
+            },
+            questions: query.questions.clone(),
+            answers,
+            authority: vec![],
+            additional: vec![],
+        })
+    }
+}
+
+/// The name PTR queries for `address` ask about, under in-addr.arpa or ip6.arpa.
+pub fn reverse_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(address) => {
+            let mut labels: Vec<String> = address
+                .octets()
+                .iter()
+                .flat_map(|byte| [byte >> 4, byte & 0xf])
+                .map(|nibble| format!("{nibble:x}"))
+                .collect();
+            labels.reverse();
+            labels.push("ip6.arpa".into());
+            labels.join(".")
+        }
+    }
+}
+
+#[test]
+fn test_hosts() {
+    use crate::{deserialize, serialize};
+
+    let path = std::env::temp_dir().join(format!("test_hosts_{}", std::process::id()));
+    std::fs::write(
+        &path,
+        "# static entries\n127.0.0.1 localhost\n::1 localhost ip6-localhost\n\
+         10.1.2.3\tapi.internal api  # the API\nnot-an-address broken\n",
+    )
+    .unwrap();
+    let mut hosts = Hosts::new(60);
+    hosts.load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    hosts.insert("DB.internal.", "10.1.2.4".parse().unwrap());
+    hosts.insert("db-alias.internal", "10.1.2.4".parse().unwrap());
+
+    let query = |name: &str, dns_type: DnsType| {
+        let mut query = deserialize(&[0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1]).unwrap();
+        query.questions[0].name = name.into();
+        query.questions[0].dns_type = dns_type;
+        hosts
+            .answer(&query)
+            .map(|response| deserialize(&serialize(&response)).unwrap())
+    };
+
+    let response = query("API.internal", DnsType::A).unwrap();
+    assert_eq!(response.header.id, 7);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].rd_data, vec![10, 1, 2, 3]);
+    assert_eq!(response.answers[0].time_to_live, 60);
+    assert_eq!(query("localhost", DnsType::Aaaa).unwrap().answers.len(), 1);
+    // only an IPv4 address pinned: no data, rather than asking the upstream
+    let response = query("api.internal", DnsType::Aaaa).unwrap();
+    assert_eq!(response.header.response_code, ResponseCode::NoError);
+    assert!(response.answers.is_empty());
+    assert!(query("api.internal", DnsType::MX).is_none());
+    assert!(query("broken", DnsType::A).is_none());
+    assert!(query("example.com", DnsType::A).is_none());
+
+    // the first name given to an address is the one PTR queries get
+    let response = query("4.2.1.10.in-addr.arpa", DnsType::Ptr).unwrap();
+    assert_eq!(response.answers[0].rd_data, serialize_name("db.internal"));
+    let response = query(&reverse_name("::1".parse().unwrap()), DnsType::Ptr).unwrap();
+    assert_eq!(response.answers[0].rd_data, serialize_name("localhost"));
+    assert!(query("9.9.9.10.in-addr.arpa", DnsType::Ptr).is_none());
+
+    assert_eq!(
+        reverse_name("2001:db8::1".parse().unwrap()),
+        "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+    );
+}
@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use tracing::warn;
+
+use crate::dns_record::{DnsRecord, DnsType};
+use crate::zone::{soa_serial, Zone};
+use crate::zone_file::{format_record, parse_zone};
+
+/// How many changes a journal remembers. Secondaries further behind get an AXFR.
+const MAX_JOURNAL_CHANGES: usize = 64;
+
+/// The records removed and added to go from one version of a zone to the next,
+/// in the shape IXFR sends them (RFC 1995 section 4).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Change {
+    /// Starts with the SOA of the old version.
+    pub deleted: Vec<DnsRecord>,
+    /// Starts with the SOA of the new version.
+    pub added: Vec<DnsRecord>,
+}
+
+impl Change {
+    pub fn old_serial(&self) -> Option<u32> {
+        self.deleted.first().and_then(soa_serial)
+    }
+
+    pub fn new_serial(&self) -> Option<u32> {
+        self.added.first().and_then(soa_serial)
+    }
+}
+
+/// The latest changes of a zone, saved to `path` when there's one.
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    pub changes: Vec<Change>,
+    pub path: Option<PathBuf>,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for a zone currently at `serial`. A journal
+    /// that doesn't end at that serial is stale and starts over empty.
+    pub fn open(path: PathBuf, serial: u32) -> Journal {
+        let changes = match load_changes(&path) {
+            Ok(changes) => changes,
+            Err(e) => {
+                warn!("Ignoring journal {}: {e:#}", path.display());
+                vec![]
+            }
+        };
+
+        let mut journal = Journal {
+            changes,
+            path: Some(path),
+        };
+        if journal
+            .changes
+            .last()
+            .is_some_and(|last| last.new_serial() != Some(serial))
+        {
+            warn!(
+                "Journal {} doesn't end at serial {serial}, starting over",
+                journal.path.as_ref().unwrap().display()
+            );
+            journal.changes.clear();
+        }
+
+        journal
+    }
+
+    /// Records `change`, forgetting the oldest ones past `MAX_JOURNAL_CHANGES`.
+    pub fn append(&mut self, change: Change) -> anyhow::Result<()> {
+        self.changes.push(change);
+        if self.changes.len() > MAX_JOURNAL_CHANGES {
+            self.changes.remove(0);
+        }
+
+        match &self.path {
+            Some(path) => save_changes(path, &self.changes)
+                .with_context(|| format!("saving journal {}", path.display())),
+            None => Ok(()),
+        }
+    }
+
+    /// The changes leading from `serial` to the latest version, if the journal goes back that far.
+    pub fn changes_since(&self, serial: u32) -> Option<&[Change]> {
+        let start = self
+            .changes
+            .iter()
+            .position(|change| change.old_serial() == Some(serial))?;
+
+        Some(&self.changes[start..])
+    }
+}
+
+/// The change that turns `old` into `new`.
+pub fn diff(old: &Zone, new: &Zone) -> Change {
+    let old_records: Vec<&DnsRecord> = old.records().collect();
+    let new_records: Vec<&DnsRecord> = new.records().collect();
+    // the records of `records` other than the SOA that `others` doesn't have
+    let missing_from = |records: &[&DnsRecord], others: &[&DnsRecord]| -> Vec<DnsRecord> {
+        records
+            .iter()
+            .filter(|record| record.dns_type != DnsType::Soa)
+            .filter(|record| !others.iter().any(|other| same_record(other, record)))
+            .map(|record| (*record).clone())
+            .collect()
+    };
+
+    let mut deleted = vec![old.soa().clone()];
+    deleted.extend(missing_from(&old_records, &new_records));
+    let mut added = vec![new.soa().clone()];
+    added.extend(missing_from(&new_records, &old_records));
+
+    Change { deleted, added }
+}
+
+/// Whether two records are the same data, whatever the case of their owner names.
+pub fn same_record(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.name.eq_ignore_ascii_case(&b.name)
+        && a.dns_type == b.dns_type
+        && a.dns_class == b.dns_class
+        && a.time_to_live == b.time_to_live
+        && a.rd_data == b.rd_data
+}
+
+/// Journals are master file lines, `-` for deleted records and `+` for added ones.
+fn save_changes(path: &Path, changes: &[Change]) -> std::io::Result<()> {
+    let mut text = String::new();
+    for change in changes {
+        for (sign, records) in [('-', &change.deleted), ('+', &change.added)] {
+            for record in records {
+                text.push(sign);
+                text.push_str(&format_record(record));
+                text.push('\n');
+            }
+        }
+    }
+
+    let tmp = path.with_extension("jnl.tmp");
+    std::fs::write(&tmp, text)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn load_changes(path: &Path) -> anyhow::Result<Vec<Change>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let text = std::fs::read_to_string(path)?;
+    let mut changes: Vec<Change> = Vec::new();
+    for (i, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+    {
+        let (sign, record) = line.split_at(1);
+        let mut records = parse_zone(record, "", Path::new("."))?;
+        let Some(record) = records.pop() else {
+            bail!("line {} has no record", i + 1);
+        };
+        let is_soa = record.dns_type == DnsType::Soa;
+
+        match sign {
+            // a deleted SOA starts the next change
+            "-" if is_soa => changes.push(Change {
+                deleted: vec![record],
+                added: vec![],
+            }),
+            "-" | "+" => {
+                let Some(change) = changes.last_mut() else {
+                    bail!("line {} comes before the first SOA", i + 1);
+                };
+                if sign == "-" && !change.added.is_empty() {
+                    bail!("line {} deletes a record after the additions", i + 1);
+                }
+                if sign == "+" && change.added.is_empty() && !is_soa {
+                    bail!("line {} adds a record before the new SOA", i + 1);
+                }
+                match sign {
+                    "-" => change.deleted.push(record),
+                    _ => change.added.push(record),
+                }
+            }
+            _ => bail!("line {} doesn't start with - or +", i + 1),
+        }
+    }
+    if changes.iter().any(|change| change.added.is_empty()) {
+        bail!("a change has no new SOA");
+    }
+
+    Ok(changes)
+}
+
+#[test]
+fn test_journal() {
+    let zone = |text: &str| {
+        let text = format!("$TTL 300\n{text}");
+        let records = parse_zone(&text, "example.com", Path::new(".")).unwrap();
+        Zone::new("example.com", records).unwrap()
+    };
+    let v1 = zone("@ SOA ns hostmaster 1 60 60 600 60\nwww A 192.0.2.1\nold A 192.0.2.2\n");
+    let v2 = zone("@ SOA ns hostmaster 2 60 60 600 60\nwww A 192.0.2.1\nnew A 192.0.2.3\n");
+    let v3 = zone("@ SOA ns hostmaster 3 60 60 600 60\nwww A 192.0.2.9\nnew A 192.0.2.3\n");
+
+    let first = diff(&v1, &v2);
+    assert_eq!((first.old_serial(), first.new_serial()), (Some(1), Some(2)));
+    assert_eq!(first.deleted[1].name, "old.example.com");
+    assert_eq!(first.added[1].name, "new.example.com");
+    assert_eq!((first.deleted.len(), first.added.len()), (2, 2));
+
+    let path = std::env::temp_dir().join(format!("journal-test-{}.jnl", std::process::id()));
+    let mut journal = Journal::open(path.clone(), 1);
+    journal.append(first).unwrap();
+    journal.append(diff(&v2, &v3)).unwrap();
+    assert_eq!(journal.changes_since(1).unwrap().len(), 2);
+    assert_eq!(journal.changes_since(2).unwrap().len(), 1);
+    assert!(journal.changes_since(0).is_none());
+
+    // reopened from disk, and dropped when the zone moved on without it
+    assert_eq!(Journal::open(path.clone(), 3).changes, journal.changes);
+    assert!(Journal::open(path.clone(), 4).changes.is_empty());
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,99 @@
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use crate::dns_header::rcode_name;
+use crate::dns_record::raw_question;
+use crate::server::Transport;
+use crate::zone_file::type_name;
+
+/// The target of the one line logged per query, which filters can turn off
+/// with `query=off`.
+pub const QUERY_TARGET: &str = "query";
+
+/// How log lines are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object a line, the fields of an event at its top level.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {value}, expected text or json")),
+        }
+    }
+}
+
+/// Logs to stderr whatever `filter` lets through, written as `format`.
+/// `filter` takes the directives of `RUST_LOG`, like
+/// `info,codecrafters_dns_server::secondary=debug,query=off`.
+pub fn init(filter: &str, format: LogFormat) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(filter).context("invalid log filter")?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+
+    result.map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Logs the raw `request` from `client` and its first raw `response`, if it
+/// got any, which took `elapsed` and was forwarded to `upstream`, if it was.
+pub fn log_query(
+    request: &[u8],
+    response: Option<&[u8]>,
+    client: SocketAddr,
+    transport: Transport,
+    elapsed: Duration,
+    upstream: Option<&str>,
+) {
+    let (qname, qtype) = match raw_question(request) {
+        Some((name, dns_type)) => (name, type_name(dns_type)),
+        None => (String::new(), "none".to_string()),
+    };
+    let rcode = match response.and_then(|response| response.get(3)) {
+        Some(flags) => rcode_name(flags & 0x0f),
+        None => "none".to_string(),
+    };
+
+    info!(
+        target: QUERY_TARGET,
+        %client,
+        %qname,
+        %qtype,
+        %rcode,
+        latency_ms = elapsed.as_secs_f64() * 1000.0,
+        upstream,
+        transport = transport.name(),
+    );
+}
+
+#[test]
+fn test_logging() {
+    use crate::dns_record::DnsType;
+
+    assert_eq!("json".parse(), Ok(LogFormat::Json));
+    assert!("yaml".parse::<LogFormat>().is_err());
+    assert!(init("info,query=nope", LogFormat::Text).is_err());
+
+    let mut query = [0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 28, 0, 1];
+    assert_eq!(raw_question(&query), Some(("a".into(), DnsType::Aaaa)));
+    query[5] = 0;
+    assert_eq!(raw_question(&query), None);
+}
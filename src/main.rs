@@ -1,11 +1,52 @@
-use std::net::{SocketAddrV4, UdpSocket};
-
-use dns_header::{deserialize_header, DnsHeader};
-use dns_header::{serialize_header, QR};
-use dns_record::{deserialize_record, serialize_record, DnsRecord};
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::Duration;
+
+use acl::{Acls, DeniedResponse, Grantee, Prefix};
+use blocklist::{BlockResponse, Blocklist};
+use dns_header::{deserialize_header, serialize_header, DnsHeader};
+use dns_record::{deserialize_record, is_valid_name, serialize_record, DnsRecord};
+use hosts::Hosts;
+use journal::Journal;
+use logging::LogFormat;
+use rpz::PolicyZone;
+use rrl::{RateLimiter, RrlOptions};
+use secondary::{Secondary, SecondaryHandle};
+use server::{Server, Settings};
+use tsig::Key;
+use update::UpdateRule;
+use upstream::{Endpoint, Forwarder, Forwarders, Upstream};
+use upstream_tls::{SpkiPin, TlsClients};
+use zone::{Catalog, Zone, ZoneOptions};
+mod acl;
+mod additional;
+mod blocklist;
+mod config;
 mod dns_header;
 mod dns_record;
+mod dnstap;
+mod doh;
+mod doq;
+mod hosts;
+mod journal;
+mod logging;
+mod metrics;
+mod notify;
+mod rpz;
+mod rrl;
+mod secondary;
+mod server;
+mod tls;
+mod transfer;
+mod tsig;
+mod update;
+mod upstream;
+mod upstream_tls;
 mod utils;
+mod zone;
+mod zone_file;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct DnsMsg {
@@ -17,170 +58,754 @@ struct DnsMsg {
 }
 
 fn serialize(msg: &DnsMsg) -> Vec<u8> {
-    // let mut bytes = [0; 12];
-    let mut bytes = serialize_header(&msg.header).to_vec();
+    let mut header = msg.header;
+    header.questions_count = msg.questions.len() as u16;
+    header.answers_count = msg.answers.len() as u16;
+    header.authority_count = msg.authority.len() as u16;
+    header.additional_count = msg.additional.len() as u16;
+
+    let mut bytes = serialize_header(&header).to_vec();
 
     for record in &msg.questions {
-        let record_bytes = serialize_record(record);
+        let record_bytes = serialize_record(record, false);
         bytes.extend_from_slice(&record_bytes);
     }
 
-    for record in &msg.answers {
-        let record_bytes = serialize_record(record);
+    for record in msg
+        .answers
+        .iter()
+        .chain(&msg.authority)
+        .chain(&msg.additional)
+    {
+        let record_bytes = serialize_record(record, true);
         bytes.extend_from_slice(&record_bytes);
     }
 
     bytes
 }
 
-fn deserialize(msg_bytes: &[u8]) -> DnsMsg {
+fn deserialize(msg_bytes: &[u8]) -> anyhow::Result<DnsMsg> {
+    const HEADER_N_BYTES: usize = 12;
+    if msg_bytes.len() < HEADER_N_BYTES {
+        bail!("message shorter than its header");
+    }
     let header = deserialize_header(msg_bytes);
 
     let bytes = msg_bytes;
-    const HEADER_N_BYTES: usize = 12;
 
     let deserialize_records =
-        |n_records: u16, index: usize, have_rd: bool| -> (Vec<DnsRecord>, usize) {
-            let mut records = Vec::with_capacity(n_records as usize);
+        |n_records: u16, index: usize, have_rd: bool| -> anyhow::Result<(Vec<DnsRecord>, usize)> {
+            let mut records = Vec::new();
             let mut index = index;
             for _i in 0..n_records {
                 let record;
-                (record, index) = deserialize_record(bytes, index, have_rd);
+                (record, index) = deserialize_record(bytes, index, have_rd)?;
                 records.push(record);
                 index += 1;
                 // bytes = &bytes[index..]
             }
 
-            (records, index)
+            Ok((records, index))
         };
 
-    let (questions, index) = deserialize_records(header.questions_count, HEADER_N_BYTES + 1, false);
-    let (answers, index) = deserialize_records(header.answers_count, index, true);
-    let (authority, index) = deserialize_records(header.authority_count, index, false);
-    let (additional, _index) = deserialize_records(header.additional_count, index, false);
+    let (questions, index) =
+        deserialize_records(header.questions_count, HEADER_N_BYTES + 1, false)?;
+    let (answers, index) = deserialize_records(header.answers_count, index, true)?;
+    let (authority, index) = deserialize_records(header.authority_count, index, true)?;
+    let (additional, _index) = deserialize_records(header.additional_count, index, true)?;
 
-    DnsMsg {
+    Ok(DnsMsg {
         header,
         questions,
         answers,
         authority,
         additional,
+    })
+}
+
+/// Drops additional records until `msg` fits in `max_size` bytes. If the answer
+/// still doesn't fit, the message is marked truncated so the client retries over TCP.
+fn truncate(msg: &mut DnsMsg, max_size: usize) {
+    while serialize(msg).len() > max_size && msg.additional.pop().is_some() {}
+
+    if serialize(msg).len() > max_size {
+        msg.header.tc = true;
+        msg.answers.clear();
+        msg.authority.clear();
     }
+    msg.header.answers_count = msg.answers.len() as u16;
+    msg.header.authority_count = msg.authority.len() as u16;
+    msg.header.additional_count = msg.additional.len() as u16;
 }
 
-use clap::Parser;
+use anyhow::{bail, Context};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use tracing::{info, warn};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_override_self = true)]
 struct Args {
-    /// the socket <address> where <address> will be of the form <ip>:<port>
+    /// a TOML file with more of these options, which a SIGHUP rereads along
+    /// with the zones and lists they name; see config.rs for its layout
     #[arg(short, long)]
-    resolver: SocketAddrV4,
+    config: Option<PathBuf>,
+
+    /// the upstream the queries we can't answer go to: <ip>[:<port>] over UDP,
+    /// tls://<host>[:<port>][#<server name>], https://<host>[:<port>]/<path> or
+    /// quic://<host>[:<port>][#<server name>]
+    #[arg(short, long)]
+    resolver: Option<Endpoint>,
+
+    /// where the queries for the names under a suffix go instead of the resolver,
+    /// given as <suffix>=<upstream>[,...], the longest matching suffix winning
+    /// and the upstreams tried in order
+    #[arg(long = "forward-zone", value_parser = parse_forward_zone_arg)]
+    forward_zones: Vec<(String, Vec<Endpoint>)>,
+
+    /// how long to wait for each server of a forward zone, given as
+    /// <suffix>=<milliseconds>, `.` being the resolver's suffix
+    #[arg(long = "forward-timeout", value_parser = parse_forward_timeout_arg)]
+    forward_timeouts: Vec<(String, Duration)>,
+
+    /// the PEM certificates trusted for DNS over TLS and HTTPS upstreams instead
+    /// of the Mozilla root program's
+    #[arg(long)]
+    upstream_ca: Option<PathBuf>,
+
+    /// a key an upstream's certificate chain must hold, given as
+    /// <server name>=<base64 SHA-256 of the SubjectPublicKeyInfo>
+    #[arg(long = "upstream-pin")]
+    upstream_pins: Vec<SpkiPin>,
+
+    /// an authoritative zone to serve, given as <origin>=<path to master file>
+    #[arg(short, long = "zone", value_parser = parse_zone_arg)]
+    zones: Vec<(String, PathBuf)>,
+
+    /// a zone to serve as a copy of the one on a primary, given as <origin>=<ip>:<port>
+    #[arg(long = "secondary", value_parser = parse_secondary_arg)]
+    secondaries: Vec<(String, SocketAddr)>,
+
+    /// the directory where secondary zones are saved between restarts
+    #[arg(long, default_value = ".")]
+    secondary_dir: PathBuf,
+
+    /// who may transfer a zone, given as [<origin>=]<grantee>[,<grantee>...] where
+    /// grantees are address prefixes or key:<key name>, the lists without an
+    /// origin going to the zones without lists of their own
+    #[arg(long = "allow-transfer", value_parser = parse_allow_transfer_arg)]
+    allow_transfer: Vec<(Option<String>, Vec<Grantee>)>,
+
+    /// the secondaries told when a zone changes, given as <origin>=<ip>:<port>[,<ip>:<port>...]
+    #[arg(long = "notify", value_parser = parse_notify_arg)]
+    notify: Vec<(String, Vec<SocketAddr>)>,
+
+    /// who may change which names of a zone with dynamic updates, given as
+    /// [<origin>=]<grantee>[,<grantee>...][@<name>], <name> limiting them to its
+    /// subtree, the rules without an origin going to the zones without rules
+    /// of their own
+    #[arg(long = "allow-update", value_parser = parse_allow_update_arg)]
+    allow_update: Vec<(Option<String>, UpdateRule)>,
+
+    /// who may query at all, given as <grantee>[,<grantee>...], anyone by default
+    #[arg(long, value_delimiter = ',')]
+    allow_query: Option<Vec<Grantee>>,
+
+    /// who gets the queries we can't answer ourselves forwarded, given as
+    /// <grantee>[,<grantee>...], anyone who may query by default
+    #[arg(long, value_delimiter = ',')]
+    allow_recursion: Option<Vec<Grantee>>,
+
+    /// what clients turned away by an allow list get: refused or drop for no
+    /// response at all
+    #[arg(long, default_value = "refused")]
+    denied_response: DeniedResponse,
+
+    /// a TSIG key, given as <algorithm>:<name>:<base64 secret> with hmac-sha256
+    /// or hmac-sha512 as the algorithm
+    #[arg(long = "tsig-key")]
+    tsig_keys: Vec<Key>,
+
+    /// the TSIG key signing the NOTIFYs, SOA checks and transfers exchanged with
+    /// a zone's primary or secondaries, given as <origin>=<key name>
+    #[arg(long = "zone-key", value_parser = parse_zone_key_arg)]
+    zone_keys: Vec<(String, String)>,
+
+    /// an /etc/hosts style file whose names are answered locally
+    #[arg(long = "hosts")]
+    hosts_files: Vec<PathBuf>,
+
+    /// a name answered locally, given as <name>=<ip>
+    #[arg(long = "static-record", value_parser = parse_static_record_arg)]
+    static_records: Vec<(String, IpAddr)>,
+
+    /// the TTL of the answers from hosts files and static records
+    #[arg(long, default_value_t = 300)]
+    hosts_ttl: u32,
+
+    /// a list of names to block along with the names below them, in hosts,
+    /// plain name or AdBlock (`||name^`) format
+    #[arg(long = "blocklist")]
+    blocklists: Vec<PathBuf>,
+
+    /// a list of names never to block, in any of the blocklist formats
+    #[arg(long = "allowlist")]
+    allowlists: Vec<PathBuf>,
+
+    /// how blocked names are answered: nxdomain, nodata, null (0.0.0.0 and ::)
+    /// or refused
+    #[arg(long, default_value = "nxdomain")]
+    block_response: BlockResponse,
+
+    /// the seconds between reloads of the blocklists and allowlists
+    #[arg(long, default_value_t = 3600)]
+    blocklist_reload: u64,
+
+    /// a response policy zone rewriting forwarded queries, given as
+    /// <origin>=<path>, the first one listed that matches winning
+    #[arg(long = "rpz", value_parser = parse_zone_arg)]
+    rpz: Vec<(String, PathBuf)>,
+
+    /// the <ip>:<port> to listen on, over both UDP and TCP
+    #[arg(short, long, default_value = "127.0.0.1:2053")]
+    listen: SocketAddr,
+
+    /// the <ip>:<port> to listen on for DNS over TLS, usually port 853
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    tls_listen: Option<SocketAddr>,
+
+    /// the <ip>:<port> to listen on for DNS over HTTPS at /dns-query, usually
+    /// port 443
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    https_listen: Option<SocketAddr>,
+
+    /// the <ip>:<port> to listen on for DNS over QUIC, usually UDP port 853
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    quic_listen: Option<SocketAddr>,
+
+    /// the PEM certificate chain presented to TLS, HTTPS and QUIC clients
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// the PEM private key of the TLS certificate
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// the seconds a TLS or QUIC connection may stay idle before it's closed
+    #[arg(long, default_value_t = 30)]
+    tls_idle_timeout: u64,
+
+    /// rate limits UDP responses to this many a second for each client
+    /// network and name, turning Response Rate Limiting on
+    #[arg(long)]
+    rrl_responses_per_second: Option<u32>,
+
+    /// the rate limit of NXDOMAINs for each zone and of errors, the responses'
+    /// one by default
+    #[arg(long, requires = "rrl_responses_per_second")]
+    rrl_errors_per_second: Option<u32>,
+
+    /// every how many dropped responses one is sent truncated, so real clients
+    /// retry over TCP, 0 for never
+    #[arg(long, default_value_t = 2)]
+    rrl_slip: u32,
+
+    /// only logs the responses rate limiting would drop
+    #[arg(long, requires = "rrl_responses_per_second")]
+    rrl_log_only: bool,
+
+    /// the <ip>:<port> to serve Prometheus metrics on over HTTP at /metrics
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// which logs to write, in RUST_LOG syntax: a level, then per-module
+    /// levels such as codecrafters_dns_server::secondary=debug; the one line
+    /// per query is logged at info to the target query
+    #[arg(long, default_value = "info")]
+    log_filter: String,
+
+    /// text or json
+    #[arg(long, default_value = "text")]
+    log_format: LogFormat,
+
+    /// a Unix socket to send dnstap frames to over Frame Streams, the
+    /// collector being reconnected to whenever it goes away
+    #[arg(long, conflicts_with = "dnstap_file")]
+    dnstap_socket: Option<PathBuf>,
+
+    /// a file to write dnstap frames to over Frame Streams
+    #[arg(long)]
+    dnstap_file: Option<PathBuf>,
+
+    /// logs one query out of every this many to dnstap, along with what is
+    /// forwarded for it
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    dnstap_sample: u64,
+
+    /// an <ip>[/<len>] block of clients never rate limited
+    #[arg(long = "rrl-exempt")]
+    rrl_exempt: Vec<Prefix>,
 }
 
-fn main() {
-    // You can use print statements as follows for debugging, they'll be visible when running tests.
-    println!("Logs from your program will appear here!");
-
-    let args = Args::parse();
-
-    println!("resolver {}", args.resolver);
-    let resolver = std::net::SocketAddr::V4(args.resolver);
-
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-
-    let mut buf_client = [0; 512];
-    let mut buff_resolver = [0; 512];
-
-    loop {
-        match udp_socket.recv_from(&mut buf_client) {
-            Ok((size_client, source_client)) => {
-                println!("Received {} bytes from {}", size_client, source_client);
-                println!("Received all bytes {:?}", &buf_client[..size_client]);
-
-                let client_msg = deserialize(&buf_client[0..size_client]);
-                println!("Received msg: {client_msg:#?} from {source_client}");
-
-                if client_msg.header.questions_count == 1 {
-                    udp_socket
-                        .send_to(&buf_client[0..size_client], resolver)
-                        .expect("Failed to send buffer to resolver");
-
-                    let (size_resolver, source_resolver) =
-                        udp_socket.recv_from(&mut buff_resolver).unwrap();
-                    assert_eq!(source_resolver, resolver);
-
-                    udp_socket
-                        .send_to(&buff_resolver[..size_resolver], source_client)
-                        .expect("Failed to send response");
-                } else {
-                    let mut header = client_msg.header;
-                    header.questions_count = 1;
-
-                    let msgs: Vec<DnsMsg> = client_msg
-                        .questions
-                        .iter()
-                        .map(|r| DnsMsg {
-                            header,
-                            questions: vec![r.clone()],
-                            authority: vec![],
-                            additional: vec![],
-                            answers: vec![],
-                        })
-                        .collect();
-
-                    let recv_msgs: Vec<DnsMsg> = msgs
-                        .iter()
-                        .map(|msg| {
-                            udp_socket
-                                .send_to(&serialize(msg), resolver)
-                                .expect("Failed to send buffer to resolver");
-
-                            let (size_resolver, source_resolver) =
-                                udp_socket.recv_from(&mut buff_resolver).unwrap();
-                            assert_eq!(source_resolver, resolver);
-                            let resolver_msg = deserialize(&buff_resolver[0..size_resolver]);
-                            println!("Received msg: {resolver_msg:#?} from {source_resolver}");
-
-                            resolver_msg
-                        })
-                        .collect();
-
-                    let mut answers =
-                        Vec::with_capacity(client_msg.header.questions_count as usize);
-
-                    for msg in recv_msgs {
-                        answers.extend(msg.answers);
-                    }
-
-                    let mut client_msg = client_msg;
-
-                    client_msg.header.query = QR::Response;
-                    client_msg.answers = answers;
-                    client_msg.header.answers_count = client_msg.header.questions_count;
-
-                    udp_socket
-                        .send_to(&serialize(&client_msg), source_client)
-                        .expect("Failed to send response");
-                }
-
-                // let response_msg = handle_dns(dns_msg);
+fn parse_secondary_arg(value: &str) -> Result<(String, SocketAddr), String> {
+    let Some((origin, primary)) = value.split_once('=') else {
+        return Err(format!("expected <origin>=<ip>:<port>, got {value}"));
+    };
+    let primary = primary
+        .parse()
+        .map_err(|_| format!("invalid primary address {primary}"))?;
+
+    Ok((origin.to_string(), primary))
+}
+
+fn parse_static_record_arg(value: &str) -> Result<(String, IpAddr), String> {
+    let Some((name, address)) = value.split_once('=') else {
+        return Err(format!("expected <name>=<ip>, got {value}"));
+    };
+    let address = address
+        .parse()
+        .map_err(|_| format!("invalid address {address}"))?;
+    if !is_valid_name(name) {
+        return Err(format!("invalid name {name}"));
+    }
+
+    Ok((name.to_string(), address))
+}
+
+fn parse_zone_arg(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((origin, path)) => Ok((origin.to_string(), path.into())),
+        None => Err(format!("expected <origin>=<path>, got {value}")),
+    }
+}
+
+fn parse_forward_zone_arg(value: &str) -> Result<(String, Vec<Endpoint>), String> {
+    let Some((suffix, servers)) = value.split_once('=') else {
+        return Err(format!(
+            "expected <suffix>=<upstream>[,<upstream>...], got {value}"
+        ));
+    };
+    let servers = servers
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+
+    Ok((suffix.to_string(), servers))
+}
+
+fn parse_forward_timeout_arg(value: &str) -> Result<(String, Duration), String> {
+    match value.split_once('=') {
+        Some((suffix, timeout)) => match timeout.parse() {
+            Ok(milliseconds) if milliseconds > 0 => {
+                Ok((suffix.to_string(), Duration::from_millis(milliseconds)))
             }
-            Err(e) => {
-                eprintln!("Error receiving data: {}", e);
-                break;
+            _ => Err(format!("invalid timeout {timeout}")),
+        },
+        None => Err(format!("expected <suffix>=<milliseconds>, got {value}")),
+    }
+}
+
+fn parse_allow_transfer_arg(value: &str) -> Result<(Option<String>, Vec<Grantee>), String> {
+    match value.split_once('=') {
+        Some((origin, grantees)) => Ok((Some(origin.to_string()), acl::parse_grantees(grantees)?)),
+        None => Ok((None, acl::parse_grantees(value)?)),
+    }
+}
+
+fn parse_zone_key_arg(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((origin, key)) => Ok((
+            origin.to_string(),
+            key.trim_end_matches('.').to_ascii_lowercase(),
+        )),
+        None => Err(format!("expected <origin>=<key name>, got {value}")),
+    }
+}
+
+fn parse_notify_arg(value: &str) -> Result<(String, Vec<SocketAddr>), String> {
+    let Some((origin, targets)) = value.split_once('=') else {
+        return Err(format!(
+            "expected <origin>=<ip>:<port>[,<ip>:<port>...], got {value}"
+        ));
+    };
+    let targets = targets
+        .split(',')
+        .map(|target| {
+            target
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid secondary address {target}"))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok((origin.to_string(), targets))
+}
+
+fn parse_allow_update_arg(value: &str) -> Result<(Option<String>, UpdateRule), String> {
+    match value.split_once('=') {
+        Some((origin, rule)) => Ok((Some(origin.to_string()), rule.parse()?)),
+        None => Ok((None, value.parse()?)),
+    }
+}
+
+/// The options of the zone or secondary zone named `origin`.
+fn zone_options<'a>(
+    origin: &str,
+    zones: &'a mut [Zone],
+    secondaries: &'a mut [Secondary],
+) -> Option<&'a mut ZoneOptions> {
+    let origin = origin.trim_end_matches('.');
+    if let Some(zone) = zones
+        .iter_mut()
+        .find(|zone| zone.origin.eq_ignore_ascii_case(origin))
+    {
+        return Some(&mut zone.options);
+    }
+    secondaries
+        .iter_mut()
+        .find(|secondary| secondary.origin.eq_ignore_ascii_case(origin))
+        .map(|secondary| &mut secondary.options)
+}
+
+/// Everything the configuration says that a SIGHUP reloads.
+struct Loaded {
+    zones: Vec<Zone>,
+    secondaries: Vec<Secondary>,
+    blocklist: Blocklist,
+    settings: Settings,
+}
+
+/// The options of the command line `argv` and of the configuration file it
+/// names, the command line's winning.
+fn parse_args(argv: &[OsString]) -> Result<Args, clap::Error> {
+    let args = Args::try_parse_from(argv)?;
+    let Some(path) = &args.config else {
+        return Ok(args);
+    };
+    let file_args = config::file_args(path, &Args::command())
+        .map_err(|e| Args::command().error(ErrorKind::InvalidValue, format!("{e:#}")))?;
+
+    let argv = argv[..1].iter().chain(&file_args).chain(&argv[1..]);
+    Args::try_parse_from(argv)
+}
+
+/// What changes to only take effect after a restart.
+fn restart_only(args: &Args, secondaries: &[Secondary]) -> String {
+    format!(
+        "{:?}",
+        (
+            (
+                args.listen,
+                args.tls_listen,
+                args.https_listen,
+                args.quic_listen
+            ),
+            (&args.tls_cert, &args.tls_key, args.tls_idle_timeout),
+            (args.metrics_listen, &args.log_filter, args.log_format),
+            (&args.dnstap_socket, &args.dnstap_file, args.dnstap_sample),
+            (secondaries, &args.secondary_dir, args.blocklist_reload),
+        )
+    )
+}
+
+/// Loads the zones, lists and upstreams `args` name.
+fn load(args: &Args) -> anyhow::Result<Loaded> {
+    let mut zones = args
+        .zones
+        .iter()
+        .map(|(origin, path)| {
+            let mut zone = Zone::load(origin, path)?;
+            let journal_path = PathBuf::from(format!("{}.jnl", path.display()));
+            zone.journal = Journal::open(journal_path, zone.serial());
+            Ok(zone)
+        })
+        .collect::<anyhow::Result<Vec<Zone>>>()
+        .context("loading zones")?;
+    let mut secondaries: Vec<Secondary> = args
+        .secondaries
+        .iter()
+        .map(|(origin, primary)| {
+            let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+            Secondary {
+                path: args.secondary_dir.join(format!("{origin}.zone")),
+                origin,
+                primary: *primary,
+                options: ZoneOptions::default(),
             }
+        })
+        .collect();
+
+    for (origin, grantees) in &args.allow_transfer {
+        let Some(origin) = origin else { continue };
+        let Some(options) = zone_options(origin, &mut zones, &mut secondaries) else {
+            bail!("--allow-transfer for {origin}, which isn't a served zone");
+        };
+        options.allow_transfer.extend(grantees.iter().cloned());
+    }
+    for (origin, name) in &args.zone_keys {
+        let Some(key) = args.tsig_keys.iter().find(|key| key.name == *name) else {
+            bail!("--zone-key for {origin} names unknown key {name}");
+        };
+        let Some(options) = zone_options(origin, &mut zones, &mut secondaries) else {
+            bail!("--zone-key for {origin}, which isn't a served zone");
+        };
+        options.tsig_key = Some(key.clone());
+    }
+    for (origin, targets) in &args.notify {
+        let Some(options) = zone_options(origin, &mut zones, &mut secondaries) else {
+            bail!("--notify for {origin}, which isn't a served zone");
+        };
+        options.notify.extend(targets);
+    }
+    for (origin, rule) in &args.allow_update {
+        let Some(origin) = origin else { continue };
+        let Some(options) = zone_options(origin, &mut zones, &mut secondaries) else {
+            bail!("--allow-update for {origin}, which isn't a served zone");
+        };
+        options.allow_update.push(rule.clone());
+    }
+    // the lists without an origin go to the zones without lists of their own
+    let all_options = zones.iter_mut().map(|zone| &mut zone.options).chain(
+        secondaries
+            .iter_mut()
+            .map(|secondary| &mut secondary.options),
+    );
+    for options in all_options {
+        if options.allow_transfer.is_empty() {
+            options.allow_transfer = args
+                .allow_transfer
+                .iter()
+                .filter(|(origin, _)| origin.is_none())
+                .flat_map(|(_, grantees)| grantees.iter().cloned())
+                .collect();
+        }
+        if options.allow_update.is_empty() {
+            options.allow_update = args
+                .allow_update
+                .iter()
+                .filter(|(origin, _)| origin.is_none())
+                .map(|(_, rule)| rule.clone())
+                .collect();
         }
     }
+    for zone in &zones {
+        info!("serving zone {} serial {}", zone.origin, zone.serial());
+    }
+
+    let tls_clients = TlsClients::new(args.upstream_ca.as_deref(), &args.upstream_pins)
+        .context("setting up TLS for upstreams")?;
+    let mut forwarders: Vec<Forwarder> = vec![];
+    let resolver = args
+        .resolver
+        .iter()
+        .map(|resolver| (".".to_string(), vec![resolver.clone()]));
+    for (suffix, endpoints) in args.forward_zones.iter().cloned().chain(resolver) {
+        let servers = endpoints
+            .into_iter()
+            .map(|endpoint| {
+                let upstream = Upstream::new(&endpoint, &tls_clients)
+                    .with_context(|| format!("setting up upstream {endpoint}"))?;
+                Ok((endpoint, upstream))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        forwarders.push(Forwarder::new(&suffix, servers));
+    }
+    for (suffix, timeout) in &args.forward_timeouts {
+        let suffix = Forwarder::new(suffix, vec![]).suffix;
+        let Some(forwarder) = forwarders.iter_mut().find(|f| f.suffix == suffix) else {
+            bail!("--forward-timeout for {suffix}, which isn't a forward zone");
+        };
+        forwarder.timeout = *timeout;
+    }
+    for forwarder in &forwarders {
+        info!("forwarding {forwarder}");
+    }
+
+    let mut hosts = Hosts::new(args.hosts_ttl);
+    for path in &args.hosts_files {
+        hosts.load(path).context("loading hosts file")?;
+    }
+    for (name, address) in &args.static_records {
+        hosts.insert(name, *address);
+    }
+
+    let blocklist =
+        Blocklist::load(&args.blocklists, &args.allowlists).context("loading blocklists")?;
+    if !args.blocklists.is_empty() {
+        info!("{} names blocked", blocklist.len());
+    }
+
+    let rpz = args
+        .rpz
+        .iter()
+        .map(|(origin, path)| PolicyZone::load(origin, path))
+        .collect::<anyhow::Result<_>>()
+        .context("loading policy zones")?;
+
+    let settings = Settings {
+        forwarders: Forwarders::new(forwarders),
+        hosts,
+        block_response: args.block_response,
+        blocklists: args.blocklists.clone(),
+        allowlists: args.allowlists.clone(),
+        rpz,
+        tsig_keys: args.tsig_keys.clone(),
+        acls: Acls {
+            query: args.allow_query.clone(),
+            recursion: args.allow_recursion.clone(),
+            denied_response: args.denied_response,
+        },
+        rrl: args.rrl_responses_per_second.map(|responses_per_second| {
+            RateLimiter::new(RrlOptions {
+                responses_per_second,
+                errors_per_second: args.rrl_errors_per_second.unwrap_or(responses_per_second),
+                slip: args.rrl_slip,
+                log_only: args.rrl_log_only,
+                exempt: args.rrl_exempt.clone(),
+            })
+        }),
+    };
+
+    Ok(Loaded {
+        zones,
+        secondaries,
+        blocklist,
+        settings,
+    })
+}
+
+fn main() {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let args = parse_args(&argv).unwrap_or_else(|e| e.exit());
+    logging::init(&args.log_filter, args.log_format).expect("Failed to set up logging");
+
+    let dnstap_output = match (&args.dnstap_socket, &args.dnstap_file) {
+        (Some(path), _) => Some(dnstap::Output::Socket(path.clone())),
+        (_, Some(path)) => Some(dnstap::Output::File(path.clone())),
+        (None, None) => None,
+    };
+    if let Some(output) = dnstap_output {
+        dnstap::start(output, args.dnstap_sample).expect("Failed to set up dnstap");
+    }
+
+    let Loaded {
+        zones,
+        secondaries,
+        blocklist,
+        settings,
+    } = load(&args).expect("Failed to load the configuration");
+    let running = restart_only(&args, &secondaries);
+    // the secondaries may have missed changes made while we were down
+    let startup_notifies: Vec<_> = zones
+        .iter()
+        .map(|zone| (zone.soa().clone(), zone.options.clone()))
+        .collect();
+    let catalog = Catalog::new(zones);
+
+    let mut handles = vec![];
+    let mut wakes = vec![];
+    for secondary in &secondaries {
+        let (wake, receiver) = mpsc::channel();
+        handles.push(SecondaryHandle {
+            origin: secondary.origin.clone(),
+            primary: secondary.primary.ip(),
+            tsig_key: secondary.options.tsig_key.clone(),
+            wake,
+        });
+        wakes.push(receiver);
+    }
+
+    let server = Arc::new(Server {
+        catalog: RwLock::new(catalog),
+        blocklist: RwLock::new(blocklist),
+        secondaries: handles,
+        settings: RwLock::new(Arc::new(settings)),
+    });
+
+    let udp_socket = UdpSocket::bind(args.listen).expect("Failed to bind to address");
+    let tcp_listener = TcpListener::bind(args.listen).expect("Failed to bind to address");
+
+    for (secondary, wake) in secondaries.into_iter().zip(wakes) {
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || secondary::run(server, secondary, wake));
+    }
+
+    let blocklist_server = Arc::clone(&server);
+    let interval = Duration::from_secs(args.blocklist_reload.max(1));
+    std::thread::spawn(move || blocklist::reload(blocklist_server, interval));
+
+    let reload_server = Arc::clone(&server);
+    std::thread::spawn(move || {
+        config::reload_on_hangup(|| {
+            // without the usage clap follows its errors with
+            let args = parse_args(&argv).map_err(|e| {
+                let message = e.to_string();
+                anyhow::anyhow!("{}", message.lines().next().unwrap_or_default())
+            })?;
+            let loaded = load(&args)?;
+            if restart_only(&args, &loaded.secondaries) != running {
+                warn!(
+                    "Listeners, TLS, logging, dnstap, metrics and secondary zones only \
+                     change on a restart"
+                );
+            }
+            reload_server.reload(loaded.zones, loaded.blocklist, loaded.settings);
+            Ok(())
+        })
+    });
+
+    for (soa, options) in startup_notifies {
+        notify::send_notify(soa, &options);
+    }
+
+    if let (Some(address), Some(cert), Some(key)) = (args.tls_listen, &args.tls_cert, &args.tls_key)
+    {
+        let config = tls::server_config(cert, key, &[b"dot"]).expect("Failed to set up TLS");
+        let listener = TcpListener::bind(address).expect("Failed to bind to address");
+        let idle_timeout = Duration::from_secs(args.tls_idle_timeout);
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || tls::serve_tls(server, listener, config, idle_timeout));
+    }
+
+    if let (Some(address), Some(cert), Some(key)) =
+        (args.https_listen, &args.tls_cert, &args.tls_key)
+    {
+        let config =
+            tls::server_config(cert, key, &[b"h2", b"http/1.1"]).expect("Failed to set up TLS");
+        let listener = TcpListener::bind(address).expect("Failed to bind to address");
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || doh::serve_https(server, listener, config));
+    }
+
+    if let (Some(address), Some(cert), Some(key)) =
+        (args.quic_listen, &args.tls_cert, &args.tls_key)
+    {
+        let config = tls::server_config(cert, key, &[b"doq"])
+            .and_then(|config| doq::quic_config(config, Duration::from_secs(args.tls_idle_timeout)))
+            .expect("Failed to set up QUIC");
+        let socket = UdpSocket::bind(address).expect("Failed to bind to address");
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || doq::serve_quic(server, socket, config));
+    }
+
+    if let Some(address) = args.metrics_listen {
+        let listener = TcpListener::bind(address).expect("Failed to bind to address");
+        std::thread::spawn(move || metrics::serve_metrics(listener));
+    }
+
+    let tcp_server = Arc::clone(&server);
+    std::thread::spawn(move || server::serve_tcp(tcp_server, tcp_listener));
+
+    server::serve_udp(&server, udp_socket);
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
         deserialize,
-        dns_header::{DnsHeader, OpCode, ResponseCode, QR},
+        dns_header::{deserialize_header, DnsHeader, OpCode, ResponseCode, QR},
         dns_record::{self, DnsRecord},
-        serialize, DnsMsg,
+        serialize, truncate, DnsMsg,
     };
 
     #[test]
@@ -230,11 +855,11 @@ mod tests {
             additional: vec![],
         };
 
-        let msg = deserialize(&request);
+        let msg = deserialize(&request).unwrap();
         assert_eq!(expected_msg, msg);
 
         let msg_bytes = serialize(&expected_msg);
-        let msg = deserialize(&msg_bytes);
+        let msg = deserialize(&msg_bytes).unwrap();
         assert_eq!(expected_msg, msg);
 
         let result = [
@@ -243,10 +868,40 @@ mod tests {
             100, 101, 102, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110, 110,
             97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1,
         ];
-        let msg = deserialize(&result);
+        let msg = deserialize(&result).unwrap();
         assert_eq!(expected_msg, msg);
     }
 
+    #[test]
+    fn test_truncate() {
+        let record = |name: &str| DnsRecord {
+            name: name.into(),
+            dns_type: dns_record::DnsType::A,
+            dns_class: dns_record::DnsClass::IN,
+            time_to_live: 60,
+            rd_length: 4,
+            rd_data: vec![192, 0, 2, 1],
+        };
+        let mut msg = DnsMsg {
+            header: deserialize_header(&[0; 12]),
+            questions: vec![],
+            answers: vec![record("a.example.com")],
+            authority: vec![],
+            additional: vec![record("b.example.com"), record("c.example.com")],
+        };
+
+        // each record takes 15 bytes for its name and 14 for the rest
+        truncate(&mut msg, 12 + 29 * 2);
+        assert!(!msg.header.tc);
+        assert_eq!(msg.answers.len(), 1);
+        assert_eq!(msg.additional.len(), 1);
+
+        truncate(&mut msg, 20);
+        assert!(msg.header.tc);
+        assert!(msg.answers.is_empty());
+        assert!(msg.additional.is_empty());
+    }
+
     #[test]
     fn test_deserialize() {
         let buffer = [
@@ -254,7 +909,7 @@ mod tests {
             101, 114, 115, 2, 105, 111, 0, 0, 1, 0, 1,
         ];
 
-        let msg = deserialize(&buffer);
+        let msg = deserialize(&buffer).unwrap();
 
         let expected_msg = DnsMsg {
             header: DnsHeader {
@@ -287,4 +942,28 @@ mod tests {
 
         assert_eq!(msg, expected_msg);
     }
+
+    #[test]
+    fn test_deserialize_malformed() {
+        // an EDNS query for a.example HTTPS, its OPT record advertising 1232 bytes
+        let query = [
+            0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, 1, 97, 7, 101, 120, 97, 109, 112, 108, 101, 0, 0,
+            65, 0, 1, 0, 0, 41, 4, 208, 0, 0, 0, 0, 0, 0,
+        ];
+        let msg = deserialize(&query).unwrap();
+        assert_eq!(msg.questions[0].dns_type, dns_record::DnsType::Unknown(65));
+        assert_eq!(msg.additional[0].dns_type, dns_record::DnsType::Opt);
+        assert_eq!(
+            msg.additional[0].dns_class,
+            dns_record::DnsClass::Unknown(1232)
+        );
+        assert_eq!(serialize(&msg), query);
+
+        for len in 0..query.len() {
+            assert!(deserialize(&query[..len]).is_err(), "{len} bytes");
+        }
+        // a compression pointer to itself
+        let looping = [0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 192, 12, 0, 1, 0, 1];
+        assert!(deserialize(&looping).is_err());
+    }
 }
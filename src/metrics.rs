@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tracing::warn;
+
+use crate::dns_header::rcode_name;
+use crate::dns_record::raw_question;
+use crate::rrl::Verdict;
+use crate::server::Transport;
+use crate::zone_file::type_name;
+
+/// The upper bounds of the upstream latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Everything the server counts, shared by all its threads.
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug, Default, Clone)]
+struct Latency {
+    /// Exchanges that took no longer than each of the `LATENCY_BUCKETS`.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+    errors: u64,
+}
+
+/// Counters kept for Prometheus to scrape.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Queries by question type, response code and transport.
+    queries: Mutex<BTreeMap<(String, String, &'static str), u64>>,
+    in_flight: AtomicI64,
+    /// By upstream.
+    upstreams: Mutex<BTreeMap<String, Latency>>,
+    /// UDP responses held back, by what was done instead.
+    rate_limited: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Counts a query as in flight for as long as it's kept.
+pub struct InFlight<'a>(&'a Metrics);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            queries: Mutex::new(BTreeMap::new()),
+            in_flight: AtomicI64::new(0),
+            upstreams: Mutex::new(BTreeMap::new()),
+            rate_limited: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn in_flight(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    /// Counts the raw `request` and its first raw `response`, if it got any.
+    pub fn count_query(&self, request: &[u8], response: Option<&[u8]>, transport: Transport) {
+        let dns_type = match raw_question(request) {
+            Some((_, dns_type)) => type_name(dns_type),
+            None => "none".to_string(),
+        };
+        let rcode = match response.and_then(|response| response.get(3)) {
+            Some(flags) => rcode_name(flags & 0x0f),
+            None => "none".to_string(),
+        };
+        let transport = transport.name();
+
+        *self
+            .queries
+            .lock()
+            .unwrap()
+            .entry((dns_type, rcode, transport))
+            .or_default() += 1;
+    }
+
+    /// Records an exchange with `upstream` that took `elapsed`.
+    pub fn observe_upstream(&self, upstream: &str, elapsed: Duration, failed: bool) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let latency = upstreams.entry(upstream.to_string()).or_default();
+        if failed {
+            latency.errors += 1;
+            return;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in latency.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        latency.sum += seconds;
+        latency.count += 1;
+    }
+
+    pub fn count_rate_limited(&self, verdict: Verdict) {
+        let action = match verdict {
+            Verdict::Send => return,
+            Verdict::Slip => "slip",
+            Verdict::Drop => "drop",
+        };
+        *self.rate_limited.lock().unwrap().entry(action).or_default() += 1;
+    }
+
+    /// The Prometheus text exposition of the metrics.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        header(
+            &mut text,
+            "dns_queries_total",
+            "counter",
+            "Queries answered, by question type, response code and transport.",
+        );
+        for ((dns_type, rcode, transport), count) in self.queries.lock().unwrap().iter() {
+            let _ = writeln!(
+                text,
+                "dns_queries_total{{type=\"{}\",rcode=\"{}\",transport=\"{transport}\"}} {count}",
+                escape(dns_type),
+                escape(rcode)
+            );
+        }
+
+        header(
+            &mut text,
+            "dns_queries_in_flight",
+            "gauge",
+            "Queries being answered.",
+        );
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        let _ = writeln!(text, "dns_queries_in_flight {in_flight}");
+
+        let upstreams = self.upstreams.lock().unwrap().clone();
+        header(
+            &mut text,
+            "dns_upstream_duration_seconds",
+            "histogram",
+            "How long upstreams took to answer forwarded queries.",
+        );
+        for (upstream, latency) in &upstreams {
+            let upstream = escape(upstream);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+                let _ = writeln!(
+                    text,
+                    "dns_upstream_duration_seconds_bucket{{upstream=\"{upstream}\",le=\"{bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                text,
+                "dns_upstream_duration_seconds_bucket{{upstream=\"{upstream}\",le=\"+Inf\"}} {}",
+                latency.count
+            );
+            let _ = writeln!(
+                text,
+                "dns_upstream_duration_seconds_sum{{upstream=\"{upstream}\"}} {}",
+                latency.sum
+            );
+            let _ = writeln!(
+                text,
+                "dns_upstream_duration_seconds_count{{upstream=\"{upstream}\"}} {}",
+                latency.count
+            );
+        }
+        header(
+            &mut text,
+            "dns_upstream_errors_total",
+            "counter",
+            "Forwarded queries upstreams failed to answer.",
+        );
+        for (upstream, latency) in &upstreams {
+            let _ = writeln!(
+                text,
+                "dns_upstream_errors_total{{upstream=\"{}\"}} {}",
+                escape(upstream),
+                latency.errors
+            );
+        }
+
+        header(
+            &mut text,
+            "dns_rrl_responses_total",
+            "counter",
+            "UDP responses held back by rate limiting, by whether they were dropped or slipped.",
+        );
+        for (action, count) in self.rate_limited.lock().unwrap().iter() {
+            let _ = writeln!(
+                text,
+                "dns_rrl_responses_total{{action=\"{action}\"}} {count}"
+            );
+        }
+
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} {kind}");
+}
+
+/// A label value with its backslashes, quotes and newlines escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the metrics over plain HTTP at `/metrics`.
+pub fn serve_metrics(listener: TcpListener) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the metrics runtime");
+
+    runtime.block_on(async move {
+        listener
+            .set_nonblocking(true)
+            .expect("Failed to set up the metrics listener");
+        let listener = tokio::net::TcpListener::from_std(listener)
+            .expect("Failed to set up the metrics listener");
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Error accepting metrics connection: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                if let Err(e) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(respond))
+                    .await
+                {
+                    warn!("Error on metrics connection: {}", e);
+                }
+            });
+        }
+    });
+}
+
+async fn respond(request: Request<Incoming>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let mut response = Response::new(Full::default());
+    if request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    *response.body_mut() = Full::new(Bytes::from(METRICS.render()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+    Ok(response)
+}
+
+#[test]
+fn test_metrics() {
+    let metrics = Metrics::new();
+    let query = [0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 28, 0, 1];
+    let mut response = query;
+    response[3] = 0x83;
+
+    {
+        let _first = metrics.in_flight();
+        let _second = metrics.in_flight();
+        assert!(metrics.render().contains("\ndns_queries_in_flight 2\n"));
+    }
+    metrics.count_query(&query, Some(&response), Transport::Udp);
+    metrics.count_query(&query, Some(&response), Transport::Udp);
+    metrics.count_query(&query, None, Transport::Quic);
+    metrics.observe_upstream(
+        "tls://192.0.2.1:853#dns.example",
+        Duration::from_millis(20),
+        false,
+    );
+    metrics.observe_upstream(
+        "tls://192.0.2.1:853#dns.example",
+        Duration::from_secs(3),
+        false,
+    );
+    metrics.observe_upstream(
+        "tls://192.0.2.1:853#dns.example",
+        Duration::from_secs(3),
+        true,
+    );
+    metrics.count_rate_limited(Verdict::Slip);
+    metrics.count_rate_limited(Verdict::Send);
+
+    let text = metrics.render();
+    let lines: Vec<&str> = text.lines().collect();
+    for line in [
+        "dns_queries_total{type=\"AAAA\",rcode=\"NXDOMAIN\",transport=\"udp\"} 2",
+        "dns_queries_total{type=\"AAAA\",rcode=\"none\",transport=\"quic\"} 1",
+        "dns_queries_in_flight 0",
+        "# TYPE dns_upstream_duration_seconds histogram",
+        "dns_upstream_duration_seconds_bucket{upstream=\"tls://192.0.2.1:853#dns.example\",le=\"0.01\"} 0",
+        "dns_upstream_duration_seconds_bucket{upstream=\"tls://192.0.2.1:853#dns.example\",le=\"0.025\"} 1",
+        "dns_upstream_duration_seconds_bucket{upstream=\"tls://192.0.2.1:853#dns.example\",le=\"+Inf\"} 2",
+        "dns_upstream_duration_seconds_sum{upstream=\"tls://192.0.2.1:853#dns.example\"} 3.02",
+        "dns_upstream_duration_seconds_count{upstream=\"tls://192.0.2.1:853#dns.example\"} 2",
+        "dns_upstream_errors_total{upstream=\"tls://192.0.2.1:853#dns.example\"} 1",
+        "dns_rrl_responses_total{action=\"slip\"} 1",
+    ] {
+        assert!(lines.contains(&line), "{line} missing from\n{text}");
+    }
+    assert!(!text.contains("action=\"send\""));
+    assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+}
@@ -0,0 +1,82 @@
+use std::net::SocketAddr;
+
+use anyhow::bail;
+use tracing::{info, warn};
+
+use crate::dns_header::{OpCode, ResponseCode};
+use crate::dns_record::{DnsRecord, DnsType};
+use crate::transfer::transfer_query;
+use crate::tsig::Key;
+use crate::zone::ZoneOptions;
+use crate::{upstream, DnsMsg};
+
+/// How many times a NOTIFY is sent before giving up on a secondary (RFC 1996 section 3.6).
+const NOTIFY_ATTEMPTS: usize = 5;
+
+/// The NOTIFY telling a secondary that the zone now has the SOA `soa`.
+pub fn notify_message(soa: &DnsRecord) -> DnsMsg {
+    let mut message = transfer_query(&soa.name, DnsType::Soa);
+    message.header.op_code = OpCode::Notify;
+    message.header.aa = true;
+    message.header.answers_count = 1;
+    message.answers.push(soa.clone());
+    message
+}
+
+/// Tells the secondaries in `options` that the zone changed to `soa`, from a
+/// thread of its own so that slow or unreachable secondaries hold nothing up.
+pub fn send_notify(soa: DnsRecord, options: &ZoneOptions) {
+    if options.notify.is_empty() {
+        return;
+    }
+
+    let (targets, key) = (options.notify.clone(), options.tsig_key.clone());
+    std::thread::spawn(move || {
+        for target in targets {
+            match notify(&soa, target, key.as_ref()) {
+                Ok(()) => info!("notified {target} of a change to {}", soa.name),
+                Err(e) => warn!("Failed to notify {target} of {}: {e:#}", soa.name),
+            }
+        }
+    });
+}
+
+fn notify(soa: &DnsRecord, target: SocketAddr, key: Option<&Key>) -> anyhow::Result<()> {
+    let message = notify_message(soa);
+
+    let mut last_error = None;
+    for _ in 0..NOTIFY_ATTEMPTS {
+        match upstream::exchange_signed(target, &message, key) {
+            Ok(response) if response.header.op_code != OpCode::Notify => {
+                bail!("answered with opcode {:?}", response.header.op_code)
+            }
+            Ok(response) if response.header.response_code != ResponseCode::NoError => {
+                bail!("answered {:?}", response.header.response_code)
+            }
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    bail!(
+        "no answer after {NOTIFY_ATTEMPTS} attempts: {}",
+        last_error.map_or_else(String::new, |e| format!("{e:#}"))
+    )
+}
+
+#[test]
+fn test_notify_message() {
+    use crate::{deserialize, serialize};
+
+    let text = "@ 3600 SOA ns1 hostmaster 7 7200 1800 1209600 300\n";
+    let records = crate::zone_file::parse_zone(text, "example.com", std::path::Path::new("."));
+    let zone = crate::zone::Zone::new("example.com", records.unwrap()).unwrap();
+    let message = deserialize(&serialize(&notify_message(zone.soa()))).unwrap();
+
+    assert_eq!(message.header.op_code, OpCode::Notify);
+    assert!(message.header.aa);
+    assert_eq!(message.questions.len(), 1);
+    assert_eq!(message.questions[0].name, "example.com");
+    assert_eq!(message.questions[0].dns_type, DnsType::Soa);
+    assert_eq!(message.answers, vec![zone.soa().clone()]);
+}
@@ -0,0 +1,555 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+use crate::acl::Prefix;
+use crate::dns_header::ResponseCode;
+use crate::dns_record::{deserialize_name, serialize_name, DnsRecord, DnsType};
+use crate::server::error_response;
+use crate::upstream::Forwarders;
+use crate::zone::parent;
+use crate::zone_file::parse_zone_file;
+use crate::{upstream, DnsMsg};
+
+/// What a policy rule does to the queries it matches, written as the data of
+/// the rule's records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// `CNAME .`
+    NxDomain,
+    /// `CNAME *.`
+    NoData,
+    /// `CNAME rpz-passthru.`, answering as if there were no policy.
+    Passthru,
+    /// `CNAME rpz-drop.`, not answering at all.
+    Drop,
+    /// Any other records, answered in place of the real ones. A CNAME is
+    /// followed through the upstream.
+    LocalData(Vec<DnsRecord>),
+}
+
+impl Action {
+    fn new(records: Vec<DnsRecord>) -> Action {
+        if let [record] = records.as_slice() {
+            if record.dns_type == DnsType::Cname {
+                match deserialize_name(&record.rd_data, 0) {
+                    Ok((target, _)) if target.is_empty() => return Action::NxDomain,
+                    Ok((target, _)) if target == "*" => return Action::NoData,
+                    Ok((target, _)) if target == "rpz-passthru" => return Action::Passthru,
+                    Ok((target, _)) if target == "rpz-drop" => return Action::Drop,
+                    _ => {}
+                }
+            }
+        }
+
+        Action::LocalData(records)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Action::NxDomain => "NXDOMAIN",
+            Action::NoData => "NODATA",
+            Action::Passthru => "PASSTHRU",
+            Action::Drop => "DROP",
+            Action::LocalData(_) => "local data",
+        }
+    }
+}
+
+/// What about a query a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    ClientIp,
+    Qname,
+    ResponseIp,
+    Nsdname,
+}
+
+/// A policy rule matching a query.
+#[derive(Debug)]
+pub struct Hit<'a> {
+    pub zone: &'a str,
+    pub trigger: Trigger,
+    /// The rule's owner name, relative to the policy zone.
+    pub rule: String,
+    pub action: &'a Action,
+    /// The question it's about, the first for rules on the client or the
+    /// answers.
+    pub question: usize,
+}
+
+/// A response policy zone: rules whose owner names say what they match, e.g.
+/// `*.example.com` for the names below example.com, `24.0.2.0.192.rpz-ip` for
+/// answers with addresses in 192.0.2.0/24, `ns.example.net.rpz-nsdname` for the
+/// names ns.example.net serves and `32.1.2.0.192.rpz-client-ip` for 192.0.2.1's
+/// queries.
+#[derive(Debug, Default)]
+pub struct PolicyZone {
+    pub origin: String,
+    qnames: HashMap<String, Action>,
+    nsdnames: HashMap<String, Action>,
+    client_ips: Vec<(Prefix, String, Action)>,
+    response_ips: Vec<(Prefix, String, Action)>,
+}
+
+impl PolicyZone {
+    pub fn load(origin: &str, path: &Path) -> anyhow::Result<PolicyZone> {
+        let records = parse_zone_file(path, origin)
+            .with_context(|| format!("loading policy zone {origin}"))?;
+
+        Ok(PolicyZone::new(origin, records))
+    }
+
+    fn new(origin: &str, records: Vec<DnsRecord>) -> PolicyZone {
+        let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+        let mut rules: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+        for record in records {
+            let name = record.name.to_ascii_lowercase();
+            // the SOA and NS records at the apex are no rules
+            if let Some(rule) = name.strip_suffix(&format!(".{origin}")) {
+                rules.entry(rule.to_string()).or_default().push(record);
+            }
+        }
+
+        let mut zone = PolicyZone {
+            origin,
+            ..PolicyZone::default()
+        };
+        for (rule, records) in rules {
+            let action = Action::new(records);
+            if let Some(prefix) = rule.strip_suffix(".rpz-client-ip") {
+                match parse_ip_rule(prefix) {
+                    Some(prefix) => zone.client_ips.push((prefix, rule, action)),
+                    None => warn!("{}: skipping invalid rule {rule}", zone.origin),
+                }
+            } else if let Some(prefix) = rule.strip_suffix(".rpz-ip") {
+                match parse_ip_rule(prefix) {
+                    Some(prefix) => zone.response_ips.push((prefix, rule, action)),
+                    None => warn!("{}: skipping invalid rule {rule}", zone.origin),
+                }
+            } else if let Some(name) = rule.strip_suffix(".rpz-nsdname") {
+                zone.nsdnames.insert(name.to_string(), action);
+            } else if rule.ends_with(".rpz-nsip") || rule.ends_with(".rpz-tcp-only") {
+                warn!("{}: skipping unsupported rule {rule}", zone.origin);
+            } else {
+                zone.qnames.insert(rule, action);
+            }
+        }
+
+        zone
+    }
+
+    fn hit<'a>(
+        &'a self,
+        trigger: Trigger,
+        rule: String,
+        action: &'a Action,
+        question: usize,
+    ) -> Hit<'a> {
+        Hit {
+            zone: &self.origin,
+            trigger,
+            rule,
+            action,
+            question,
+        }
+    }
+
+    /// Whether some rules of the zone look at the response, which the query
+    /// has to be resolved for.
+    fn has_response_rules(&self) -> bool {
+        !self.response_ips.is_empty() || !self.nsdnames.is_empty()
+    }
+
+    /// The rule matching `query` from `client` before it's resolved, by
+    /// client IP and then by the name of each question.
+    fn check_query(&self, query: &DnsMsg, client: IpAddr) -> Option<Hit<'_>> {
+        if let Some((rule, action)) = find_address(&self.client_ips, client) {
+            return Some(self.hit(Trigger::ClientIp, rule, action, 0));
+        }
+        query
+            .questions
+            .iter()
+            .enumerate()
+            .find_map(|(i, question)| {
+                let qname = question.name.trim_end_matches('.').to_ascii_lowercase();
+                find_name(&self.qnames, &qname)
+                    .map(|(rule, action)| self.hit(Trigger::Qname, rule, action, i))
+            })
+    }
+
+    /// The rule matching a response to `questions` questions, by the
+    /// `addresses` in its answers and then by the `name_servers` of each
+    /// question's name, looked up when needed.
+    fn check_response(
+        &self,
+        questions: usize,
+        addresses: &[IpAddr],
+        name_servers: &mut dyn FnMut(usize) -> Vec<String>,
+    ) -> Option<Hit<'_>> {
+        let by_address = addresses.iter().find_map(|address| {
+            find_address(&self.response_ips, *address)
+                .map(|(rule, action)| self.hit(Trigger::ResponseIp, rule, action, 0))
+        });
+        if by_address.is_some() || self.nsdnames.is_empty() {
+            return by_address;
+        }
+
+        (0..questions).find_map(|i| {
+            name_servers(i).iter().find_map(|name_server| {
+                find_name(&self.nsdnames, name_server).map(|(rule, action)| {
+                    self.hit(Trigger::Nsdname, format!("{rule}.rpz-nsdname"), action, i)
+                })
+            })
+        })
+    }
+}
+
+/// Reads the address block of an IP rule, its prefix length followed by the
+/// address in reverse: `24.0.2.0.192` for 192.0.2.0/24, `48.zz.db8.2001` for
+/// 2001:db8::/48, `zz` standing for the `::`.
+fn parse_ip_rule(rule: &str) -> Option<Prefix> {
+    let mut labels: Vec<&str> = rule.split('.').collect();
+    let len = labels.remove(0);
+    labels.reverse();
+
+    let address = if labels.len() == 4 && !labels.contains(&"zz") {
+        labels.join(".")
+    } else {
+        let mut address = labels.join(":").replace("zz", "");
+        if labels.first() == Some(&"zz") {
+            address.insert(0, ':');
+        }
+        if labels.last() == Some(&"zz") {
+            address.push(':');
+        }
+        address
+    };
+
+    format!("{address}/{len}").parse().ok()
+}
+
+/// The rule in `rules` for `name`, the rule for the name itself winning over
+/// the wildcard of its closest parent.
+fn find_name<'a>(rules: &'a HashMap<String, Action>, name: &str) -> Option<(String, &'a Action)> {
+    if let Some(action) = rules.get(name) {
+        return Some((name.to_string(), action));
+    }
+
+    let mut name = name;
+    while !name.is_empty() {
+        name = parent(name);
+        let wildcard = if name.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{name}")
+        };
+        if let Some(action) = rules.get(&wildcard) {
+            return Some((wildcard, action));
+        }
+    }
+
+    None
+}
+
+/// The most specific rule in `rules` whose block holds `address`.
+fn find_address(rules: &[(Prefix, String, Action)], address: IpAddr) -> Option<(String, &Action)> {
+    rules
+        .iter()
+        .filter(|(prefix, _, _)| prefix.contains(address))
+        .max_by_key(|(prefix, _, _)| prefix.len)
+        .map(|(_, rule, action)| (rule.clone(), action))
+}
+
+/// The first rule matching `query` from `client` that can be told before
+/// it's resolved. The zones are tried in order, up to the first with rules
+/// on the response, which have to wait for `check_response`.
+pub fn check_query<'a>(zones: &'a [PolicyZone], query: &DnsMsg, client: IpAddr) -> Option<Hit<'a>> {
+    for zone in zones {
+        if let Some(hit) = zone.check_query(query, client) {
+            return Some(hit);
+        }
+        if zone.has_response_rules() {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// The first rule matching `query` from `client` and the upstream's
+/// `response` to it, the zones being tried in order with all of their
+/// triggers each. The name servers of the query name are only looked up
+/// through `forwarders` when a zone gets to its NSDNAME rules.
+pub fn check_response<'a>(
+    zones: &'a [PolicyZone],
+    query: &DnsMsg,
+    client: IpAddr,
+    response: &DnsMsg,
+    forwarders: &Forwarders,
+) -> Option<Hit<'a>> {
+    let addresses: Vec<IpAddr> = response
+        .answers
+        .iter()
+        .filter_map(|record| match record.dns_type {
+            DnsType::A => <[u8; 4]>::try_from(record.rd_data.as_slice())
+                .ok()
+                .map(IpAddr::from),
+            DnsType::Aaaa => <[u8; 16]>::try_from(record.rd_data.as_slice())
+                .ok()
+                .map(IpAddr::from),
+            _ => None,
+        })
+        .collect();
+    let questions = &query.questions;
+    let mut looked_up: Vec<Option<Vec<String>>> = vec![None; questions.len()];
+    let mut lookup = |i: usize| {
+        looked_up[i]
+            .get_or_insert_with(|| {
+                name_servers(forwarders, &questions[i].name.to_ascii_lowercase())
+            })
+            .clone()
+    };
+
+    zones.iter().find_map(|zone| {
+        zone.check_query(query, client)
+            .or_else(|| zone.check_response(questions.len(), &addresses, &mut lookup))
+    })
+}
+
+/// The name servers of the closest zone holding `name`.
+fn name_servers(forwarders: &Forwarders, name: &str) -> Vec<String> {
+    let mut name = name.trim_end_matches('.');
+    while !name.is_empty() {
+        match upstream::query(forwarders, name, DnsType::NS) {
+            Ok(response) => {
+                let name_servers: Vec<String> = response
+                    .answers
+                    .iter()
+                    .filter(|record| record.dns_type == DnsType::NS)
+                    .filter_map(|record| deserialize_name(&record.rd_data, 0).ok())
+                    .map(|(name, _)| name.to_ascii_lowercase())
+                    .collect();
+                if !name_servers.is_empty() {
+                    return name_servers;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to look up the name servers of {name}: {e}");
+                return vec![];
+            }
+        }
+        name = parent(name);
+    }
+
+    vec![]
+}
+
+/// The response `hit` gives `query` from `client`, `None` when it's dropped or
+/// passed through. Logs the hit. The whole query gets it, whichever of its
+/// questions the rule matched.
+pub fn rewrite(
+    hit: &Hit,
+    query: &DnsMsg,
+    client: IpAddr,
+    forwarders: &Forwarders,
+) -> Option<DnsMsg> {
+    let question = query.questions.get(hit.question)?;
+    info!(
+        "rpz {} {:?} {} from {client}: {:?} rule {}, {}",
+        hit.zone,
+        question.dns_type,
+        question.name,
+        hit.trigger,
+        hit.rule,
+        hit.action.name()
+    );
+
+    let mut response = error_response(query, ResponseCode::NoError);
+    response.header.aa = false;
+    response.header.ra = true;
+    match hit.action {
+        Action::Passthru | Action::Drop => return None,
+        Action::NxDomain => response.header.response_code = ResponseCode::NameError,
+        Action::NoData => {}
+        Action::LocalData(records) => {
+            let local = |record: &DnsRecord| DnsRecord {
+                name: question.name.clone(),
+                ..record.clone()
+            };
+            let cname = records
+                .iter()
+                .find(|record| record.dns_type == DnsType::Cname);
+            response.answers = match cname {
+                // a CNAME to `*.<suffix>` stands for the query name with the suffix appended
+                Some(cname) => {
+                    let (target, _) = deserialize_name(&cname.rd_data, 0).unwrap_or_default();
+                    let mut record = local(cname);
+                    if let Some(suffix) = target.strip_prefix("*.") {
+                        let target = format!("{}.{suffix}", question.name.trim_end_matches('.'));
+                        record.rd_data = serialize_name(&target);
+                        record.rd_length = record.rd_data.len() as u16;
+                    }
+                    vec![record]
+                }
+                None => records
+                    .iter()
+                    .filter(|record| {
+                        question.dns_type == DnsType::AllRecords
+                            || record.dns_type == question.dns_type
+                    })
+                    .map(local)
+                    .collect(),
+            };
+            upstream::complete_chain(&mut response, forwarders);
+        }
+    }
+
+    Some(response)
+}
+
+#[test]
+fn test_rpz() {
+    use crate::zone_file::parse_zone;
+
+    let records = parse_zone(
+        "$TTL 60\n\
+         @ SOA localhost. root.localhost. 1 3600 600 86400 60\n\
+         @ NS localhost.\n\
+         bad.example.com CNAME .\n\
+         *.bad.example.com CNAME .\n\
+         ok.bad.example.com CNAME rpz-passthru.\n\
+         empty.example.com CNAME *.\n\
+         gone.example.com CNAME rpz-drop.\n\
+         portal.example.com A 10.0.0.80\n\
+         portal.example.com TXT \"walled garden\"\n\
+         moved.example.com CNAME *.garden.example.net.\n\
+         24.0.2.0.192.rpz-ip CNAME .\n\
+         32.1.0.0.10.rpz-ip CNAME rpz-passthru.\n\
+         64.zz.db8.2001.rpz-client-ip CNAME rpz-drop.\n\
+         *.example.org.rpz-nsdname CNAME .\n",
+        "rpz.local",
+        Path::new("."),
+    )
+    .unwrap();
+    let zones = vec![PolicyZone::new("rpz.local.", records.clone())];
+    assert_eq!(zones[0].qnames.len(), 7);
+
+    let client: IpAddr = "192.0.2.53".parse().unwrap();
+    let query = |name: &str, dns_type: DnsType| {
+        let mut query =
+            crate::deserialize(&[0, 9, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1]).unwrap();
+        query.questions[0].name = name.into();
+        query.questions[0].dns_type = dns_type;
+        query
+    };
+    let check = |name: &str| {
+        check_query(&zones, &query(name, DnsType::A), client)
+            .map(|hit| (hit.trigger, hit.rule, hit.action.clone()))
+    };
+
+    assert_eq!(
+        check("Bad.example.com"),
+        Some((Trigger::Qname, "bad.example.com".into(), Action::NxDomain))
+    );
+    assert_eq!(
+        check("a.b.bad.example.com"),
+        Some((Trigger::Qname, "*.bad.example.com".into(), Action::NxDomain))
+    );
+    assert_eq!(check("ok.bad.example.com").unwrap().2, Action::Passthru);
+    assert_eq!(check("example.com"), None);
+    let hit = check_query(
+        &zones,
+        &query("x.example.com", DnsType::A),
+        "2001:db8::1".parse().unwrap(),
+    );
+    assert_eq!(hit.unwrap().trigger, Trigger::ClientIp);
+
+    // none of these need the upstream
+    let forwarders = Forwarders::default();
+    let rewrite = |name: &str, dns_type: DnsType| {
+        let query = query(name, dns_type);
+        let hit = check_query(&zones, &query, client).unwrap();
+        rewrite(&hit, &query, client, &forwarders)
+    };
+    let response = rewrite("bad.example.com", DnsType::A).unwrap();
+    assert_eq!(response.header.id, 9);
+    assert_eq!(response.header.response_code, ResponseCode::NameError);
+    let response = rewrite("empty.example.com", DnsType::A).unwrap();
+    assert_eq!(response.header.response_code, ResponseCode::NoError);
+    assert!(response.answers.is_empty());
+    assert!(rewrite("gone.example.com", DnsType::A).is_none());
+    let response = rewrite("portal.example.com", DnsType::A).unwrap();
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].name, "portal.example.com");
+    assert_eq!(response.answers[0].rd_data, vec![10, 0, 0, 80]);
+    assert!(rewrite("portal.example.com", DnsType::Aaaa)
+        .unwrap()
+        .answers
+        .is_empty());
+
+    let mut response = query("www.example.net", DnsType::A);
+    response.answers.push(DnsRecord {
+        name: "www.example.net".into(),
+        dns_type: DnsType::A,
+        dns_class: crate::dns_record::DnsClass::IN,
+        time_to_live: 60,
+        rd_length: 4,
+        rd_data: vec![192, 0, 2, 7],
+    });
+    let hit = check_response(&zones, &response, client, &response, &forwarders).unwrap();
+    assert_eq!(
+        (hit.trigger, hit.rule.as_str()),
+        (Trigger::ResponseIp, "24.0.2.0.192.rpz-ip")
+    );
+
+    // the first zone wins, even when a later one could tell without resolving
+    let later = parse_zone(
+        "$TTL 60\n\
+         @ SOA localhost. root.localhost. 1 3600 600 86400 60\n\
+         www.example.net CNAME *.\n",
+        "later.local",
+        Path::new("."),
+    )
+    .unwrap();
+    let zones = vec![
+        PolicyZone::new("rpz.local", records),
+        PolicyZone::new("later.local", later),
+    ];
+    assert!(check_query(&zones, &response, client).is_none());
+    let hit = check_response(&zones, &response, client, &response, &forwarders).unwrap();
+    assert_eq!((hit.zone, hit.trigger), ("rpz.local", Trigger::ResponseIp));
+    response.answers[0].rd_data = vec![198, 51, 100, 7];
+    let hit = check_response(&zones, &response, client, &response, &forwarders).unwrap();
+    assert_eq!((hit.zone, hit.trigger), ("later.local", Trigger::Qname));
+
+    // a second question doesn't get a name past the rules
+    let mut two = query("www.example.net", DnsType::A);
+    let mut second = two.questions[0].clone();
+    second.name = "portal.example.com".into();
+    two.questions.push(second);
+    let hit = check_query(&zones, &two, client).unwrap();
+    assert_eq!((hit.trigger, hit.question), (Trigger::Qname, 1));
+    let rewritten = crate::rpz::rewrite(&hit, &two, client, &forwarders).unwrap();
+    assert_eq!(rewritten.questions.len(), 2);
+    assert_eq!(rewritten.answers[0].name, "portal.example.com");
+    two.questions[1].name = "example.com".into();
+    two.answers = response.answers.clone();
+    two.answers[0].rd_data = vec![192, 0, 2, 7];
+    let hit = check_response(&zones, &two, client, &two, &forwarders).unwrap();
+    assert_eq!(hit.trigger, Trigger::ResponseIp);
+
+    assert_eq!(
+        parse_ip_rule("48.zz.db8.2001"),
+        Some("2001:db8::/48".parse().unwrap())
+    );
+    assert_eq!(parse_ip_rule("128.1.zz"), Some("::1/128".parse().unwrap()));
+    assert_eq!(parse_ip_rule("33.1.2.0.192"), None);
+    assert_eq!(
+        find_name(&zones[0].nsdnames, "ns1.example.org").map(|(rule, _)| rule),
+        Some("*.example.org".into())
+    );
+}
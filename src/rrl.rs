@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tracing::info;
+
+use crate::acl::Prefix;
+use crate::dns_header::ResponseCode;
+use crate::dns_record::{deserialize_name, DnsType};
+use crate::server::error_response;
+use crate::{serialize, DnsMsg};
+
+/// The client addresses sharing a bucket, as spoofed sources are often
+/// spread over a network.
+const IPV4_PREFIX_LEN: u32 = 24;
+const IPV6_PREFIX_LEN: u32 = 56;
+
+/// How many buckets are kept before the least recently used one goes.
+const MAX_BUCKETS: usize = 100_000;
+
+/// How UDP responses are rate limited.
+#[derive(Debug, Clone)]
+pub struct RrlOptions {
+    /// Answers, delegations and empty answers a client network gets for a
+    /// name each second.
+    pub responses_per_second: u32,
+    /// NXDOMAINs a client network gets for a zone, and errors it gets at
+    /// all, each second.
+    pub errors_per_second: u32,
+    /// Every how many dropped responses one goes out truncated instead, so
+    /// real clients can retry over TCP. 0 never does.
+    pub slip: u32,
+    /// Only logs what would be dropped.
+    pub log_only: bool,
+    /// The clients never limited.
+    pub exempt: Vec<Prefix>,
+}
+
+/// What a limited response is about, a flood of one kind not holding back
+/// the others.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// Keyed on the question's name and type.
+    Response(String, DnsType),
+    /// Keyed on the zone, which random names under it all share.
+    NxDomain(String),
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    network: IpAddr,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    dropped: u32,
+    /// When it was last used, in checks.
+    used: u64,
+}
+
+/// The buckets, with the order they were used in so the least recently used
+/// one goes when there are too many.
+#[derive(Debug)]
+struct Buckets {
+    by_key: HashMap<Key, Bucket>,
+    by_use: BTreeMap<u64, Key>,
+    uses: u64,
+    capacity: usize,
+}
+
+impl Buckets {
+    /// The bucket of `key`, made with `new` if it has none, as the most
+    /// recently used.
+    fn touch(&mut self, key: &Key, new: impl FnOnce() -> Bucket) -> &mut Bucket {
+        self.uses += 1;
+        match self.by_key.get(key) {
+            Some(bucket) => {
+                self.by_use.remove(&bucket.used);
+            }
+            None if self.by_key.len() >= self.capacity => {
+                if let Some((_, oldest)) = self.by_use.pop_first() {
+                    self.by_key.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        self.by_use.insert(self.uses, key.clone());
+        let bucket = self.by_key.entry(key.clone()).or_insert_with(new);
+        bucket.used = self.uses;
+        bucket
+    }
+}
+
+/// What to do with a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    /// Send it truncated and empty.
+    Slip,
+    Drop,
+}
+
+/// Response Rate Limiting: token buckets per client network and response
+/// kind, each filling up at its kind's rate and holding a second's worth.
+#[derive(Debug)]
+pub struct RateLimiter {
+    options: RrlOptions,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(options: RrlOptions) -> RateLimiter {
+        RateLimiter {
+            options,
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                by_use: BTreeMap::new(),
+                uses: 0,
+                capacity: MAX_BUCKETS,
+            }),
+        }
+    }
+
+    /// What to do with a response of `kind` to `client`.
+    pub fn check(&self, client: IpAddr, kind: Kind) -> Verdict {
+        self.check_at(client, kind, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, kind: Kind, now: Instant) -> Verdict {
+        let client = client.to_canonical();
+        if self.options.exempt.iter().any(|p| p.contains(client)) {
+            return Verdict::Send;
+        }
+        let rate = match kind {
+            Kind::Response(..) => self.options.responses_per_second,
+            Kind::NxDomain(_) | Kind::Error => self.options.errors_per_second,
+        } as f64;
+        let key = Key {
+            network: network(client),
+            kind,
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.touch(&key, || Bucket {
+            tokens: rate,
+            last: now,
+            dropped: 0,
+            used: 0,
+        });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.dropped = 0;
+            return Verdict::Send;
+        }
+
+        bucket.dropped += 1;
+        if bucket.dropped == 1 {
+            let action = if self.options.log_only {
+                "would limit"
+            } else {
+                "limiting"
+            };
+            info!("{action} responses to {}: {:?}", key.network, key.kind);
+        }
+        if self.options.log_only {
+            Verdict::Send
+        } else if self.options.slip > 0 && bucket.dropped % self.options.slip == 0 {
+            Verdict::Slip
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+/// The network `client` is counted with.
+fn network(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX << (32 - IPV4_PREFIX_LEN);
+            IpAddr::V4((u32::from(address) & mask).into())
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX << (128 - IPV6_PREFIX_LEN);
+            IpAddr::V6((u128::from(address) & mask).into())
+        }
+    }
+}
+
+/// What the raw `response` to `query` is about, read from the header
+/// alone unless it's an NXDOMAIN.
+pub fn kind(query: &DnsMsg, response: &[u8]) -> Kind {
+    let (Some(question), Some(flags)) = (query.questions.first(), response.get(3)) else {
+        return Kind::Error;
+    };
+
+    match flags & 0x0f {
+        rcode if rcode == ResponseCode::NoError as u8 => {
+            Kind::Response(question.name.to_ascii_lowercase(), question.dns_type)
+        }
+        // the zone's SOA comes along with NXDOMAINs, the name itself otherwise
+        rcode if rcode == ResponseCode::NameError as u8 => {
+            let zone = soa_owner(response).unwrap_or_else(|| question.name.clone());
+            Kind::NxDomain(zone.to_ascii_lowercase())
+        }
+        _ => Kind::Error,
+    }
+}
+
+/// The owner of the first SOA in the authority section of the raw
+/// `response`, the records before it skipped rather than decoded.
+fn soa_owner(response: &[u8]) -> Option<String> {
+    let read_u16 = |index: usize| {
+        let bytes = response.get(index..index + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let answers = read_u16(6)? as usize;
+    let records = answers + read_u16(8)? as usize;
+
+    let mut index = 12;
+    for _ in 0..read_u16(4)? {
+        index = deserialize_name(response, index).ok()?.1 + 4;
+    }
+    for n in 0..records {
+        let (name, end) = deserialize_name(response, index).ok()?;
+        if n >= answers && read_u16(end)? == u16::from(DnsType::Soa) {
+            return Some(name);
+        }
+        // type, class, TTL and the RDATA length come before the RDATA
+        index = end + 10 + read_u16(end + 8)? as usize;
+    }
+
+    None
+}
+
+/// An empty response to `query` with the response code of the raw
+/// `response`, marked truncated: too small to amplify anything, and sending
+/// real clients over to TCP.
+pub fn slip(query: &DnsMsg, response: &[u8]) -> Vec<u8> {
+    let mut slipped = error_response(query, ResponseCode::NoError);
+    slipped.header.tc = true;
+    let mut slipped = serialize(&slipped);
+    // copied as is, as not every response code has a variant
+    if let Some(flags) = response.get(3) {
+        slipped[3] |= flags & 0x0f;
+    }
+
+    slipped
+}
+
+#[test]
+fn test_rate_limiter() {
+    use std::time::Duration;
+
+    use crate::deserialize;
+    use crate::dns_record::{DnsClass, DnsRecord};
+
+    let limiter = RateLimiter::new(RrlOptions {
+        responses_per_second: 2,
+        errors_per_second: 1,
+        slip: 2,
+        log_only: false,
+        exempt: vec!["198.51.100.0/24".parse().unwrap()],
+    });
+    let mut msg = deserialize(&[0, 5, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1]).unwrap();
+    msg.questions[0].name = "www.example.com".into();
+    msg.answers.push(DnsRecord {
+        name: "www.example.com".into(),
+        dns_type: DnsType::A,
+        dns_class: DnsClass::IN,
+        time_to_live: 300,
+        rd_length: 4,
+        rd_data: vec![192, 0, 2, 1],
+    });
+    let start = Instant::now();
+    let check = |client: &str, msg: &DnsMsg, after: u64| {
+        let now = start + Duration::from_millis(after);
+        limiter.check_at(client.parse().unwrap(), kind(msg, &serialize(msg)), now)
+    };
+
+    // a second's worth, then every second dropped one slips
+    assert_eq!(check("192.0.2.1", &msg, 0), Verdict::Send);
+    assert_eq!(check("192.0.2.200", &msg, 0), Verdict::Send);
+    assert_eq!(check("192.0.2.1", &msg, 0), Verdict::Drop);
+    assert_eq!(check("192.0.2.1", &msg, 0), Verdict::Slip);
+    assert_eq!(check("192.0.2.1", &msg, 0), Verdict::Drop);
+    assert_eq!(check("192.0.3.1", &msg, 0), Verdict::Send);
+    assert_eq!(check("198.51.100.1", &msg, 0), Verdict::Send);
+    assert_eq!(check("198.51.100.1", &msg, 0), Verdict::Send);
+    assert_eq!(check("198.51.100.1", &msg, 0), Verdict::Send);
+    assert_eq!(check("192.0.2.1", &msg, 500), Verdict::Send);
+    assert_eq!(check("192.0.2.1", &msg, 500), Verdict::Drop);
+
+    // random names under a zone share its NXDOMAIN bucket
+    msg.header.response_code = ResponseCode::NameError;
+    msg.answers.clear();
+    msg.authority.push(DnsRecord {
+        name: "example.com".into(),
+        dns_type: DnsType::Soa,
+        dns_class: DnsClass::IN,
+        time_to_live: 300,
+        rd_length: 0,
+        rd_data: vec![],
+    });
+    for (name, verdict) in [
+        ("a.example.com", Verdict::Send),
+        ("b.example.com", Verdict::Drop),
+    ] {
+        msg.questions[0].name = name.into();
+        assert_eq!(check("2001:db8::1", &msg, 0), verdict);
+    }
+    assert_eq!(check("2001:db8:0:ff::1", &msg, 0), Verdict::Slip);
+    assert_eq!(check("2001:db8:1::1", &msg, 0), Verdict::Send);
+
+    let slipped = deserialize(&slip(&msg, &serialize(&msg))).unwrap();
+    assert!(slipped.header.tc && slipped.authority.is_empty());
+    assert_eq!(slipped.header.response_code, ResponseCode::NameError);
+    assert_eq!(slipped.questions, msg.questions);
+
+    let log_only = RateLimiter::new(RrlOptions {
+        log_only: true,
+        ..limiter.options.clone()
+    });
+    for _ in 0..5 {
+        assert_eq!(
+            log_only.check_at("192.0.2.1".parse().unwrap(), Kind::Error, start),
+            Verdict::Send
+        );
+    }
+
+    // making room drops the least recently used bucket
+    let small = RateLimiter::new(limiter.options.clone());
+    small.buckets.lock().unwrap().capacity = 2;
+    let check = |client: &str| small.check_at(client.parse().unwrap(), Kind::Error, start);
+    assert_eq!(check("192.0.2.1"), Verdict::Send);
+    assert_eq!(check("192.0.3.1"), Verdict::Send);
+    assert_eq!(check("192.0.2.1"), Verdict::Drop);
+    assert_eq!(check("192.0.4.1"), Verdict::Send);
+    assert_eq!(check("192.0.2.1"), Verdict::Slip);
+    assert_eq!(check("192.0.3.1"), Verdict::Send);
+    assert_eq!(small.buckets.lock().unwrap().by_key.len(), 2);
+}
@@ -0,0 +1,268 @@
+use std::cmp::Ordering;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use tracing::{error, info, warn};
+
+use crate::dns_header::ResponseCode;
+use crate::dns_record::{is_well_formed, DnsRecord, DnsType};
+use crate::journal::{diff, Journal};
+use crate::server::Server;
+use crate::transfer::{request_axfr, request_ixfr, transfer_query, Transfer};
+use crate::tsig::Key;
+use crate::zone::{soa_serial, Zone, ZoneOptions};
+use crate::{notify, upstream, zone_file};
+
+/// How long to wait between attempts while we have no copy of the zone at all.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// A zone we serve as a copy of the one on `primary`.
+#[derive(Debug, Clone)]
+pub struct Secondary {
+    pub origin: String,
+    pub primary: SocketAddr,
+    /// Where the transferred copy is kept so it survives restarts.
+    pub path: PathBuf,
+    pub options: ZoneOptions,
+}
+
+/// What the listeners need to pass a NOTIFY for a secondary zone on to the
+/// thread keeping it in sync.
+#[derive(Debug)]
+pub struct SecondaryHandle {
+    pub origin: String,
+    pub primary: IpAddr,
+    /// NOTIFYs have to be signed with it when there is one.
+    pub tsig_key: Option<Key>,
+    pub wake: Sender<()>,
+}
+
+/// Compares two SOA serials with RFC 1982 serial number arithmetic, where
+/// the numbers wrap around. Serials exactly 2^31 apart can't be compared.
+pub fn serial_cmp(a: u32, b: u32) -> Option<Ordering> {
+    let distance = a.wrapping_sub(b);
+    match distance {
+        0 => Some(Ordering::Equal),
+        0x8000_0000 => None,
+        d if d < 0x8000_0000 => Some(Ordering::Greater),
+        _ => Some(Ordering::Less),
+    }
+}
+
+/// Keeps the zone in sync with its primary: checks the serial every refresh
+/// interval or as soon as a NOTIFY comes through `wake`, transfers the zone when
+/// it changed, retries failed checks and stops serving the zone once it couldn't
+/// be checked for longer than its expire time.
+pub fn run(server: Arc<Server>, secondary: Secondary, wake: Receiver<()>) {
+    let mut last_refresh = load_persisted(&server, &secondary);
+
+    loop {
+        let wait = match refresh(&server, &secondary) {
+            Ok(()) => {
+                last_refresh = Some(SystemTime::now());
+                current_timers(&server, &secondary).map_or(INITIAL_RETRY, |(refresh, _, _)| refresh)
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to refresh {} from {}: {e:#}",
+                    secondary.origin, secondary.primary
+                );
+                match current_timers(&server, &secondary) {
+                    Some((_, retry, expire)) => {
+                        if is_expired(last_refresh, expire) {
+                            info!("zone {} expired, no longer serving it", secondary.origin);
+                            server.catalog.write().unwrap().remove(&secondary.origin);
+                        }
+                        retry
+                    }
+                    None => INITIAL_RETRY,
+                }
+            }
+        };
+
+        if let Err(RecvTimeoutError::Disconnected) = wake.recv_timeout(wait) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Checks the primary's serial and transfers the zone if it's newer than ours.
+fn refresh(server: &Server, secondary: &Secondary) -> anyhow::Result<()> {
+    let current = server
+        .catalog
+        .read()
+        .unwrap()
+        .get(&secondary.origin)
+        .cloned();
+
+    let key = secondary.options.tsig_key.as_ref();
+    let primary_serial = query_serial(secondary)?;
+    if let Some(current) = &current {
+        let current_serial = current.serial();
+        match serial_cmp(primary_serial, current_serial) {
+            Some(Ordering::Greater) => {}
+            Some(Ordering::Equal) => return Ok(()),
+            _ => {
+                warn!(
+                    "{} has serial {primary_serial} for {}, older than our {current_serial}",
+                    secondary.primary, secondary.origin
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    let mut zone = match current {
+        Some(current) => match request_ixfr(secondary.primary, &current, key) {
+            Ok(Transfer::UpToDate) => return Ok(()),
+            Ok(Transfer::Incremental(changes)) => {
+                let mut zone = current;
+                for change in changes {
+                    zone.apply(&change)?;
+                    zone.journal.append(change)?;
+                }
+                zone
+            }
+            Ok(Transfer::Full(records)) => replace(&current, records)?,
+            Err(e) => {
+                warn!("IXFR of {} failed, trying AXFR: {e:#}", secondary.origin);
+                replace(
+                    &current,
+                    request_axfr(secondary.primary, &secondary.origin, key)?,
+                )?
+            }
+        },
+        None => {
+            let records = request_axfr(secondary.primary, &secondary.origin, key)?;
+            let mut zone = Zone::new(&secondary.origin, records)?;
+            zone.journal = Journal::open(journal_path(secondary), zone.serial());
+            zone
+        }
+    };
+    zone.options = secondary.options.clone();
+
+    let records: Vec<DnsRecord> = zone.records().cloned().collect();
+    zone_file::write_zone_file(&secondary.path, &records)
+        .with_context(|| format!("saving {}", secondary.path.display()))?;
+    info!(
+        "transferred zone {} serial {} from {}",
+        zone.origin,
+        zone.serial(),
+        secondary.primary
+    );
+    notify::send_notify(zone.soa().clone(), &zone.options);
+    server.catalog.write().unwrap().insert(zone);
+
+    Ok(())
+}
+
+/// The zone made of the fully transferred `records`, journaling what changed since `current`.
+fn replace(current: &Zone, records: Vec<DnsRecord>) -> anyhow::Result<Zone> {
+    let mut zone = Zone::new(&current.origin, records)?;
+    zone.journal = current.journal.clone();
+    zone.journal.append(diff(current, &zone))?;
+
+    Ok(zone)
+}
+
+fn journal_path(secondary: &Secondary) -> PathBuf {
+    PathBuf::from(format!("{}.jnl", secondary.path.display()))
+}
+
+fn query_serial(secondary: &Secondary) -> anyhow::Result<u32> {
+    let query = transfer_query(&secondary.origin, DnsType::Soa);
+    let key = secondary.options.tsig_key.as_ref();
+    let response = upstream::exchange_signed(secondary.primary, &query, key)?;
+
+    let soa = response
+        .answers
+        .iter()
+        .find(|record| record.dns_type == DnsType::Soa);
+    match soa {
+        Some(soa) if !is_well_formed(DnsType::Soa, &soa.rd_data) => bail!(
+            "{} sent a malformed SOA for {}",
+            secondary.primary,
+            secondary.origin
+        ),
+        Some(soa)
+            if response.header.aa && response.header.response_code == ResponseCode::NoError =>
+        {
+            soa_serial(soa).context("SOA too short")
+        }
+        _ => bail!(
+            "{} isn't authoritative for {}",
+            secondary.primary,
+            secondary.origin
+        ),
+    }
+}
+
+fn current_timers(
+    server: &Server,
+    secondary: &Secondary,
+) -> Option<(Duration, Duration, Duration)> {
+    let catalog = server.catalog.read().unwrap();
+    catalog.get(&secondary.origin).map(|zone| zone.timers())
+}
+
+fn is_expired(last_refresh: Option<SystemTime>, expire: Duration) -> bool {
+    last_refresh.map_or(true, |last| last.elapsed().unwrap_or_default() > expire)
+}
+
+/// Serves the copy saved by a previous run, unless it has expired since.
+/// Returns when that copy was last known to be current.
+fn load_persisted(server: &Server, secondary: &Secondary) -> Option<SystemTime> {
+    if !secondary.path.exists() {
+        return None;
+    }
+
+    let loaded = Zone::load(&secondary.origin, &secondary.path).and_then(|zone| {
+        let modified = std::fs::metadata(&secondary.path)?.modified()?;
+        Ok((zone, modified))
+    });
+    let (mut zone, modified) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!(
+                "Can't read the saved copy of {} at {}, transferring it again: {e:#}",
+                secondary.origin,
+                secondary.path.display()
+            );
+            return None;
+        }
+    };
+
+    let (_, _, expire) = zone.timers();
+    if is_expired(Some(modified), expire) {
+        info!("saved copy of {} has expired", secondary.origin);
+        return Some(modified);
+    }
+
+    info!(
+        "serving saved copy of zone {} serial {}",
+        zone.origin,
+        zone.serial()
+    );
+    zone.options = secondary.options.clone();
+    zone.journal = Journal::open(journal_path(secondary), zone.serial());
+    server.catalog.write().unwrap().insert(zone);
+
+    Some(modified)
+}
+
+#[test]
+fn test_serial_cmp() {
+    assert_eq!(serial_cmp(2, 1), Some(Ordering::Greater));
+    assert_eq!(serial_cmp(1, 2), Some(Ordering::Less));
+    assert_eq!(serial_cmp(7, 7), Some(Ordering::Equal));
+    // wrapping around: 0 comes right after 2^32 - 1
+    assert_eq!(serial_cmp(0, u32::MAX), Some(Ordering::Greater));
+    assert_eq!(serial_cmp(5, 0xFFFF_FFF0), Some(Ordering::Greater));
+    assert_eq!(serial_cmp(0xFFFF_FFF0, 5), Some(Ordering::Less));
+    assert_eq!(serial_cmp(0x8000_0000, 0), None);
+    assert_eq!(serial_cmp(0x7FFF_FFFF, 0), Some(Ordering::Greater));
+}
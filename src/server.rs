@@ -0,0 +1,739 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{debug, error, info, warn};
+
+use crate::acl::{Acls, DeniedResponse};
+use crate::additional::{self, UDP_MAX_SIZE};
+use crate::blocklist::{BlockResponse, Blocklist};
+use crate::dns_header::{deserialize_header, OpCode, ResponseCode, QR};
+use crate::dns_record::{DnsRecord, DnsType};
+use crate::hosts::Hosts;
+use crate::metrics::METRICS;
+use crate::rpz::{self, Action, PolicyZone};
+use crate::rrl::{self, RateLimiter, Verdict};
+use crate::secondary::SecondaryHandle;
+use crate::tsig::{self, Key};
+use crate::upstream::Forwarders;
+use crate::zone::{Catalog, Zone};
+use crate::{
+    deserialize, dnstap, logging, notify, serialize, transfer, truncate, update, upstream,
+    zone_file, DnsMsg,
+};
+
+/// The largest message the two byte length prefix of DNS over TCP can frame.
+pub const TCP_MAX_SIZE: usize = 65535;
+
+/// How long a TCP connection may sit without sending a query (RFC 7766 section 6.2.3).
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a query came over. Everything but UDP carries whole messages of
+/// any size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+    Quic,
+}
+
+impl Transport {
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+            Transport::Quic => "quic",
+        }
+    }
+}
+
+/// Everything needed to answer a query, shared by all the listeners.
+pub struct Server {
+    /// Written to by zone transfers, so never held across network round trips.
+    pub catalog: RwLock<Catalog>,
+    /// Names answered with the settings' `block_response` instead of being
+    /// forwarded. Swapped whole when the lists are reloaded.
+    pub blocklist: RwLock<Blocklist>,
+    /// The secondary zones, to pass on NOTIFYs from their primaries.
+    pub secondaries: Vec<SecondaryHandle>,
+    /// Swapped whole when the configuration is reloaded, the queries being
+    /// answered keeping the settings they started with.
+    pub settings: RwLock<Arc<Settings>>,
+}
+
+/// How queries are answered, as configured.
+pub struct Settings {
+    /// Where the queries we can't answer ourselves go, by name suffix.
+    pub forwarders: Forwarders,
+    /// Names pinned to addresses, which override our zones and the upstream.
+    pub hosts: Hosts,
+    pub block_response: BlockResponse,
+    /// The lists the blocklist is loaded from, and the names never blocked.
+    pub blocklists: Vec<PathBuf>,
+    pub allowlists: Vec<PathBuf>,
+    /// Response policy zones rewriting forwarded queries, the first one
+    /// matching winning.
+    pub rpz: Vec<PolicyZone>,
+    /// The keys requests may be signed with.
+    pub tsig_keys: Vec<Key>,
+    /// Holds back floods of UDP responses, which spoofed queries could
+    /// aim at someone else.
+    pub rrl: Option<RateLimiter>,
+    /// Who may query and get recursion.
+    pub acls: Acls,
+}
+
+impl Server {
+    pub fn settings(&self) -> Arc<Settings> {
+        Arc::clone(&self.settings.read().unwrap())
+    }
+
+    /// Swaps in a reloaded configuration's primary `zones`, `blocklist` and
+    /// `settings` together, keeping the secondary zones as they are. The
+    /// secondaries of zones whose serial changed are notified.
+    pub fn reload(&self, zones: Vec<Zone>, blocklist: Blocklist, settings: Settings) {
+        let mut catalog = self.catalog.write().unwrap();
+        for zone in &zones {
+            let serial = zone.serial();
+            let old = catalog.get(&zone.origin).map(|old| old.serial());
+            if old != Some(serial) {
+                notify::send_notify(zone.soa().clone(), &zone.options);
+            }
+        }
+        let secondaries = self
+            .secondaries
+            .iter()
+            .filter_map(|secondary| catalog.get(&secondary.origin).cloned());
+        let zones = zones.into_iter().chain(secondaries).collect();
+
+        // the settings go first, so the blocklist reloads see they're stale
+        *self.settings.write().unwrap() = Arc::new(settings);
+        *self.blocklist.write().unwrap() = blocklist;
+        *catalog = Catalog::new(zones);
+    }
+
+    /// The response messages to the raw `request`. Only zone transfers, which
+    /// need TCP, ever get more than one.
+    pub fn handle(&self, request: &[u8], client: SocketAddr, transport: Transport) -> Vec<Vec<u8>> {
+        let _in_flight = METRICS.in_flight();
+        let started = Instant::now();
+        let query_time = SystemTime::now();
+        upstream::take_last_upstream();
+        dnstap::sample_query();
+        let settings = self.settings();
+        let responses = self.handle_request(&settings, request, client, transport);
+        let response = responses.first().map(Vec::as_slice);
+        METRICS.count_query(request, response, transport);
+        dnstap::log_client(request, response, client, transport, query_time);
+        logging::log_query(
+            request,
+            response,
+            client,
+            transport,
+            started.elapsed(),
+            upstream::take_last_upstream().as_deref(),
+        );
+        responses
+    }
+
+    fn handle_request(
+        &self,
+        settings: &Settings,
+        request: &[u8],
+        client: SocketAddr,
+        transport: Transport,
+    ) -> Vec<Vec<u8>> {
+        let query = match deserialize(request) {
+            Ok(query) => query,
+            Err(e) => {
+                warn!("Malformed request from {client}: {e}");
+                let responses = format_error(request).into_iter().collect();
+                return rate_limit(settings, None, client, transport, responses);
+            }
+        };
+
+        let responses = self.handle_query(settings, request, &query, client, transport);
+        rate_limit(settings, Some(&query), client, transport, responses)
+    }
+
+    /// The responses to `query`, whose raw form is `request`, once its
+    /// signature if any checks out.
+    fn handle_query(
+        &self,
+        settings: &Settings,
+        request: &[u8],
+        query: &DnsMsg,
+        client: SocketAddr,
+        transport: Transport,
+    ) -> Vec<Vec<u8>> {
+        let max_size = match transport {
+            Transport::Udp => UDP_MAX_SIZE,
+            _ => TCP_MAX_SIZE,
+        };
+
+        let tsig = match tsig::verify_request(request, &settings.tsig_keys) {
+            Ok(tsig) => tsig,
+            Err(e) => {
+                warn!("Rejected signed request from {client}: {:?}", e.error);
+                let response = error_response(query, e.response_code());
+                return vec![e.sign(serialize(&response))];
+            }
+        };
+        let Some(mut tsig) = tsig else {
+            return self.respond(settings, request, query, client, transport, max_size, None);
+        };
+
+        // the signature is no part of the question, and isn't for the upstream to check
+        let mut query = query.clone();
+        query.additional.pop();
+        let key = tsig.key().name.clone();
+        let responses = self.respond(
+            settings,
+            &serialize(&query),
+            &query,
+            client,
+            transport,
+            max_size - tsig.overhead(),
+            Some(&key),
+        );
+        responses
+            .into_iter()
+            .map(|response| tsig.sign(response))
+            .collect()
+    }
+
+    /// The responses to `query` from `client`, whose raw form is `request`,
+    /// signed with the key named `key` if any.
+    #[allow(clippy::too_many_arguments)]
+    fn respond(
+        &self,
+        settings: &Settings,
+        request: &[u8],
+        query: &DnsMsg,
+        client: SocketAddr,
+        transport: Transport,
+        max_size: usize,
+        key: Option<&str>,
+    ) -> Vec<Vec<u8>> {
+        let response = match query.header.op_code {
+            OpCode::Notify => Some(self.notify(query, client, key)),
+            OpCode::Update => Some(self.update(query, client, key)),
+            _ => None,
+        };
+        if let Some(response) = response {
+            // their only refusals are of clients they don't take them from
+            if response.header.response_code == ResponseCode::Refused {
+                return settings.deny(query).iter().map(serialize).collect();
+            }
+            return vec![serialize(&response)];
+        }
+
+        let is_transfer = |q: &DnsRecord| matches!(q.dns_type, DnsType::Axfr | DnsType::Ixfr);
+        if query.questions.iter().any(is_transfer) {
+            return self
+                .zone_transfer(settings, query, client, transport, key)
+                .iter()
+                .map(serialize)
+                .collect();
+        }
+
+        if !settings.acls.allows_query(client.ip(), key) {
+            debug!("Refused query from {client}");
+            return settings.deny(query).iter().map(serialize).collect();
+        }
+        let recursion = settings.acls.allows_recursion(client.ip(), key);
+
+        if let Some(response) = settings.hosts.answer(query) {
+            return vec![serialize(&response)];
+        }
+
+        let answer = self.catalog.read().unwrap().answer(query);
+        if let Some(mut response) = answer {
+            if recursion {
+                upstream::complete_chain(&mut response, &settings.forwarders);
+            }
+            additional::add_additional(
+                &mut response,
+                &self.catalog.read().unwrap(),
+                &settings.hosts,
+                max_size,
+            );
+            truncate(&mut response, max_size);
+            return vec![serialize(&response)];
+        }
+
+        if !recursion {
+            debug!("Refused recursion to {client}");
+            return settings.deny(query).iter().map(serialize).collect();
+        }
+
+        let blocked = self
+            .blocklist
+            .read()
+            .unwrap()
+            .answer(query, settings.block_response);
+        if let Some(response) = blocked {
+            return vec![serialize(&response)];
+        }
+
+        let forwarder = query
+            .questions
+            .first()
+            .and_then(|question| settings.forwarders.select(&question.name));
+        let Some(forwarder) = forwarder else {
+            return vec![serialize(&error_response(query, ResponseCode::Refused))];
+        };
+
+        let forwarders = &settings.forwarders;
+        let hit = rpz::check_query(&settings.rpz, query, client.ip());
+        let rewritten = hit
+            .as_ref()
+            .and_then(|hit| apply_policy(hit, query, client, forwarders));
+        if let Some(responses) = rewritten {
+            return responses;
+        }
+
+        if query.questions.len() == 1 {
+            let forwarded = forwarder
+                .forward(request, transport)
+                .map(|response| match transport {
+                    Transport::Udp => {
+                        truncate_raw(query, response, udp_payload_size(query, max_size))
+                    }
+                    _ => response,
+                });
+            return match forwarded {
+                // a passed through query isn't checked again
+                Ok(response) if hit.is_some() || settings.rpz.is_empty() => vec![response],
+                Ok(response) => deserialize(&response)
+                    .ok()
+                    .and_then(|parsed| {
+                        rpz::check_response(&settings.rpz, query, client.ip(), &parsed, forwarders)
+                    })
+                    .and_then(|hit| apply_policy(&hit, query, client, forwarders))
+                    .unwrap_or(vec![response]),
+                Err(e) => {
+                    warn!("Failed to forward query for {forwarder}: {e}");
+                    vec![serialize(&error_response(
+                        query,
+                        ResponseCode::ServerFailure,
+                    ))]
+                }
+            };
+        }
+
+        let mut response = settings.forward_questions(query, transport);
+        if hit.is_none() && !settings.rpz.is_empty() {
+            let rewritten =
+                rpz::check_response(&settings.rpz, query, client.ip(), &response, forwarders)
+                    .and_then(|hit| apply_policy(&hit, query, client, forwarders));
+            if let Some(responses) = rewritten {
+                return responses;
+            }
+        }
+        additional::add_additional(
+            &mut response,
+            &self.catalog.read().unwrap(),
+            &settings.hosts,
+            max_size,
+        );
+        truncate(&mut response, max_size);
+        vec![serialize(&response)]
+    }
+
+    /// Answers an AXFR or IXFR from a client in the zone's allow-list. AXFR
+    /// needs TCP, and IXFR over UDP only ever gets our SOA (RFC 1995 section 2)
+    /// which tells the client to come back over TCP if it's behind.
+    fn zone_transfer(
+        &self,
+        settings: &Settings,
+        query: &DnsMsg,
+        client: SocketAddr,
+        transport: Transport,
+        key: Option<&str>,
+    ) -> Vec<DnsMsg> {
+        let question = &query.questions[0];
+        let catalog = self.catalog.read().unwrap();
+        let zone = catalog.get(&question.name);
+
+        let Some(zone) = zone else {
+            return vec![error_response(query, ResponseCode::NotAuth)];
+        };
+        let is_axfr = question.dns_type == DnsType::Axfr;
+        if is_axfr && transport == Transport::Udp {
+            return vec![error_response(query, ResponseCode::Refused)];
+        }
+        if !zone
+            .options
+            .allow_transfer
+            .iter()
+            .any(|grantee| grantee.allows(client.ip(), key))
+        {
+            info!("Refused transfer of {} to {client}", zone.origin);
+            return settings.deny(query);
+        }
+
+        info!("Transferring {} to {client}", zone.origin);
+        match (is_axfr, transport) {
+            (true, _) => transfer::axfr(zone, query, transfer::TRANSFER_MESSAGE_SIZE),
+            (false, Transport::Udp) => {
+                transfer::pack_records(query, vec![zone.soa().clone()], UDP_MAX_SIZE)
+            }
+            (false, _) => transfer::ixfr(zone, query, transfer::TRANSFER_MESSAGE_SIZE),
+        }
+    }
+
+    /// Acknowledges a NOTIFY (RFC 1996) from the primary of one of our
+    /// secondary zones and has that zone checked right away. When the zone has
+    /// a key, the NOTIFY has to be signed with it.
+    fn notify(&self, query: &DnsMsg, client: SocketAddr, key: Option<&str>) -> DnsMsg {
+        let [question] = query.questions.as_slice() else {
+            return error_response(query, ResponseCode::FormatError);
+        };
+        let origin = question.name.trim_end_matches('.');
+        let Some(secondary) = self
+            .secondaries
+            .iter()
+            .find(|secondary| secondary.origin.eq_ignore_ascii_case(origin))
+        else {
+            return error_response(query, ResponseCode::NotAuth);
+        };
+        if secondary.primary.to_canonical() != client.ip().to_canonical() {
+            warn!("Ignoring NOTIFY for {origin} from {client}, which isn't its primary");
+            return error_response(query, ResponseCode::Refused);
+        }
+        if let Some(expected) = &secondary.tsig_key {
+            if key != Some(expected.name.as_str()) {
+                warn!("Ignoring NOTIFY for {origin} from {client} not signed with its key");
+                return error_response(query, ResponseCode::Refused);
+            }
+        }
+
+        info!("NOTIFY for {origin} from {client}");
+        // the refresh thread may be busy, in which case it checks again once done
+        let _ = secondary.wake.send(());
+
+        let mut response = error_response(query, ResponseCode::NoError);
+        response.header.aa = true;
+        response
+    }
+
+    /// Applies a dynamic update (RFC 2136) to one of our primary zones, saving
+    /// the zone and notifying its secondaries when it changed.
+    fn update(&self, query: &DnsMsg, client: SocketAddr, key: Option<&str>) -> DnsMsg {
+        // the question section holds the zone being updated
+        let [zone_section] = query.questions.as_slice() else {
+            return error_response(query, ResponseCode::FormatError);
+        };
+        if zone_section.dns_type != DnsType::Soa {
+            return error_response(query, ResponseCode::FormatError);
+        }
+        let origin = zone_section.name.trim_end_matches('.');
+        if self
+            .secondaries
+            .iter()
+            .any(|secondary| secondary.origin.eq_ignore_ascii_case(origin))
+        {
+            // updates have to go to the primary, we don't forward them
+            return error_response(query, ResponseCode::Refused);
+        }
+
+        // held until the new version is in, so concurrent updates can't undo each other
+        let mut catalog = self.catalog.write().unwrap();
+        let Some(zone) = catalog.get(origin) else {
+            return error_response(query, ResponseCode::NotAuth);
+        };
+        let zone = match update::apply_update(zone, query, client.ip(), key) {
+            Ok(Some(zone)) => zone,
+            Ok(None) => return error_response(query, ResponseCode::NoError),
+            Err(response_code) => return error_response(query, response_code),
+        };
+
+        if let Some(path) = &zone.path {
+            let records: Vec<DnsRecord> = zone.records().cloned().collect();
+            if let Err(e) = zone_file::write_zone_file(path, &records) {
+                error!("Failed to save {}: {e}", path.display());
+                return error_response(query, ResponseCode::ServerFailure);
+            }
+        }
+        info!(
+            "updated zone {} to serial {} for {client}",
+            zone.origin,
+            zone.serial()
+        );
+        notify::send_notify(zone.soa().clone(), &zone.options);
+        catalog.insert(zone);
+
+        error_response(query, ResponseCode::NoError)
+    }
+}
+
+impl Settings {
+    /// The response to a client turned away, if it gets any.
+    fn deny(&self, query: &DnsMsg) -> Vec<DnsMsg> {
+        match self.acls.denied_response {
+            DeniedResponse::Refused => vec![error_response(query, ResponseCode::Refused)],
+            DeniedResponse::Drop => vec![],
+        }
+    }
+
+    /// Asks each question's forwarder about it on its own, since most resolvers
+    /// only answer the first, and merges the answers.
+    fn forward_questions(&self, query: &DnsMsg, transport: Transport) -> DnsMsg {
+        let mut header = query.header;
+        header.questions_count = 1;
+
+        let mut response = query.clone();
+        response.header.query = QR::Response;
+        for question in &query.questions {
+            let msg = DnsMsg {
+                header,
+                questions: vec![question.clone()],
+                authority: vec![],
+                additional: vec![],
+                answers: vec![],
+            };
+
+            let Some(forwarder) = self.forwarders.select(&question.name) else {
+                return error_response(query, ResponseCode::Refused);
+            };
+            match forwarder.exchange(&msg, transport) {
+                Ok(resolver_msg) => response.answers.extend(resolver_msg.answers),
+                Err(e) => {
+                    warn!("Failed to forward query for {forwarder}: {e}");
+                    return error_response(query, ResponseCode::ServerFailure);
+                }
+            }
+        }
+        response.header.answers_count = response.answers.len() as u16;
+
+        response
+    }
+}
+
+/// The responses a policy `hit` gives `query`, none when it's dropped, `None`
+/// when it passes the query through.
+fn apply_policy(
+    hit: &rpz::Hit,
+    query: &DnsMsg,
+    client: SocketAddr,
+    forwarders: &Forwarders,
+) -> Option<Vec<Vec<u8>>> {
+    let response = rpz::rewrite(hit, query, client.ip(), forwarders);
+    match hit.action {
+        Action::Passthru => None,
+        _ => Some(response.iter().map(serialize).collect()),
+    }
+}
+
+/// An empty response to `query` with `response_code`.
+pub fn error_response(query: &DnsMsg, response_code: ResponseCode) -> DnsMsg {
+    DnsMsg {
+        header: crate::dns_header::DnsHeader {
+            query: QR::Response,
+            response_code,
+            ..query.header
+        },
+        questions: query.questions.clone(),
+        answers: vec![],
+        authority: vec![],
+        additional: vec![],
+    }
+}
+
+/// The UDP `responses` to `query` as the rate limiter lets them out to
+/// `client`. Those to requests we couldn't read count as errors, and are
+/// dropped rather than slipped.
+fn rate_limit(
+    settings: &Settings,
+    query: Option<&DnsMsg>,
+    client: SocketAddr,
+    transport: Transport,
+    responses: Vec<Vec<u8>>,
+) -> Vec<Vec<u8>> {
+    let Some(rrl) = settings
+        .rrl
+        .as_ref()
+        .filter(|_| transport == Transport::Udp)
+    else {
+        return responses;
+    };
+
+    responses
+        .into_iter()
+        .filter_map(|response| {
+            let kind = query.map_or(rrl::Kind::Error, |query| rrl::kind(query, &response));
+            let verdict = rrl.check(client.ip(), kind);
+            METRICS.count_rate_limited(verdict);
+            match verdict {
+                Verdict::Send => Some(response),
+                Verdict::Slip => query.map(|query| rrl::slip(query, &response)),
+                Verdict::Drop => None,
+            }
+        })
+        .collect()
+}
+
+/// The most a UDP response to `query` may hold: what its EDNS record says
+/// the client takes, and never less than `max_size`.
+fn udp_payload_size(query: &DnsMsg, max_size: usize) -> usize {
+    query
+        .additional
+        .iter()
+        .find(|record| record.dns_type == DnsType::Opt)
+        .map_or(max_size, |opt| {
+            usize::from(u16::from(opt.dns_class)).max(max_size)
+        })
+}
+
+/// The raw upstream `response` to `query` cut down to `max_size` bytes like
+/// `truncate` does, for the upstreams that send it whole whatever the
+/// client takes.
+fn truncate_raw(query: &DnsMsg, response: Vec<u8>, max_size: usize) -> Vec<u8> {
+    if response.len() <= max_size {
+        return response;
+    }
+
+    let mut msg = deserialize(&response)
+        .unwrap_or_else(|_| error_response(query, ResponseCode::ServerFailure));
+    truncate(&mut msg, max_size);
+    serialize(&msg)
+}
+
+/// A FORMERR for the raw `request` we couldn't parse, none when it hasn't
+/// even a header or is a response itself.
+fn format_error(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < 12 {
+        return None;
+    }
+    let query = DnsMsg {
+        header: deserialize_header(request),
+        questions: vec![],
+        answers: vec![],
+        authority: vec![],
+        additional: vec![],
+    };
+    if query.header.query == QR::Response {
+        return None;
+    }
+
+    Some(serialize(&error_response(
+        &query,
+        ResponseCode::FormatError,
+    )))
+}
+
+pub fn serve_udp(server: &Server, udp_socket: UdpSocket) {
+    let mut buf_client = vec![0; TCP_MAX_SIZE];
+
+    loop {
+        match udp_socket.recv_from(&mut buf_client) {
+            Ok((size_client, source_client)) => {
+                let request = &buf_client[0..size_client];
+                for response in server.handle(request, source_client, Transport::Udp) {
+                    if let Err(e) = udp_socket.send_to(&response, source_client) {
+                        warn!("Failed to send response to {source_client}: {e}");
+                    }
+                }
+            }
+            // errors like the ICMP unreachable of an earlier response belong to that client alone
+            Err(e) => warn!("Error receiving data: {}", e),
+        }
+    }
+}
+
+/// Accepts TCP connections, each served by a thread of its own.
+pub fn serve_tcp(server: Arc<Server>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            if let Err(e) = serve_connection(&server, stream) {
+                warn!("Error on TCP connection: {}", e);
+            }
+        });
+    }
+}
+
+/// Answers the queries of one connection in order until the client closes it
+/// or stays idle for too long.
+fn serve_connection(server: &Server, mut stream: TcpStream) -> io::Result<()> {
+    let client = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+
+    serve_stream(server, &mut stream, client, Transport::Tcp)
+}
+
+/// Answers the length-prefixed queries `client` sends over `stream`, which
+/// may come pipelined, in order.
+pub fn serve_stream(
+    server: &Server,
+    stream: &mut (impl Read + Write),
+    client: SocketAddr,
+    transport: Transport,
+) -> io::Result<()> {
+    while let Some(request) = read_message(stream)? {
+        for response in server.handle(&request, client, transport) {
+            write_message(stream, &response)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads one length-prefixed message, or `None` once the peer is done.
+pub fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    }
+
+    let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut msg)?;
+
+    Ok(Some(msg))
+}
+
+pub fn write_message(stream: &mut impl Write, msg: &[u8]) -> io::Result<()> {
+    let mut framed = Vec::with_capacity(msg.len() + 2);
+    framed.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    framed.extend_from_slice(msg);
+
+    stream.write_all(&framed)
+}
+
+#[test]
+fn test_message_framing() {
+    let mut stream = Vec::new();
+    write_message(&mut stream, &[1, 2, 3]).unwrap();
+    write_message(&mut stream, &[4]).unwrap();
+    assert_eq!(stream, [0, 3, 1, 2, 3, 0, 1, 4]);
+
+    let mut reader = stream.as_slice();
+    assert_eq!(read_message(&mut reader).unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(read_message(&mut reader).unwrap(), Some(vec![4]));
+    assert_eq!(read_message(&mut reader).unwrap(), None);
+}
@@ -0,0 +1,146 @@
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::ServerSessionMemoryCache;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use tracing::warn;
+
+use crate::server::{self, Server, Transport};
+
+/// How many TLS sessions are kept around for clients to resume.
+const SESSION_CACHE_SIZE: usize = 1024;
+
+/// The TLS setup for `cert_path`, a PEM certificate chain, and `key_path`,
+/// its PEM private key, offering the `alpn` protocols. Clients can resume
+/// their sessions both from our cache and with tickets.
+pub fn server_config(
+    cert_path: &Path,
+    key_path: &Path,
+    alpn: &[&[u8]],
+) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading certificates from {}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("reading private key from {}", key_path.display()))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("setting up TLS")?;
+    config.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+    config.ticketer = rustls::crypto::ring::Ticketer::new().context("setting up TLS tickets")?;
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Ok(Arc::new(config))
+}
+
+/// Accepts DNS over TLS connections (RFC 7858), each served by a thread of
+/// its own like TCP ones and closed once idle for `idle_timeout`.
+pub fn serve_tls(
+    server: Arc<Server>,
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    idle_timeout: Duration,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Error accepting TLS connection: {}", e);
+                continue;
+            }
+        };
+
+        let server = Arc::clone(&server);
+        let config = Arc::clone(&config);
+        std::thread::spawn(move || {
+            if let Err(e) = serve_connection(&server, stream, config, idle_timeout) {
+                warn!("Error on TLS connection: {}", e);
+            }
+        });
+    }
+}
+
+fn serve_connection(
+    server: &Server,
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let client = stream.peer_addr()?;
+    // also bounds the handshake
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+    let mut stream = StreamOwned::new(connection, stream);
+
+    server::serve_stream(server, &mut stream, client, Transport::Tls)?;
+
+    stream.conn.send_close_notify();
+    stream.flush()
+}
+
+#[test]
+fn test_tls() {
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("test_tls_cert_{}", std::process::id()));
+    let key_path = dir.join(format!("test_tls_key_{}", std::process::id()));
+    std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+    std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+    let config = server_config(&cert_path, &key_path, &[b"dot"]).unwrap();
+    std::fs::remove_file(&cert_path).unwrap();
+    std::fs::remove_file(&key_path).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    // echoes each message, framed as DNS over TCP
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let connection = ServerConnection::new(Arc::clone(&config)).unwrap();
+            let mut stream = StreamOwned::new(connection, stream.unwrap());
+            while let Some(msg) = server::read_message(&mut stream).unwrap() {
+                server::write_message(&mut stream, &msg).unwrap();
+            }
+        }
+    });
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    let mut client_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_config.alpn_protocols = vec![b"dot".to_vec()];
+    let client_config = Arc::new(client_config);
+
+    for resumed in [false, true] {
+        let connection = ClientConnection::new(
+            Arc::clone(&client_config),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+        // pipelined: both queries go out before reading any answer
+        server::write_message(&mut stream, &[1, 2, 3]).unwrap();
+        server::write_message(&mut stream, &[4]).unwrap();
+        assert_eq!(
+            server::read_message(&mut stream).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(server::read_message(&mut stream).unwrap(), Some(vec![4]));
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"dot"[..]));
+        assert_eq!(
+            stream.conn.handshake_kind() == Some(rustls::HandshakeKind::Resumed),
+            resumed
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use std::cmp::Ordering;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::dns_header::{DnsHeader, OpCode, ResponseCode, QR};
+use crate::dns_record::{is_well_formed, serialize_record, DnsClass, DnsRecord, DnsType};
+use crate::journal::Change;
+use crate::secondary::serial_cmp;
+use crate::server::{error_response, read_message, write_message};
+use crate::tsig::{Key, TsigContext};
+use crate::zone::{soa_serial, Zone};
+use crate::{deserialize, serialize, upstream, DnsMsg};
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The size each message of a zone transfer is filled up to, well under the
+/// 65535 bytes a TCP message can carry.
+pub const TRANSFER_MESSAGE_SIZE: usize = 16 * 1024;
+
+/// The messages of an AXFR of `zone` (RFC 5936): the SOA, every other record and
+/// the SOA again, packed into as many messages as needed. Only the first
+/// message repeats the question.
+pub fn axfr(zone: &Zone, query: &DnsMsg, max_size: usize) -> Vec<DnsMsg> {
+    let records = zone
+        .records()
+        .chain(std::iter::once(zone.soa()))
+        .cloned()
+        .collect();
+
+    pack_records(query, records, max_size)
+}
+
+/// The messages of an IXFR of `zone` (RFC 1995) for a client that has the
+/// serial of the SOA in the query's authority section. Clients already up to
+/// date get just our SOA, and an AXFR is sent when the journal doesn't go
+/// back far enough. An SOA that isn't one is a FORMERR.
+pub fn ixfr(zone: &Zone, query: &DnsMsg, max_size: usize) -> Vec<DnsMsg> {
+    let soa = zone.soa();
+    let client_soa = query
+        .authority
+        .iter()
+        .find(|record| record.dns_type == DnsType::Soa);
+    let Some(client_soa) = client_soa else {
+        return axfr(zone, query, max_size);
+    };
+    let client_serial = soa_serial(client_soa);
+    let (Some(client_serial), true) = (
+        client_serial,
+        is_well_formed(DnsType::Soa, &client_soa.rd_data),
+    ) else {
+        return vec![error_response(query, ResponseCode::FormatError)];
+    };
+
+    if serial_cmp(client_serial, zone.serial()) != Some(Ordering::Less) {
+        return pack_records(query, vec![soa.clone()], max_size);
+    }
+    let Some(changes) = zone.journal.changes_since(client_serial) else {
+        return axfr(zone, query, max_size);
+    };
+
+    let mut records = vec![soa.clone()];
+    for change in changes {
+        records.extend(change.deleted.iter().cloned());
+        records.extend(change.added.iter().cloned());
+    }
+    records.push(soa.clone());
+
+    pack_records(query, records, max_size)
+}
+
+/// Splits `records` across responses to `query` of at most `max_size` bytes.
+pub fn pack_records(query: &DnsMsg, records: Vec<DnsRecord>, max_size: usize) -> Vec<DnsMsg> {
+    let mut messages: Vec<DnsMsg> = Vec::new();
+    let mut size = 0;
+
+    for record in records {
+        let record_size = serialize_record(&record, true).len();
+        let fits = messages
+            .last()
+            .is_some_and(|current| size + record_size <= max_size || current.answers.is_empty());
+        if !fits {
+            let message = transfer_response(query, messages.is_empty());
+            size = crate::serialize(&message).len();
+            messages.push(message);
+        }
+
+        let current = messages.last_mut().expect("a message was just pushed");
+        size += record_size;
+        current.answers.push(record);
+        current.header.answers_count = current.answers.len() as u16;
+    }
+
+    messages
+}
+
+/// Sends `query` to `primary` over a new TCP connection, signed with `key` if
+/// there's one, in which case every message of the response has to be signed too.
+fn send_query(
+    primary: SocketAddr,
+    query: &DnsMsg,
+    key: Option<&Key>,
+) -> anyhow::Result<(TcpStream, Option<TsigContext>)> {
+    let mut stream = TcpStream::connect_timeout(&primary, TRANSFER_TIMEOUT)?;
+    stream.set_read_timeout(Some(TRANSFER_TIMEOUT))?;
+
+    let mut tsig = key.map(|key| TsigContext::new(key.clone()));
+    let request = match &mut tsig {
+        Some(tsig) => tsig.sign(serialize(query)),
+        None => serialize(query),
+    };
+    write_message(&mut stream, &request)?;
+
+    Ok((stream, tsig))
+}
+
+/// Pulls `origin` from `primary` with an AXFR and returns its records, SOA first.
+pub fn request_axfr(
+    primary: SocketAddr,
+    origin: &str,
+    key: Option<&Key>,
+) -> anyhow::Result<Vec<DnsRecord>> {
+    let query = transfer_query(origin, DnsType::Axfr);
+    let (mut stream, mut tsig) = send_query(primary, &query, key)?;
+
+    let mut records: Vec<DnsRecord> = Vec::new();
+    let mut first = true;
+    loop {
+        let Some(bytes) = read_message(&mut stream)? else {
+            bail!("{primary} closed the transfer of {origin} early");
+        };
+        let msg = deserialize(&bytes)
+            .with_context(|| format!("reading the transfer of {origin} from {primary}"))?;
+        if !is_response(&msg, &query, first) {
+            bail!("{primary} answered the transfer of {origin} with the wrong id or question");
+        }
+        first = false;
+        if let Some(tsig) = &mut tsig {
+            tsig.verify(&bytes)
+                .with_context(|| format!("checking the transfer of {origin} from {primary}"))?;
+        }
+        if msg.header.response_code != ResponseCode::NoError {
+            bail!(
+                "{primary} refused the transfer of {origin}: {:?}",
+                msg.header.response_code
+            );
+        }
+
+        for record in msg.answers {
+            let is_soa = record.dns_type == DnsType::Soa;
+            if records.is_empty() && !is_soa {
+                bail!("the transfer of {origin} from {primary} doesn't start with its SOA");
+            }
+            if is_soa && !records.is_empty() {
+                // the closing SOA
+                return Ok(records);
+            }
+            records.push(record);
+        }
+    }
+}
+
+/// What an IXFR brought back.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Transfer {
+    UpToDate,
+    /// The changes from our version to the primary's, oldest first.
+    Incremental(Vec<Change>),
+    /// The primary sent the whole zone instead, SOA first.
+    Full(Vec<DnsRecord>),
+}
+
+/// Asks `primary` for the changes to `zone` since our version (RFC 1995).
+pub fn request_ixfr(
+    primary: SocketAddr,
+    zone: &Zone,
+    key: Option<&Key>,
+) -> anyhow::Result<Transfer> {
+    let mut query = transfer_query(&zone.origin, DnsType::Ixfr);
+    query.authority.push(zone.soa().clone());
+    query.header.authority_count = 1;
+    let (mut stream, mut tsig) = send_query(primary, &query, key)?;
+
+    let mut reader = IxfrReader::default();
+    let mut first = true;
+    loop {
+        let Some(bytes) = read_message(&mut stream)? else {
+            bail!("{primary} closed the transfer of {} early", zone.origin);
+        };
+        let msg = deserialize(&bytes)
+            .with_context(|| format!("reading the transfer of {} from {primary}", zone.origin))?;
+        if !is_response(&msg, &query, first) {
+            bail!(
+                "{primary} answered the transfer of {} with the wrong id or question",
+                zone.origin
+            );
+        }
+        first = false;
+        if let Some(tsig) = &mut tsig {
+            tsig.verify(&bytes).with_context(|| {
+                format!("checking the transfer of {} from {primary}", zone.origin)
+            })?;
+        }
+        if msg.header.response_code != ResponseCode::NoError {
+            bail!(
+                "{primary} refused the transfer of {}: {:?}",
+                zone.origin,
+                msg.header.response_code
+            );
+        }
+
+        for record in msg.answers {
+            if let Some(transfer) = reader.push(record)? {
+                return Ok(transfer);
+            }
+        }
+        // a lone SOA in the first message means there's nothing new
+        if reader.state == IxfrState::Second {
+            return Ok(Transfer::UpToDate);
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+enum IxfrState {
+    #[default]
+    First,
+    Second,
+    Full,
+    Deleting,
+    Adding,
+}
+
+/// Tells apart the records of an IXFR response as they come in.
+#[derive(Debug, Default)]
+struct IxfrReader {
+    state: IxfrState,
+    serial: u32,
+    records: Vec<DnsRecord>,
+    changes: Vec<Change>,
+}
+
+impl IxfrReader {
+    /// Takes the next record, returning the outcome once the last one is in.
+    fn push(&mut self, record: DnsRecord) -> anyhow::Result<Option<Transfer>> {
+        let serial = match record.dns_type {
+            DnsType::Soa if is_well_formed(DnsType::Soa, &record.rd_data) => soa_serial(&record),
+            DnsType::Soa => bail!("the transfer has a malformed SOA"),
+            _ => None,
+        };
+        let is_soa = serial.is_some();
+
+        match self.state {
+            IxfrState::First => {
+                let Some(serial) = serial else {
+                    bail!("the transfer doesn't start with an SOA");
+                };
+                self.serial = serial;
+                self.records.push(record);
+                self.state = IxfrState::Second;
+            }
+            // the primary's SOA again is a full zone made of just the SOA
+            IxfrState::Second if serial == Some(self.serial) => {
+                return Ok(Some(Transfer::Full(std::mem::take(&mut self.records))));
+            }
+            IxfrState::Second if is_soa => {
+                self.start_change(record);
+            }
+            IxfrState::Second => {
+                self.records.push(record);
+                self.state = IxfrState::Full;
+            }
+            IxfrState::Full if is_soa => {
+                return Ok(Some(Transfer::Full(std::mem::take(&mut self.records))));
+            }
+            IxfrState::Full => self.records.push(record),
+            IxfrState::Deleting if is_soa => {
+                self.changes.last_mut().unwrap().added.push(record);
+                self.state = IxfrState::Adding;
+            }
+            IxfrState::Deleting => self.changes.last_mut().unwrap().deleted.push(record),
+            IxfrState::Adding if serial == Some(self.serial) => {
+                return Ok(Some(Transfer::Incremental(std::mem::take(
+                    &mut self.changes,
+                ))));
+            }
+            IxfrState::Adding if is_soa => {
+                self.start_change(record);
+            }
+            IxfrState::Adding => self.changes.last_mut().unwrap().added.push(record),
+        }
+
+        Ok(None)
+    }
+
+    fn start_change(&mut self, soa: DnsRecord) {
+        self.changes.push(Change {
+            deleted: vec![soa],
+            added: vec![],
+        });
+        self.state = IxfrState::Deleting;
+    }
+}
+
+/// Whether `msg` is one of the responses to the transfer `query`. Only the
+/// first has to repeat the question (RFC 5936 section 2.2.1).
+fn is_response(msg: &DnsMsg, query: &DnsMsg, first: bool) -> bool {
+    if msg.questions.is_empty() && !first {
+        return msg.header.id == query.header.id && msg.header.query == QR::Response;
+    }
+
+    upstream::answers(msg, query)
+}
+
+/// A query for the zone `origin` with recursion turned off and a random id.
+pub fn transfer_query(origin: &str, dns_type: DnsType) -> DnsMsg {
+    DnsMsg {
+        header: DnsHeader {
+            id: rand::random(),
+            query: QR::Query,
+            op_code: OpCode::StandardQuery,
+            aa: false,
+            tc: false,
+            rd: false,
+            ra: false,
+            z: 0,
+            response_code: ResponseCode::NoError,
+            questions_count: 1,
+            answers_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        },
+        questions: vec![DnsRecord {
+            name: origin.to_string(),
+            dns_type,
+            dns_class: DnsClass::IN,
+            time_to_live: 0,
+            rd_length: 0,
+            rd_data: vec![],
+        }],
+        answers: vec![],
+        authority: vec![],
+        additional: vec![],
+    }
+}
+
+fn transfer_response(query: &DnsMsg, with_question: bool) -> DnsMsg {
+    let questions = if with_question {
+        query.questions.clone()
+    } else {
+        vec![]
+    };
+
+    DnsMsg {
+        header: DnsHeader {
+            query: QR::Response,
+            aa: true,
+            tc: false,
+            ra: false,
+            z: 0,
+            response_code: ResponseCode::NoError,
+            questions_count: questions.len() as u16,
+            answers_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+            ..query.header
+        },
+        questions,
+        answers: vec![],
+        authority: vec![],
+        additional: vec![],
+    }
+}
+
+#[test]
+fn test_axfr() {
+    let mut text = String::from("$TTL 3600\n@ SOA ns1 hostmaster 1 7200 1800 1209600 300\n");
+    for i in 0..100 {
+        text.push_str(&format!("host{i} A 192.0.2.{i}\n"));
+    }
+    let records = crate::zone_file::parse_zone(&text, "example.com", std::path::Path::new("."));
+    let zone = Zone::new("example.com", records.unwrap()).unwrap();
+    let mut query =
+        crate::deserialize(&[0, 9, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 252, 0, 1]).unwrap();
+    query.questions[0].name = "example.com".into();
+
+    let messages = axfr(&zone, &query, 1024);
+    assert!(messages.len() > 1);
+    assert!(messages
+        .iter()
+        .all(|msg| crate::serialize(msg).len() <= 1024 && msg.header.id == 9 && msg.header.aa));
+    assert_eq!(messages[0].questions.len(), 1);
+    assert!(messages[1].questions.is_empty());
+
+    let records: Vec<&DnsRecord> = messages.iter().flat_map(|msg| &msg.answers).collect();
+    assert_eq!(records.len(), 102);
+    assert_eq!(records[0].dns_type, DnsType::Soa);
+    assert_eq!(records[101].dns_type, DnsType::Soa);
+    assert!(records[1..101].iter().all(|r| r.dns_type == DnsType::A));
+}
+
+#[test]
+fn test_ixfr() {
+    use crate::journal::{diff, Journal};
+
+    let zone = |serial: u32, www: &str| {
+        let text = format!("$TTL 300\n@ SOA ns hostmaster {serial} 60 60 600 60\nwww A {www}\n");
+        let records = crate::zone_file::parse_zone(&text, "example.com", std::path::Path::new("."));
+        Zone::new("example.com", records.unwrap()).unwrap()
+    };
+    let (v1, v2) = (zone(1, "192.0.2.1"), zone(2, "192.0.2.2"));
+    let mut v3 = zone(3, "192.0.2.3");
+    v3.journal = Journal::default();
+    v3.journal.append(diff(&v1, &v2)).unwrap();
+    v3.journal.append(diff(&v2, &v3)).unwrap();
+
+    let mut query = transfer_query("example.com", DnsType::Ixfr);
+    let read = |messages: Vec<DnsMsg>| {
+        let mut reader = IxfrReader::default();
+        let records = messages.into_iter().flat_map(|msg| msg.answers);
+        records
+            .filter_map(|record| reader.push(record).unwrap())
+            .next()
+    };
+
+    // from serial 1: SOA 3, (SOA 1, www 1, SOA 2, www 2), (SOA 2, www 2, SOA 3, www 3), SOA 3
+    query.authority = vec![v1.soa().clone()];
+    let messages = ixfr(&v3, &query, TRANSFER_MESSAGE_SIZE);
+    assert_eq!(messages[0].answers.len(), 10);
+    let Some(Transfer::Incremental(changes)) = read(messages) else {
+        panic!("expected an incremental transfer");
+    };
+    assert_eq!(changes, v3.journal.changes);
+
+    // applying the changes brings the old version up to date
+    let mut copy = v1.clone();
+    for change in &changes {
+        copy.apply(change).unwrap();
+    }
+    assert_eq!(
+        copy.records().collect::<Vec<_>>(),
+        v3.records().collect::<Vec<_>>()
+    );
+    assert!(copy.apply(&changes[0]).is_err());
+
+    // up to date clients get a lone SOA
+    query.authority = vec![v3.soa().clone()];
+    let messages = ixfr(&v3, &query, TRANSFER_MESSAGE_SIZE);
+    assert_eq!(messages[0].answers, vec![v3.soa().clone()]);
+
+    // without the history, the whole zone
+    query.authority = vec![zone(0, "192.0.2.0").soa().clone()];
+    let Some(Transfer::Full(records)) = read(ixfr(&v3, &query, TRANSFER_MESSAGE_SIZE)) else {
+        panic!("expected a full transfer");
+    };
+    assert_eq!(records.len(), 2);
+
+    query.authority[0].rd_data.clear();
+    let messages = ixfr(&v3, &query, TRANSFER_MESSAGE_SIZE);
+    assert_eq!(messages[0].header.response_code, ResponseCode::FormatError);
+
+    // nor is one from the primary taken
+    let mut soa = v3.soa().clone();
+    soa.rd_data.truncate(4);
+    assert!(IxfrReader::default().push(soa).is_err());
+}
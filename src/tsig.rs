@@ -0,0 +1,540 @@
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+
+use crate::dns_header::deserialize_header;
+use crate::dns_header::ResponseCode;
+use crate::dns_record::{
+    deserialize_name, deserialize_record, serialize_name, serialize_record, DnsClass, DnsRecord,
+    DnsType,
+};
+
+/// How far apart the clocks of the two ends of a signed exchange may be, in seconds.
+pub const FUDGE: u16 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    /// The name identifying the algorithm in TSIG records (RFC 8945 section 6).
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256",
+            Algorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Some(Algorithm::HmacSha256),
+            "hmac-sha512" => Some(Algorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    fn mac(self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Algorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Whether `mac` is the MAC of `data`, compared in constant time so that
+    /// its timing gives nothing away.
+    fn verify(self, secret: &[u8], data: &[u8], mac: &[u8]) -> bool {
+        match self {
+            Algorithm::HmacSha256 => {
+                let mut hmac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key");
+                hmac.update(data);
+                hmac.verify_slice(mac).is_ok()
+            }
+            Algorithm::HmacSha512 => {
+                let mut hmac = Hmac::<Sha512>::new_from_slice(secret).expect("HMAC takes any key");
+                hmac.update(data);
+                hmac.verify_slice(mac).is_ok()
+            }
+        }
+    }
+}
+
+/// A shared secret, written `<algorithm>:<name>:<base64 secret>` like the
+/// `-y` option of dig and nsupdate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    /// Lowercase, without the trailing dot.
+    pub name: String,
+    pub algorithm: Algorithm,
+    pub secret: Vec<u8>,
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(3, ':');
+        let (Some(algorithm), Some(name), Some(secret)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "expected <algorithm>:<name>:<base64 secret>, got {value}"
+            ));
+        };
+        let algorithm = Algorithm::from_name(algorithm)
+            .ok_or_else(|| format!("unsupported TSIG algorithm {algorithm}"))?;
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(secret)
+            .map_err(|e| format!("invalid secret for key {name}: {e}"))?;
+
+        Ok(Key {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm,
+            secret,
+        })
+    }
+}
+
+/// The RDATA of a TSIG record (RFC 8945 section 4.2), along with its owner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tsig {
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Tsig {
+    fn from_record(record: &DnsRecord) -> Option<Tsig> {
+        let data = &record.rd_data;
+        if data.is_empty() {
+            return None;
+        }
+        let (algorithm, mut index) = deserialize_name(data, 0).ok()?;
+        let mut take = |len: usize| -> Option<&[u8]> {
+            let bytes = data.get(index..index + len)?;
+            index += len;
+            Some(bytes)
+        };
+        let number = |bytes: &[u8]| bytes.iter().fold(0u64, |n, byte| n << 8 | *byte as u64);
+
+        let time_signed = number(take(6)?);
+        let fudge = number(take(2)?) as u16;
+        let mac_size = number(take(2)?) as usize;
+        let mac = take(mac_size)?.to_vec();
+        let original_id = number(take(2)?) as u16;
+        let error = number(take(2)?) as u16;
+        let other_len = number(take(2)?) as usize;
+        let other = take(other_len)?.to_vec();
+
+        Some(Tsig {
+            key_name: record.name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    fn to_record(&self) -> DnsRecord {
+        let mut rd_data = serialize_name(&self.algorithm);
+        rd_data.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        rd_data.extend_from_slice(&self.fudge.to_be_bytes());
+        rd_data.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+        rd_data.extend_from_slice(&self.mac);
+        rd_data.extend_from_slice(&self.original_id.to_be_bytes());
+        rd_data.extend_from_slice(&self.error.to_be_bytes());
+        rd_data.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        rd_data.extend_from_slice(&self.other);
+
+        DnsRecord {
+            name: self.key_name.clone(),
+            dns_type: DnsType::Tsig,
+            dns_class: DnsClass::AnyClass,
+            time_to_live: 0,
+            rd_length: rd_data.len() as u16,
+            rd_data,
+        }
+    }
+
+    /// The TSIG fields the MAC covers (RFC 8945 section 4.3.3), only the
+    /// timers for the messages of a stream after the first.
+    fn variables(&self, timers_only: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if !timers_only {
+            bytes.extend(serialize_name(&self.key_name.to_ascii_lowercase()));
+            bytes.extend_from_slice(&u16::from(DnsClass::AnyClass).to_be_bytes());
+            bytes.extend_from_slice(&0u32.to_be_bytes());
+            bytes.extend(serialize_name(&self.algorithm.to_ascii_lowercase()));
+        }
+        bytes.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        bytes.extend_from_slice(&self.fudge.to_be_bytes());
+        if !timers_only {
+            bytes.extend_from_slice(&self.error.to_be_bytes());
+            bytes.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&self.other);
+        }
+        bytes
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// Splits a raw message into the message as it was before signing and its
+/// TSIG record, which has to be the last one. `None` when the message isn't
+/// signed, an error when it can't be read.
+fn split_signed(message: &[u8]) -> anyhow::Result<Option<(Vec<u8>, DnsRecord)>> {
+    if message.len() < 12 {
+        bail!("message shorter than its header");
+    }
+    let header = deserialize_header(message);
+    let records = header.answers_count as usize
+        + header.authority_count as usize
+        + header.additional_count as usize;
+    if header.additional_count == 0 {
+        return Ok(None);
+    }
+
+    // like `deserialize`, records are read from one byte past their start
+    let mut index = 12 + 1;
+    for _ in 0..header.questions_count {
+        index = deserialize_record(message, index, false)?.1 + 1;
+    }
+    let mut last = None;
+    for _ in 0..records {
+        let start = index - 1;
+        let (record, end) = deserialize_record(message, index, true)?;
+        last = Some((start, record));
+        index = end + 1;
+    }
+
+    let Some((start, record)) = last else {
+        return Ok(None);
+    };
+    if record.dns_type != DnsType::Tsig {
+        return Ok(None);
+    }
+    let mut unsigned = message[..start].to_vec();
+    unsigned[10..12].copy_from_slice(&(header.additional_count - 1).to_be_bytes());
+    Ok(Some((unsigned, record)))
+}
+
+/// The signing state of an exchange of messages with a key: each MAC covers
+/// the one of the message before it, binding responses to their request and
+/// the messages of a zone transfer to each other.
+#[derive(Debug, Clone)]
+pub struct TsigContext {
+    key: Key,
+    prior_mac: Option<Vec<u8>>,
+    /// Messages signed or verified so far, the request being the first.
+    messages: usize,
+}
+
+impl TsigContext {
+    pub fn new(key: Key) -> TsigContext {
+        TsigContext {
+            key,
+            prior_mac: None,
+            messages: 0,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// The MAC over `unsigned`, the next message, with the TSIG fields of `tsig`.
+    fn mac(&self, unsigned: &[u8], tsig: &Tsig) -> Vec<u8> {
+        let data = self.signed_data(unsigned, tsig);
+        self.key.algorithm.mac(&self.key.secret, &data)
+    }
+
+    /// Whether the MAC of `tsig` is the one `mac` gives for `unsigned`.
+    fn verify_mac(&self, unsigned: &[u8], tsig: &Tsig) -> bool {
+        let data = self.signed_data(unsigned, tsig);
+        self.key
+            .algorithm
+            .verify(&self.key.secret, &data, &tsig.mac)
+    }
+
+    fn signed_data(&self, unsigned: &[u8], tsig: &Tsig) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(prior_mac) = &self.prior_mac {
+            data.extend_from_slice(&(prior_mac.len() as u16).to_be_bytes());
+            data.extend_from_slice(prior_mac);
+        }
+        data.extend_from_slice(unsigned);
+        // past the request and the first response, the messages of a stream only cover the timers
+        data.extend(tsig.variables(self.messages >= 2));
+        data
+    }
+
+    /// Appends a TSIG record signing `message`, the next one of the exchange.
+    pub fn sign(&mut self, message: Vec<u8>) -> Vec<u8> {
+        self.sign_with(message, now(), 0, vec![])
+    }
+
+    fn sign_with(
+        &mut self,
+        mut message: Vec<u8>,
+        time_signed: u64,
+        error: u16,
+        other: Vec<u8>,
+    ) -> Vec<u8> {
+        let mut tsig = Tsig {
+            key_name: self.key.name.clone(),
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed,
+            fudge: FUDGE,
+            mac: vec![],
+            original_id: u16::from_be_bytes([message[0], message[1]]),
+            error,
+            other,
+        };
+        tsig.mac = self.mac(&message, &tsig);
+        self.prior_mac = Some(tsig.mac.clone());
+        self.messages += 1;
+
+        append_record(&mut message, &tsig.to_record());
+        message
+    }
+
+    /// Checks that `message`, the next one of the exchange, is signed with our key.
+    pub fn verify(&mut self, message: &[u8]) -> anyhow::Result<()> {
+        let (unsigned, record) = split_signed(message)?.context("the message isn't signed")?;
+        let tsig = Tsig::from_record(&record).context("malformed TSIG record")?;
+        if tsig.key_name != self.key.name {
+            bail!("the message is signed with key {}", tsig.key_name);
+        }
+        if tsig.error != 0 {
+            bail!("the message was rejected with TSIG error {}", tsig.error);
+        }
+        if !self.verify_mac(&with_id(unsigned, tsig.original_id), &tsig) {
+            bail!("bad TSIG signature");
+        }
+        if now().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            bail!("the TSIG signature is too old");
+        }
+
+        self.prior_mac = Some(tsig.mac);
+        self.messages += 1;
+        Ok(())
+    }
+
+    /// How many bytes signing a message adds to it.
+    pub fn overhead(&self) -> usize {
+        let tsig = Tsig {
+            key_name: self.key.name.clone(),
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed: 0,
+            fudge: FUDGE,
+            mac: self.key.algorithm.mac(&self.key.secret, &[]),
+            original_id: 0,
+            error: 0,
+            other: vec![],
+        };
+        serialize_record(&tsig.to_record(), true).len()
+    }
+}
+
+fn with_id(mut message: Vec<u8>, id: u16) -> Vec<u8> {
+    message[..2].copy_from_slice(&id.to_be_bytes());
+    message
+}
+
+fn append_record(message: &mut Vec<u8>, record: &DnsRecord) {
+    let additional_count = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional_count.to_be_bytes());
+    message.extend(serialize_record(record, true));
+}
+
+/// Why a signed request was turned down (RFC 8945 section 5.2).
+#[derive(Debug)]
+pub struct TsigError {
+    /// BADKEY, BADSIG or BADTIME, or FORMERR for a misplaced or malformed TSIG record.
+    pub error: ResponseCode,
+    tsig: Option<Tsig>,
+    /// Responses to requests signed at the wrong time are still signed.
+    context: Option<TsigContext>,
+}
+
+impl TsigError {
+    /// The response code going in the header of the response.
+    pub fn response_code(&self) -> ResponseCode {
+        match self.error {
+            ResponseCode::FormatError => ResponseCode::FormatError,
+            _ => ResponseCode::NotAuth,
+        }
+    }
+
+    /// Appends the TSIG record telling the client what was wrong to `response`.
+    pub fn sign(self, response: Vec<u8>) -> Vec<u8> {
+        let Some(tsig) = self.tsig else {
+            return response;
+        };
+        if let Some(mut context) = self.context {
+            let server_time = now().to_be_bytes()[2..].to_vec();
+            return context.sign_with(response, tsig.time_signed, self.error as u16, server_time);
+        }
+
+        let mut response = response;
+        let unsigned = Tsig {
+            time_signed: now(),
+            mac: vec![],
+            error: self.error as u16,
+            other: vec![],
+            ..tsig
+        };
+        append_record(&mut response, &unsigned.to_record());
+        response
+    }
+}
+
+/// Checks the signature of `request` against `keys`. Returns the context to
+/// sign the responses with, or `None` when the request isn't signed.
+pub fn verify_request(request: &[u8], keys: &[Key]) -> Result<Option<TsigContext>, Box<TsigError>> {
+    let format_error = || {
+        Box::new(TsigError {
+            error: ResponseCode::FormatError,
+            tsig: None,
+            context: None,
+        })
+    };
+    let Some((unsigned, record)) = split_signed(request).map_err(|_| format_error())? else {
+        let msg = crate::deserialize(request).map_err(|_| format_error())?;
+        if msg
+            .additional
+            .iter()
+            .any(|record| record.dns_type == DnsType::Tsig)
+        {
+            return Err(format_error());
+        }
+        return Ok(None);
+    };
+    let Some(tsig) = Tsig::from_record(&record) else {
+        return Err(format_error());
+    };
+
+    let algorithm = Algorithm::from_name(&tsig.algorithm);
+    let Some(key) = keys
+        .iter()
+        .find(|key| key.name == tsig.key_name && Some(key.algorithm) == algorithm)
+    else {
+        return Err(Box::new(TsigError {
+            error: ResponseCode::BadKey,
+            tsig: Some(tsig),
+            context: None,
+        }));
+    };
+
+    let mut context = TsigContext::new(key.clone());
+    if !context.verify_mac(&with_id(unsigned, tsig.original_id), &tsig) {
+        return Err(Box::new(TsigError {
+            error: ResponseCode::BadSig,
+            tsig: Some(tsig),
+            context: None,
+        }));
+    }
+    context.prior_mac = Some(tsig.mac.clone());
+    context.messages = 1;
+
+    if now().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(Box::new(TsigError {
+            error: ResponseCode::BadTime,
+            tsig: Some(tsig),
+            context: Some(context),
+        }));
+    }
+
+    Ok(Some(context))
+}
+
+#[test]
+fn test_tsig() {
+    use crate::{deserialize, serialize};
+
+    let key: Key = "hmac-sha256:transfer.example.:c2VjcmV0IHNlY3JldA=="
+        .parse()
+        .unwrap();
+    assert_eq!(key.name, "transfer.example");
+    assert_eq!(key.secret, b"secret secret");
+    let other: Key = "hmac-sha512:other:c2VjcmV0".parse().unwrap();
+
+    let query = crate::transfer::transfer_query("example.com", DnsType::Axfr);
+    let mut client = TsigContext::new(key.clone());
+    let request = client.sign(serialize(&query));
+    assert_eq!(request.len(), serialize(&query).len() + client.overhead());
+    assert_eq!(deserialize(&request).unwrap().additional.len(), 1);
+
+    // the server finds the key and signs a stream of responses the client can check
+    let mut server = verify_request(&request, &[other.clone(), key.clone()])
+        .unwrap()
+        .unwrap();
+    assert!(
+        verify_request(&serialize(&query), std::slice::from_ref(&key))
+            .unwrap()
+            .is_none()
+    );
+    let mut response = query.clone();
+    response.header.query = crate::dns_header::QR::Response;
+    for _ in 0..3 {
+        client.verify(&server.sign(serialize(&response))).unwrap();
+    }
+
+    // a message out of order breaks the chain
+    let first = server.sign(serialize(&response));
+    let second = server.sign(serialize(&response));
+    assert!(client.clone().verify(&second).is_err());
+    client.verify(&first).unwrap();
+
+    // unknown keys and tampered messages
+    let error = verify_request(&request, &[other]).unwrap_err();
+    assert_eq!(error.error, ResponseCode::BadKey);
+    let mut tampered = request.clone();
+    // the recursion desired flag
+    tampered[2] ^= 1;
+    let error = verify_request(&tampered, std::slice::from_ref(&key)).unwrap_err();
+    assert_eq!(error.error, ResponseCode::BadSig);
+    assert!(TsigContext::new(key.clone())
+        .verify(&error.sign(serialize(&response)))
+        .is_err());
+    // a signed request cut short can't be read at all
+    let error = verify_request(&request[..request.len() - 1], std::slice::from_ref(&key));
+    assert_eq!(error.unwrap_err().error, ResponseCode::FormatError);
+
+    // requests signed too long ago get a signed BADTIME telling the server's time
+    let mut stale = TsigContext::new(key.clone());
+    let request = stale.sign_with(serialize(&query), now() - 3600, 0, vec![]);
+    let error = verify_request(&request, &[key]).unwrap_err();
+    assert_eq!(error.error, ResponseCode::BadTime);
+    assert_eq!(error.response_code(), ResponseCode::NotAuth);
+    let signed = error.sign(serialize(&response));
+    let record = deserialize(&signed).unwrap().additional.pop().unwrap();
+    let tsig = Tsig::from_record(&record).unwrap();
+    assert_eq!(tsig.error, ResponseCode::BadTime as u16);
+    assert_eq!(tsig.other.len(), 6);
+}
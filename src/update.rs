@@ -0,0 +1,472 @@
+use std::cmp::Ordering;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use tracing::{error, warn};
+
+use crate::acl::{parse_grantees, Grantee};
+use crate::dns_header::ResponseCode;
+use crate::dns_record::{is_well_formed, DnsClass, DnsRecord, DnsType};
+use crate::journal::diff;
+use crate::secondary::serial_cmp;
+use crate::zone::{is_subdomain, set_soa_serial, soa_serial, Zone};
+use crate::DnsMsg;
+
+/// Lets `grantees` change the records at or below `name`, or anywhere in the
+/// zone when there's no `name`. Written as `<grantee>[,<grantee>...][@<name>]`
+/// where grantees are address prefixes or `key:<name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateRule {
+    pub grantees: Vec<Grantee>,
+    pub name: Option<String>,
+}
+
+impl UpdateRule {
+    pub fn allows(&self, client: IpAddr, key: Option<&str>, name: &str) -> bool {
+        let in_scope = self.name.as_ref().map_or(true, |scope| {
+            is_subdomain(&name.to_ascii_lowercase(), scope)
+        });
+        in_scope
+            && self
+                .grantees
+                .iter()
+                .any(|grantee| grantee.allows(client, key))
+    }
+}
+
+impl FromStr for UpdateRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (grantees, name) = match value.rsplit_once('@') {
+            Some((grantees, name)) => (
+                grantees,
+                Some(name.trim_end_matches('.').to_ascii_lowercase()),
+            ),
+            None => (value, None),
+        };
+
+        Ok(UpdateRule {
+            grantees: parse_grantees(grantees)?,
+            name,
+        })
+    }
+}
+
+/// Applies the dynamic update `request` from `client`, signed with the key
+/// named `key` if any, to `zone` (RFC 2136 section 3): either every change is
+/// made or none is. Returns the next version
+/// of the zone with its serial bumped and the change journaled, or `None` when
+/// the update changed nothing.
+pub fn apply_update(
+    zone: &Zone,
+    request: &DnsMsg,
+    client: IpAddr,
+    key: Option<&str>,
+) -> Result<Option<Zone>, ResponseCode> {
+    // the prerequisite section is in the answers and the update section in the authority
+    let (prerequisites, updates) = (&request.answers, &request.authority);
+
+    for record in prerequisites.iter().chain(updates) {
+        if !zone.contains(&record.name) {
+            return Err(ResponseCode::NotZone);
+        }
+    }
+    for update in updates {
+        prescan(update)?;
+    }
+
+    let rules = &zone.options.allow_update;
+    let allowed = |name: &str| rules.iter().any(|rule| rule.allows(client, key, name));
+    if rules.is_empty() || !updates.iter().all(|update| allowed(&update.name)) {
+        warn!(
+            "Refusing update of {} from {client} with key {key:?}",
+            zone.origin
+        );
+        return Err(ResponseCode::Refused);
+    }
+
+    let mut records: Vec<DnsRecord> = zone.records().cloned().collect();
+    check_prerequisites(&records, prerequisites)?;
+
+    let old_serial = zone.serial();
+    let mut changed = false;
+    for update in updates {
+        changed |= apply(&mut records, update, &zone.origin);
+    }
+    if !changed {
+        return Ok(None);
+    }
+
+    // the SOA is always first, and its serial may have been set by the update itself
+    if soa_serial(&records[0]) == Some(old_serial) {
+        set_soa_serial(&mut records[0], old_serial.wrapping_add(1));
+    }
+
+    let mut updated = Zone::new(&zone.origin, records).map_err(|e| {
+        warn!("Update of {} left an invalid zone: {e:#}", zone.origin);
+        ResponseCode::ServerFailure
+    })?;
+    updated.options = zone.options.clone();
+    updated.path = zone.path.clone();
+    updated.journal = zone.journal.clone();
+    updated.journal.append(diff(zone, &updated)).map_err(|e| {
+        error!("Failed to journal update of {}: {e:#}", zone.origin);
+        ResponseCode::ServerFailure
+    })?;
+
+    Ok(Some(updated))
+}
+
+/// Whether `dns_type` only makes sense in questions.
+fn is_meta_type(dns_type: DnsType) -> bool {
+    matches!(
+        dns_type,
+        DnsType::Ixfr | DnsType::Axfr | DnsType::Mailb | DnsType::Maila | DnsType::AllRecords
+    )
+}
+
+/// Checks the form of an update record (RFC 2136 section 3.4.1).
+fn prescan(update: &DnsRecord) -> Result<(), ResponseCode> {
+    let well_formed = match update.dns_class {
+        DnsClass::IN => {
+            !is_meta_type(update.dns_type) && is_well_formed(update.dns_type, &update.rd_data)
+        }
+        DnsClass::AnyClass => {
+            update.time_to_live == 0
+                && update.rd_data.is_empty()
+                && (update.dns_type == DnsType::AllRecords || !is_meta_type(update.dns_type))
+        }
+        DnsClass::NoneClass => update.time_to_live == 0 && !is_meta_type(update.dns_type),
+        _ => false,
+    };
+
+    if well_formed {
+        Ok(())
+    } else {
+        Err(ResponseCode::FormatError)
+    }
+}
+
+/// Whether two records hold the same data, whatever their TTLs and classes.
+fn same_data(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.name.eq_ignore_ascii_case(&b.name) && a.dns_type == b.dns_type && a.rd_data == b.rd_data
+}
+
+fn at_name<'a>(records: &'a [DnsRecord], name: &'a str) -> impl Iterator<Item = &'a DnsRecord> {
+    records
+        .iter()
+        .filter(move |record| record.name.eq_ignore_ascii_case(name))
+}
+
+/// Checks the prerequisite section against the zone's `records` (RFC 2136 section 3.2).
+fn check_prerequisites(
+    records: &[DnsRecord],
+    prerequisites: &[DnsRecord],
+) -> Result<(), ResponseCode> {
+    for prerequisite in prerequisites {
+        if prerequisite.time_to_live != 0 {
+            return Err(ResponseCode::FormatError);
+        }
+        let name = &prerequisite.name;
+        let in_use = at_name(records, name).next().is_some();
+
+        match (prerequisite.dns_class, prerequisite.dns_type) {
+            (DnsClass::AnyClass | DnsClass::NoneClass, _) if !prerequisite.rd_data.is_empty() => {
+                return Err(ResponseCode::FormatError)
+            }
+            (DnsClass::AnyClass, DnsType::AllRecords) if !in_use => {
+                return Err(ResponseCode::NameError)
+            }
+            (DnsClass::AnyClass, DnsType::AllRecords) => {}
+            (DnsClass::AnyClass, dns_type) if rrset(records, name, dns_type).is_empty() => {
+                return Err(ResponseCode::NXRRSet)
+            }
+            (DnsClass::AnyClass, _) => {}
+            (DnsClass::NoneClass, DnsType::AllRecords) if in_use => {
+                return Err(ResponseCode::YXDomain)
+            }
+            (DnsClass::NoneClass, DnsType::AllRecords) => {}
+            (DnsClass::NoneClass, dns_type) if !rrset(records, name, dns_type).is_empty() => {
+                return Err(ResponseCode::YXRRSet)
+            }
+            (DnsClass::NoneClass, _) => {}
+            (DnsClass::IN, dns_type) => {
+                // the RRset must be exactly the prerequisites with its name and type
+                let expected: Vec<&DnsRecord> = prerequisites
+                    .iter()
+                    .filter(|other| {
+                        other.dns_class == DnsClass::IN && same_rrset(other, prerequisite)
+                    })
+                    .collect();
+                let actual = rrset(records, name, dns_type);
+                let covers = |these: &[&DnsRecord], those: &[&DnsRecord]| {
+                    these
+                        .iter()
+                        .all(|this| those.iter().any(|that| same_data(this, that)))
+                };
+                if !covers(&expected, &actual) || !covers(&actual, &expected) {
+                    return Err(ResponseCode::NXRRSet);
+                }
+            }
+            _ => return Err(ResponseCode::FormatError),
+        }
+    }
+
+    Ok(())
+}
+
+fn rrset<'a>(records: &'a [DnsRecord], name: &str, dns_type: DnsType) -> Vec<&'a DnsRecord> {
+    records
+        .iter()
+        .filter(|record| record.name.eq_ignore_ascii_case(name) && record.dns_type == dns_type)
+        .collect()
+}
+
+fn same_rrset(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.name.eq_ignore_ascii_case(&b.name) && a.dns_type == b.dns_type
+}
+
+/// Applies a single prescanned update to `records` (RFC 2136 section 3.4.2).
+/// Returns whether anything changed.
+fn apply(records: &mut Vec<DnsRecord>, update: &DnsRecord, origin: &str) -> bool {
+    let at_apex = update.name.eq_ignore_ascii_case(origin);
+    let before = records.len();
+
+    match update.dns_class {
+        DnsClass::IN => return add(records, update, at_apex),
+        DnsClass::AnyClass if update.dns_type == DnsType::AllRecords => {
+            // the apex keeps its SOA and NS records
+            records.retain(|record| {
+                !record.name.eq_ignore_ascii_case(&update.name)
+                    || (at_apex && matches!(record.dns_type, DnsType::Soa | DnsType::NS))
+            });
+        }
+        DnsClass::AnyClass => {
+            if at_apex && matches!(update.dns_type, DnsType::Soa | DnsType::NS) {
+                return false;
+            }
+            records.retain(|record| !same_rrset(record, update));
+        }
+        DnsClass::NoneClass => {
+            if update.dns_type == DnsType::Soa {
+                return false;
+            }
+            let name_servers = at_name(records, &update.name)
+                .filter(|record| record.dns_type == DnsType::NS)
+                .count();
+            if at_apex && update.dns_type == DnsType::NS && name_servers <= 1 {
+                return false;
+            }
+            records.retain(|record| !same_data(record, update));
+        }
+        _ => unreachable!("prescan only lets IN, ANY and NONE through"),
+    }
+
+    records.len() != before
+}
+
+fn add(records: &mut Vec<DnsRecord>, update: &DnsRecord, at_apex: bool) -> bool {
+    // a name with a CNAME can't have other data, nor a name with other data a CNAME
+    let is_cname = update.dns_type == DnsType::Cname;
+    let conflicts = at_name(records, &update.name)
+        .any(|record| (record.dns_type == DnsType::Cname) != is_cname);
+    if conflicts {
+        return false;
+    }
+
+    match update.dns_type {
+        DnsType::Soa => {
+            let newer = match (soa_serial(update), soa_serial(&records[0])) {
+                (Some(new), Some(old)) => {
+                    at_apex && serial_cmp(new, old) == Some(Ordering::Greater)
+                }
+                _ => false,
+            };
+            if newer {
+                records[0] = update.clone();
+            }
+            newer
+        }
+        DnsType::Cname => {
+            records.retain(|record| !same_rrset(record, update));
+            records.push(update.clone());
+            true
+        }
+        _ => match records.iter_mut().find(|record| same_data(record, update)) {
+            Some(record) if record.time_to_live == update.time_to_live => false,
+            Some(record) => {
+                record.time_to_live = update.time_to_live;
+                true
+            }
+            None => {
+                records.push(update.clone());
+                true
+            }
+        },
+    }
+}
+
+#[test]
+fn test_apply_update() {
+    use crate::zone_file::parse_zone;
+
+    let text = "$TTL 3600
+@       SOA ns1 hostmaster 1 7200 1800 1209600 300
+        NS  ns1
+ns1     A   192.0.2.1
+www     A   192.0.2.10
+alias   CNAME www
+";
+    let records = parse_zone(text, "example.com", std::path::Path::new(".")).unwrap();
+    let mut zone = Zone::new("example.com", records).unwrap();
+    zone.options.allow_update = vec!["192.0.2.0/24@dyn.example.com".parse().unwrap()];
+
+    let client: IpAddr = "192.0.2.53".parse().unwrap();
+    let record = |line: &str| {
+        let text = format!("$TTL 0\n{line}\n");
+        parse_zone(&text, "example.com", std::path::Path::new("."))
+            .unwrap()
+            .remove(0)
+    };
+    let with_class = |mut record: DnsRecord, dns_class| {
+        record.dns_class = dns_class;
+        record.rd_data.clear();
+        record
+    };
+    let request = |prerequisites: Vec<DnsRecord>, updates: Vec<DnsRecord>| {
+        let mut request =
+            crate::deserialize(&[0, 9, 40, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 6, 0, 1]).unwrap();
+        request.questions[0].name = "example.com".into();
+        request.answers = prerequisites;
+        request.authority = updates;
+        request
+    };
+
+    // adding a name where the rule allows it bumps the serial
+    let add = request(vec![], vec![record("host.dyn 300 A 192.0.2.99")]);
+    let updated = apply_update(&zone, &add, client, None).unwrap().unwrap();
+    assert_eq!(updated.serial(), 2);
+    assert_eq!(
+        updated
+            .lookup("host.dyn.example.com", DnsType::A)
+            .answers
+            .len(),
+        1
+    );
+    assert_eq!(updated.journal.changes_since(1).unwrap().len(), 1);
+
+    // the same add again changes nothing
+    assert!(matches!(
+        apply_update(&updated, &add, client, None),
+        Ok(None)
+    ));
+
+    // names outside the rule or clients outside the prefix are refused
+    let outside = request(vec![], vec![record("www 300 A 192.0.2.11")]);
+    assert_eq!(
+        apply_update(&zone, &outside, client, None).err(),
+        Some(ResponseCode::Refused)
+    );
+    let stranger: IpAddr = "198.51.100.1".parse().unwrap();
+    assert_eq!(
+        apply_update(&zone, &add, stranger, None).err(),
+        Some(ResponseCode::Refused)
+    );
+
+    // or by whoever holds a granted key
+    zone.options.allow_update = vec!["key:dhcp@dyn.example.com".parse().unwrap()];
+    assert!(apply_update(&zone, &add, stranger, Some("dhcp")).is_ok());
+    assert_eq!(
+        apply_update(&zone, &add, client, None).err(),
+        Some(ResponseCode::Refused)
+    );
+
+    // names outside the zone
+    let elsewhere = request(vec![], vec![record("host.example.org. 300 A 192.0.2.99")]);
+    assert_eq!(
+        apply_update(&zone, &elsewhere, client, None).err(),
+        Some(ResponseCode::NotZone)
+    );
+
+    zone.options.allow_update = vec!["192.0.2.0/24".parse().unwrap()];
+
+    // prerequisites
+    let name_in_use = with_class(record("www A 192.0.2.10"), DnsClass::AnyClass);
+    let mut name_in_use_any = name_in_use.clone();
+    name_in_use_any.dns_type = DnsType::AllRecords;
+    let missing = with_class(record("nope A 192.0.2.10"), DnsClass::AnyClass);
+    let not_in_use = with_class(record("www A 192.0.2.10"), DnsClass::NoneClass);
+    let mut not_in_use_any = not_in_use.clone();
+    not_in_use_any.dns_type = DnsType::AllRecords;
+    let exact = record("www A 192.0.2.10");
+    let inexact = record("www A 192.0.2.11");
+    let delete_www = with_class(record("www A 192.0.2.10"), DnsClass::AnyClass);
+
+    let check = |prerequisite: DnsRecord| {
+        apply_update(
+            &zone,
+            &request(vec![prerequisite], vec![delete_www.clone()]),
+            client,
+            None,
+        )
+        .map(|zone| zone.is_some())
+    };
+    assert_eq!(check(name_in_use), Ok(true));
+    assert_eq!(check(name_in_use_any), Ok(true));
+    assert_eq!(check(missing), Err(ResponseCode::NXRRSet));
+    assert_eq!(check(not_in_use), Err(ResponseCode::YXRRSet));
+    assert_eq!(check(not_in_use_any), Err(ResponseCode::YXDomain));
+    assert_eq!(check(exact), Ok(true));
+    assert_eq!(check(inexact), Err(ResponseCode::NXRRSet));
+
+    // the apex keeps its SOA and last NS
+    let mut delete_apex = with_class(record("@ A 192.0.2.1"), DnsClass::AnyClass);
+    delete_apex.dns_type = DnsType::AllRecords;
+    let delete_ns = with_class(record("@ NS ns1"), DnsClass::NoneClass);
+    let updated = apply_update(
+        &zone,
+        &request(vec![], vec![delete_apex, delete_ns]),
+        client,
+        None,
+    );
+    assert!(matches!(updated, Ok(None)));
+
+    // a CNAME can't be added next to other data, nor other data next to a CNAME
+    let update = request(
+        vec![],
+        vec![
+            record("www 300 CNAME alias"),
+            record("alias 300 A 192.0.2.12"),
+        ],
+    );
+    assert!(matches!(
+        apply_update(&zone, &update, client, None),
+        Ok(None)
+    ));
+    let update = request(vec![], vec![record("alias 300 CNAME ns1")]);
+    let updated = apply_update(&zone, &update, client, None).unwrap().unwrap();
+    let alias = updated.lookup("alias.example.com", DnsType::Cname).answers;
+    assert_eq!(alias.len(), 1);
+    assert_eq!(alias[0].rd_data, record("x CNAME ns1").rd_data);
+
+    // an SOA with a newer serial replaces the old one and isn't bumped again
+    let update = request(
+        vec![],
+        vec![record("@ 3600 SOA ns1 hostmaster 10 7200 1800 1209600 300")],
+    );
+    let updated = apply_update(&zone, &update, client, None).unwrap().unwrap();
+    assert_eq!(updated.serial(), 10);
+
+    // added records have to be well-formed for their type
+    let mut soa = record("@ 3600 SOA ns1 hostmaster 11 7200 1800 1209600 300");
+    soa.rd_data.clear();
+    let mut address = record("host.dyn 300 A 192.0.2.99");
+    address.rd_data.push(0);
+    for update in [soa, address] {
+        assert_eq!(
+            apply_update(&zone, &request(vec![], vec![update]), client, None).err(),
+            Some(ResponseCode::FormatError)
+        );
+    }
+}
@@ -0,0 +1,560 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::dns_header::{DnsHeader, OpCode, ResponseCode, QR};
+use crate::dns_record::{deserialize_name, DnsClass, DnsRecord, DnsType};
+use crate::dnstap;
+use crate::metrics::METRICS;
+use crate::server::{read_message, write_message, Transport, TCP_MAX_SIZE};
+use crate::tsig::{Key, TsigContext};
+use crate::upstream_tls::{HttpsUpstream, QuicUpstream, TlsClients, TlsUpstream};
+use crate::zone::is_subdomain;
+use crate::{deserialize, serialize, DnsMsg};
+
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+thread_local! {
+    /// The upstream the thread last forwarded to, for the query log.
+    static LAST_UPSTREAM: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The upstream the thread last forwarded to since it was last asked.
+pub fn take_last_upstream() -> Option<String> {
+    LAST_UPSTREAM.with(|last| last.borrow_mut().take())
+}
+
+/// Where an upstream is and how it's spoken to: `<ip>[:<port>]` for plain
+/// UDP, `tls://<host>[:<port>][#<server name>]` for DNS over TLS,
+/// `https://<host>[:<port>]/<path>` for DNS over HTTPS and
+/// `quic://<host>[:<port>][#<server name>]` for DNS over QUIC. Host names are
+/// resolved once, by the system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Udp(SocketAddr),
+    Tls {
+        address: SocketAddr,
+        server_name: String,
+    },
+    Https {
+        address: SocketAddr,
+        host: String,
+        uri: String,
+    },
+    Quic {
+        address: SocketAddr,
+        server_name: String,
+    },
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let tls = value.strip_prefix("tls://");
+        let quic = value.strip_prefix("quic://");
+        if let Some(rest) = tls.or(quic) {
+            let (authority, server_name) = match rest.split_once('#') {
+                Some((authority, server_name)) => (authority, Some(server_name)),
+                None => (rest, None),
+            };
+            // RFC 9250 took UDP port 853 for DNS over QUIC
+            let (host, address) = resolve(authority, 853)?;
+            let server_name = server_name.unwrap_or(&host).to_string();
+            Ok(match tls {
+                Some(_) => Endpoint::Tls {
+                    address,
+                    server_name,
+                },
+                None => Endpoint::Quic {
+                    address,
+                    server_name,
+                },
+            })
+        } else if let Some(rest) = value.strip_prefix("https://") {
+            let authority = rest.split('/').next().unwrap_or_default();
+            let (host, address) = resolve(authority, 443)?;
+            let uri = match rest.len() == authority.len() {
+                true => format!("{value}/dns-query"),
+                false => value.to_string(),
+            };
+            Ok(Endpoint::Https { address, host, uri })
+        } else {
+            let (_, address) = resolve(value.strip_prefix("udp://").unwrap_or(value), 53)?;
+            Ok(Endpoint::Udp(address))
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Udp(address) => write!(f, "{address}"),
+            Endpoint::Tls {
+                address,
+                server_name,
+            } => write!(f, "tls://{address}#{server_name}"),
+            Endpoint::Https { address, uri, .. } => write!(f, "{uri} at {address}"),
+            Endpoint::Quic {
+                address,
+                server_name,
+            } => write!(f, "quic://{address}#{server_name}"),
+        }
+    }
+}
+
+/// The host of `authority`, `<host>[:<port>]` with IPv6 addresses in
+/// brackets, and its address.
+fn resolve(authority: &str, default_port: u16) -> Result<(String, SocketAddr), String> {
+    if let Ok(address) = authority.parse::<SocketAddr>() {
+        return Ok((address.ip().to_string(), address));
+    }
+    let unbracketed = authority.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return Ok((ip.to_string(), SocketAddr::new(ip, default_port)));
+    }
+
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("invalid port in {authority}"))?,
+        ),
+        None => (authority, default_port),
+    };
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("resolving {host}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("{host} has no address"))?;
+
+    Ok((host.to_string(), address))
+}
+
+/// An upstream ready to take queries.
+#[derive(Debug)]
+pub enum Upstream {
+    Udp(SocketAddr),
+    Tls(TlsUpstream),
+    Https(Arc<HttpsUpstream>),
+    Quic(Arc<QuicUpstream>),
+}
+
+impl Upstream {
+    pub fn new(endpoint: &Endpoint, clients: &TlsClients) -> anyhow::Result<Upstream> {
+        Ok(match endpoint {
+            Endpoint::Udp(address) => Upstream::Udp(*address),
+            Endpoint::Tls {
+                address,
+                server_name,
+            } => Upstream::Tls(TlsUpstream::new(*address, server_name, clients)?),
+            Endpoint::Https { address, host, uri } => {
+                Upstream::Https(Arc::new(HttpsUpstream::new(*address, host, uri, clients)?))
+            }
+            Endpoint::Quic {
+                address,
+                server_name,
+            } => Upstream::Quic(Arc::new(QuicUpstream::new(*address, server_name, clients)?)),
+        })
+    }
+
+    /// The raw response to `request`. A UDP upstream's truncated one is asked
+    /// for again over TCP unless it's for a client over UDP, the other
+    /// upstreams always sending it whole for the server to cut down.
+    fn forward(
+        &self,
+        request: &[u8],
+        timeout: Duration,
+        transport: Transport,
+    ) -> io::Result<Vec<u8>> {
+        match self {
+            Upstream::Udp(address) => {
+                let response = relay(*address, request, timeout)?;
+                // the TC flag, low in the third byte
+                if response[2] & 0b10 != 0 && transport != Transport::Udp {
+                    return relay_tcp(*address, request, timeout);
+                }
+                Ok(response)
+            }
+            Upstream::Tls(upstream) => upstream.forward(request, timeout),
+            Upstream::Https(upstream) => upstream.forward(request, timeout),
+            Upstream::Quic(upstream) => upstream.forward(request, timeout),
+        }
+    }
+}
+
+/// The upstreams queries for the names under `suffix` are forwarded to, tried
+/// in order until one answers within `timeout`.
+#[derive(Debug)]
+pub struct Forwarder {
+    /// Lowercase without a trailing dot, the root being empty.
+    pub suffix: String,
+    pub servers: Vec<(Endpoint, Upstream)>,
+    pub timeout: Duration,
+}
+
+impl Forwarder {
+    /// A forwarder for `suffix`, given as `corp.example`, `*.corp.example` or
+    /// `.` for every name.
+    pub fn new(suffix: &str, servers: Vec<(Endpoint, Upstream)>) -> Forwarder {
+        let suffix = suffix.strip_prefix("*.").unwrap_or(suffix);
+        Forwarder {
+            suffix: suffix.trim_end_matches('.').to_ascii_lowercase(),
+            servers,
+            timeout: UPSTREAM_TIMEOUT,
+        }
+    }
+
+    /// Relays the raw `request` of a client over `transport` to the first
+    /// server that answers and returns its raw response, leaving records we
+    /// can't parse untouched.
+    pub fn forward(&self, request: &[u8], transport: Transport) -> io::Result<Vec<u8>> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "no upstream servers");
+        for (endpoint, upstream) in &self.servers {
+            let started = Instant::now();
+            let query_time = SystemTime::now();
+            let response = upstream.forward(request, self.timeout, transport);
+            let answer = response.as_deref().ok();
+            dnstap::log_forwarder(endpoint, request, answer, query_time);
+            let name = endpoint.to_string();
+            METRICS.observe_upstream(&name, started.elapsed(), response.is_err());
+            LAST_UPSTREAM.with(|last| *last.borrow_mut() = Some(name));
+            match response {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    warn!("Upstream {endpoint} failed: {e}");
+                    error = e;
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    pub fn exchange(&self, request: &DnsMsg, transport: Transport) -> io::Result<DnsMsg> {
+        let response = self.forward(&serialize(request), transport)?;
+        let response =
+            deserialize(&response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if !answers(&response, request) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the response doesn't match the query",
+            ));
+        }
+
+        Ok(response)
+    }
+}
+
+impl fmt::Display for Forwarder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let suffix = if self.suffix.is_empty() {
+            "."
+        } else {
+            &self.suffix
+        };
+        let servers: Vec<String> = self.servers.iter().map(|(e, _)| e.to_string()).collect();
+        write!(f, "{suffix} via {}", servers.join(", "))
+    }
+}
+
+/// The forwarders by suffix, the longest suffix of a name picking its
+/// forwarder.
+#[derive(Debug, Default)]
+pub struct Forwarders(Vec<Forwarder>);
+
+impl Forwarders {
+    pub fn new(forwarders: Vec<Forwarder>) -> Forwarders {
+        Forwarders(forwarders)
+    }
+
+    pub fn select(&self, name: &str) -> Option<&Forwarder> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.0
+            .iter()
+            .filter(|forwarder| is_subdomain(&name, &forwarder.suffix))
+            .max_by_key(|forwarder| forwarder.suffix.len())
+    }
+}
+
+/// Asks the forwarder for `name` about `name`/`dns_type` with recursion desired.
+/// Every exchange uses a socket of its own so answers can't be mixed up with
+/// client traffic.
+pub fn query(forwarders: &Forwarders, name: &str, dns_type: DnsType) -> io::Result<DnsMsg> {
+    let forwarder = forwarders.select(name).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no forwarder for {name}"))
+    })?;
+    let request = DnsMsg {
+        header: DnsHeader {
+            // unpredictable, so that nobody can spoof the answer
+            id: rand::random(),
+            query: QR::Query,
+            op_code: OpCode::StandardQuery,
+            aa: false,
+            tc: false,
+            rd: true,
+            ra: false,
+            z: 0,
+            response_code: ResponseCode::NoError,
+            questions_count: 1,
+            answers_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        },
+        questions: vec![DnsRecord {
+            name: name.to_string(),
+            dns_type,
+            dns_class: DnsClass::IN,
+            time_to_live: 0,
+            rd_length: 0,
+            rd_data: vec![],
+        }],
+        answers: vec![],
+        authority: vec![],
+        additional: vec![],
+    };
+
+    // our own lookups make do with what fits a datagram
+    forwarder.exchange(&request, Transport::Udp)
+}
+
+/// Sends `request` to `server` and waits for the response with the same id.
+fn exchange(server: SocketAddr, request: &DnsMsg, timeout: Duration) -> io::Result<DnsMsg> {
+    let socket = connect(server, timeout)?;
+    socket.send(&serialize(request))?;
+
+    let mut buf = [0; 512];
+    loop {
+        let size = socket.recv(&mut buf)?;
+        // anything we can't read isn't the response we're waiting for
+        let Ok(response) = deserialize(&buf[..size]) else {
+            continue;
+        };
+        if answers(&response, request) {
+            return Ok(response);
+        }
+    }
+}
+
+/// Like `exchange`, signing the request with `key` if there's one and then
+/// only accepting a response signed with it too.
+pub fn exchange_signed(
+    server: SocketAddr,
+    request: &DnsMsg,
+    key: Option<&Key>,
+) -> anyhow::Result<DnsMsg> {
+    let Some(key) = key else {
+        return Ok(exchange(server, request, UPSTREAM_TIMEOUT)?);
+    };
+
+    let mut tsig = TsigContext::new(key.clone());
+    let socket = connect(server, UPSTREAM_TIMEOUT)?;
+    socket.send(&tsig.sign(serialize(request)))?;
+
+    let mut buf = [0; 512];
+    loop {
+        let size = socket.recv(&mut buf)?;
+        let Ok(response) = deserialize(&buf[..size]) else {
+            continue;
+        };
+        if answers(&response, request) {
+            tsig.verify(&buf[..size])
+                .with_context(|| format!("checking the response of {server}"))?;
+            return Ok(response);
+        }
+    }
+}
+
+/// Whether `response` is the response to `request`: the same id and question.
+pub fn answers(response: &DnsMsg, request: &DnsMsg) -> bool {
+    let same_question = |a: &DnsRecord, b: &DnsRecord| {
+        let name = |record: &DnsRecord| record.name.trim_end_matches('.').to_ascii_lowercase();
+        name(a) == name(b) && a.dns_type == b.dns_type && a.dns_class == b.dns_class
+    };
+
+    response.header.query == QR::Response
+        && response.header.id == request.header.id
+        && response.questions.len() == request.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(&request.questions)
+            .all(|(a, b)| same_question(a, b))
+}
+
+fn relay(server: SocketAddr, request: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+    let socket = connect(server, timeout)?;
+    socket.send(request)?;
+
+    let mut buf = vec![0; TCP_MAX_SIZE];
+    loop {
+        let size = socket.recv(&mut buf)?;
+        // the id is the first two bytes and the QR flag the high bit of the third
+        if size >= 12 && buf[..2] == request[..2] && buf[2] & 0b10000000 != 0 {
+            return Ok(buf[..size].to_vec());
+        }
+    }
+}
+
+/// Like `relay`, over TCP for the responses too big for a datagram.
+fn relay_tcp(server: SocketAddr, request: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    write_message(&mut stream, request)?;
+
+    match read_message(&mut stream)? {
+        Some(response) if response.len() >= 12 && response[..2] == request[..2] => Ok(response),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{server} sent no response over TCP"),
+        )),
+    }
+}
+
+fn connect(server: SocketAddr, timeout: Duration) -> io::Result<UdpSocket> {
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+
+    Ok(socket)
+}
+
+/// Completes a CNAME chain whose last target we couldn't answer locally by asking
+/// its forwarder for the rest of it. Records already in the chain are never added twice.
+pub fn complete_chain(response: &mut DnsMsg, forwarders: &Forwarders) {
+    let Some(question) = response.questions.first() else {
+        return;
+    };
+    let Some(last) = response.answers.last() else {
+        return;
+    };
+    if response.header.response_code != ResponseCode::NoError
+        || last.dns_type != DnsType::Cname
+        || matches!(question.dns_type, DnsType::Cname | DnsType::AllRecords)
+    {
+        return;
+    }
+
+    let Ok((target, _)) = deserialize_name(&last.rd_data, 0) else {
+        return;
+    };
+    if forwarders.select(&target).is_none() {
+        return;
+    }
+    match query(forwarders, &target, question.dns_type) {
+        Ok(upstream) => {
+            for record in upstream.answers {
+                if !response.answers.contains(&record) {
+                    response.answers.push(record);
+                }
+            }
+            response.header.response_code = upstream.header.response_code;
+            response.header.ra = upstream.header.ra;
+            response.header.answers_count = response.answers.len() as u16;
+        }
+        Err(e) => warn!("Couldn't resolve {target}: {e}"),
+    }
+}
+
+#[test]
+fn test_forwarders() {
+    let server = |address: &str| {
+        let address = address.parse().unwrap();
+        (Endpoint::Udp(address), Upstream::Udp(address))
+    };
+    let forwarders = Forwarders::new(vec![
+        Forwarder::new(".", vec![server("192.0.2.1:53")]),
+        Forwarder::new("corp.example", vec![server("10.0.0.53:53")]),
+        Forwarder::new("*.consul.", vec![server("127.0.0.1:8600")]),
+        Forwarder::new("lab.corp.example", vec![server("10.0.1.53:53")]),
+    ]);
+    let suffix = |name: &str| {
+        forwarders
+            .select(name)
+            .map(|forwarder| forwarder.suffix.clone())
+    };
+
+    assert_eq!(suffix("www.example.com"), Some("".into()));
+    assert_eq!(suffix("corp.example"), Some("corp.example".into()));
+    assert_eq!(suffix("Host.CORP.example."), Some("corp.example".into()));
+    assert_eq!(
+        suffix("host.lab.corp.example"),
+        Some("lab.corp.example".into())
+    );
+    assert_eq!(suffix("web.service.consul"), Some("consul".into()));
+    assert_eq!(suffix("notcorp.example"), Some("".into()));
+    assert!(Forwarders::default().select("example.com").is_none());
+
+    let endpoint = |value: &str| value.parse::<Endpoint>().unwrap();
+    let address = |value: &str| value.parse::<SocketAddr>().unwrap();
+    assert_eq!(
+        endpoint("192.0.2.1"),
+        Endpoint::Udp(address("192.0.2.1:53"))
+    );
+    assert_eq!(
+        endpoint("[2001:db8::1]:5353"),
+        Endpoint::Udp(address("[2001:db8::1]:5353"))
+    );
+    assert_eq!(
+        endpoint("tls://192.0.2.1#dns.example"),
+        Endpoint::Tls {
+            address: address("192.0.2.1:853"),
+            server_name: "dns.example".into()
+        }
+    );
+    let Endpoint::Tls {
+        address: resolved,
+        server_name,
+    } = endpoint("tls://localhost:8853")
+    else {
+        panic!("not DNS over TLS");
+    };
+    assert!(resolved.ip().is_loopback() && resolved.port() == 8853);
+    assert_eq!(server_name, "localhost");
+    assert_eq!(
+        endpoint("https://[2001:db8::1]"),
+        Endpoint::Https {
+            address: address("[2001:db8::1]:443"),
+            host: "2001:db8::1".into(),
+            uri: "https://[2001:db8::1]/dns-query".into()
+        }
+    );
+    let Endpoint::Https { host, uri, .. } = endpoint("https://localhost:8443/resolve") else {
+        panic!("not DNS over HTTPS");
+    };
+    assert_eq!(
+        (host.as_str(), uri.as_str()),
+        ("localhost", "https://localhost:8443/resolve")
+    );
+    assert_eq!(
+        endpoint("quic://192.0.2.1"),
+        Endpoint::Quic {
+            address: address("192.0.2.1:853"),
+            server_name: "192.0.2.1".into()
+        }
+    );
+    assert!("tls://192.0.2.1:dot".parse::<Endpoint>().is_err());
+
+    let request =
+        crate::deserialize(&[0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 1, 0, 1]).unwrap();
+    let mut response = request.clone();
+    response.header.query = QR::Response;
+    response.questions[0].name = "A.".into();
+    assert!(answers(&response, &request));
+    response.questions[0].dns_type = DnsType::Aaaa;
+    assert!(!answers(&response, &request));
+    response.questions[0].dns_type = DnsType::A;
+    response.header.id = 8;
+    assert!(!answers(&response, &request));
+}
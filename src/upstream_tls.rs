@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::client::conn::http2::SendRequest;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// A SHA-256 digest of a certificate's SubjectPublicKeyInfo, given base64
+/// encoded like HPKP's pin-sha256 as `<server name>=<base64>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpkiPin {
+    pub server_name: String,
+    pub digest: [u8; 32],
+}
+
+impl std::str::FromStr for SpkiPin {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((server_name, digest)) = value.split_once('=') else {
+            return Err(format!(
+                "expected <server name>=<base64 SHA-256>, got {value}"
+            ));
+        };
+        let digest = BASE64_STANDARD
+            .decode(digest)
+            .ok()
+            .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+            .ok_or_else(|| format!("invalid SHA-256 digest {digest}"))?;
+
+        Ok(SpkiPin {
+            server_name: server_name.trim_end_matches('.').to_ascii_lowercase(),
+            digest,
+        })
+    }
+}
+
+/// Checks upstream certificates against the roots, and then, for the server
+/// names with pins, that one of the chain's keys is pinned.
+#[derive(Debug)]
+struct PinningVerifier {
+    roots: Arc<WebPkiServerVerifier>,
+    pins: HashMap<String, Vec<[u8; 32]>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.roots.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let name = server_name.to_str().to_ascii_lowercase();
+        let Some(pins) = self.pins.get(&name) else {
+            return Ok(verified);
+        };
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| spki_digest(cert))
+            .any(|digest| pins.contains(&digest));
+        if !pinned {
+            return Err(rustls::Error::General(format!(
+                "no key of {name}'s certificates is pinned"
+            )));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.roots.supported_verify_schemes()
+    }
+}
+
+/// The pin of `cert`'s key.
+pub fn spki_digest(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(Sha256::digest(cert.subject_public_key_info()).into())
+}
+
+/// How we connect to encrypted upstreams: the TLS setups for DNS over TLS,
+/// over HTTPS and over QUIC.
+#[derive(Debug, Clone)]
+pub struct TlsClients {
+    tls: Arc<ClientConfig>,
+    https: Arc<ClientConfig>,
+    quic: Arc<ClientConfig>,
+}
+
+impl TlsClients {
+    /// Trusts the PEM certificates in `roots`, or the Mozilla root program's
+    /// without any, and requires one of the `pins` of a server name to be in
+    /// its chain.
+    pub fn new(roots: Option<&Path>, pins: &[SpkiPin]) -> anyhow::Result<TlsClients> {
+        let mut store = RootCertStore::empty();
+        match roots {
+            Some(path) => {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .with_context(|| format!("reading certificates from {}", path.display()))?;
+                for cert in certs {
+                    store.add(cert).context("adding upstream root")?;
+                }
+            }
+            None => store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let roots = WebPkiServerVerifier::builder_with_provider(Arc::new(store), provider)
+            .build()
+            .context("setting up upstream verification")?;
+        let mut pinned: HashMap<String, Vec<[u8; 32]>> = HashMap::new();
+        for pin in pins {
+            pinned
+                .entry(pin.server_name.clone())
+                .or_default()
+                .push(pin.digest);
+        }
+        let verifier = Arc::new(PinningVerifier {
+            roots,
+            pins: pinned,
+        });
+
+        let tls = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+        let mut https = tls.clone();
+        https.alpn_protocols = vec![b"h2".to_vec()];
+        let mut quic = tls.clone();
+        quic.alpn_protocols = vec![b"doq".to_vec()];
+        // forwarded queries can be replayed, so they may go in 0-RTT data
+        quic.enable_early_data = true;
+
+        Ok(TlsClients {
+            tls: Arc::new(tls),
+            https: Arc::new(https),
+            quic: Arc::new(quic),
+        })
+    }
+}
+
+/// A DNS over TLS upstream (RFC 7858). Queries share one connection, kept
+/// open for the next ones, and don't wait for each other's answers.
+#[derive(Debug)]
+pub struct TlsUpstream {
+    address: SocketAddr,
+    server_name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    connection: Mutex<Option<Arc<TlsConnection>>>,
+}
+
+/// An open connection, whose answers a thread of its own reads and hands to
+/// the queries waiting for them by id.
+#[derive(Debug)]
+struct TlsConnection {
+    tls: Mutex<ClientConnection>,
+    socket: TcpStream,
+    waiting: Mutex<HashMap<u16, mpsc::Sender<Vec<u8>>>>,
+    next_id: AtomicU16,
+    closed: AtomicBool,
+}
+
+impl TlsUpstream {
+    pub fn new(
+        address: SocketAddr,
+        server_name: &str,
+        clients: &TlsClients,
+    ) -> anyhow::Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .with_context(|| format!("invalid server name {server_name}"))?;
+
+        Ok(TlsUpstream {
+            address,
+            server_name,
+            config: Arc::clone(&clients.tls),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Sends the raw `request` and returns the raw response. A connection the
+    /// upstream closed in the meantime is opened again once.
+    pub fn forward(&self, request: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let (connection, reused) = self.connection(timeout)?;
+        match connection.exchange(request, timeout) {
+            Err(e) if reused && e.kind() != io::ErrorKind::TimedOut => {
+                let (connection, _) = self.connection(timeout)?;
+                connection.exchange(request, timeout)
+            }
+            result => result,
+        }
+    }
+
+    /// The open connection, and whether it was opened before.
+    fn connection(&self, timeout: Duration) -> io::Result<(Arc<TlsConnection>, bool)> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(open) = connection
+            .as_ref()
+            .filter(|c| !c.closed.load(Ordering::Relaxed))
+        {
+            return Ok((Arc::clone(open), true));
+        }
+
+        let mut socket = TcpStream::connect_timeout(&self.address, timeout)?;
+        socket.set_nodelay(true)?;
+        socket.set_read_timeout(Some(timeout))?;
+        let mut tls = ClientConnection::new(Arc::clone(&self.config), self.server_name.clone())
+            .map_err(io::Error::other)?;
+        while tls.is_handshaking() {
+            tls.complete_io(&mut socket)?;
+        }
+        // the reader waits for as long as the upstream keeps the connection
+        socket.set_read_timeout(None)?;
+
+        let open = Arc::new(TlsConnection {
+            tls: Mutex::new(tls),
+            socket: socket.try_clone()?,
+            waiting: Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(0),
+            closed: AtomicBool::new(false),
+        });
+        let reader = Arc::clone(&open);
+        std::thread::spawn(move || reader.read_responses(socket));
+        *connection = Some(Arc::clone(&open));
+
+        Ok((open, false))
+    }
+}
+
+impl TlsConnection {
+    fn exchange(&self, request: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        if request.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "short message"));
+        }
+        // ids are ours on a shared connection, the client's one goes back in the response
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut framed = Vec::with_capacity(request.len() + 2);
+        framed.extend_from_slice(&(request.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&id.to_be_bytes());
+        framed.extend_from_slice(&request[2..]);
+
+        let (sender, receiver) = mpsc::channel();
+        self.waiting.lock().unwrap().insert(id, sender);
+        let sent = self.send(&framed);
+        let response = sent.and_then(|()| {
+            receiver.recv_timeout(timeout).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => {
+                    io::Error::new(io::ErrorKind::TimedOut, "upstream timed out")
+                }
+                mpsc::RecvTimeoutError::Disconnected => io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "upstream closed the connection",
+                ),
+            })
+        });
+        self.waiting.lock().unwrap().remove(&id);
+
+        let mut response = response?;
+        response[..2].copy_from_slice(&request[..2]);
+        Ok(response)
+    }
+
+    fn send(&self, framed: &[u8]) -> io::Result<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection closed",
+            ));
+        }
+        let mut tls = self.tls.lock().unwrap();
+        tls.writer().write_all(framed)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hands each response to the query waiting for it until the connection
+    /// is closed, which then fails the queries still waiting.
+    fn read_responses(&self, mut socket: TcpStream) {
+        let mut buf = vec![0; 16384];
+        let mut received = Vec::new();
+        loop {
+            let size = match socket.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(size) => size,
+            };
+
+            let mut tls = self.tls.lock().unwrap();
+            if tls.read_tls(&mut &buf[..size]).is_err() || tls.process_new_packets().is_err() {
+                break;
+            }
+            let open = match tls.reader().read_to_end(&mut received) {
+                Ok(_) => false,
+                Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+            };
+            while tls.wants_write() {
+                if tls.write_tls(&mut &self.socket).is_err() {
+                    break;
+                }
+            }
+            drop(tls);
+
+            while received.len() >= 2 {
+                let len = u16::from_be_bytes([received[0], received[1]]) as usize;
+                if received.len() < len + 2 {
+                    break;
+                }
+                let response: Vec<u8> = received.drain(..len + 2).skip(2).collect();
+                if response.len() >= 2 {
+                    let id = u16::from_be_bytes([response[0], response[1]]);
+                    if let Some(sender) = self.waiting.lock().unwrap().get(&id) {
+                        let _ = sender.send(response);
+                    }
+                }
+            }
+            if !open {
+                break;
+            }
+        }
+
+        self.closed.store(true, Ordering::Relaxed);
+        self.waiting.lock().unwrap().clear();
+        let _ = self.socket.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Where the connections to DNS over HTTPS and QUIC upstreams run.
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("Failed to start the HTTPS client runtime")
+    })
+}
+
+/// A DNS over HTTPS upstream (RFC 8484), POSTed to over one HTTP/2
+/// connection kept open, its streams carrying queries side by side.
+#[derive(Debug)]
+pub struct HttpsUpstream {
+    address: SocketAddr,
+    server_name: ServerName<'static>,
+    uri: hyper::Uri,
+    config: Arc<ClientConfig>,
+    sender: Mutex<Option<SendRequest<Full<Bytes>>>>,
+}
+
+impl HttpsUpstream {
+    /// The upstream at `address`, serving `uri` as `host`.
+    pub fn new(
+        address: SocketAddr,
+        host: &str,
+        uri: &str,
+        clients: &TlsClients,
+    ) -> anyhow::Result<Self> {
+        Ok(HttpsUpstream {
+            address,
+            server_name: ServerName::try_from(host.to_string())
+                .with_context(|| format!("invalid server name {host}"))?,
+            uri: uri.parse().with_context(|| format!("invalid URI {uri}"))?,
+            config: Arc::clone(&clients.https),
+            sender: Mutex::new(None),
+        })
+    }
+
+    pub fn forward(self: &Arc<Self>, request: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel();
+        let upstream = Arc::clone(self);
+        let request = request.to_vec();
+        runtime().spawn(async move {
+            let _ = sender.send(upstream.exchange(request, timeout).await);
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(response) => response.map_err(io::Error::other),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "upstream timed out",
+            )),
+        }
+    }
+
+    async fn exchange(&self, request: Vec<u8>, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        let mut sender = self.sender(timeout).await?;
+        let request = Request::post(self.uri.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Full::new(Bytes::from(request)))?;
+
+        let response = sender.send_request(request).await?;
+        if response.status() != StatusCode::OK {
+            anyhow::bail!("{} answered {}", self.uri, response.status());
+        }
+        Ok(response.into_body().collect().await?.to_bytes().to_vec())
+    }
+
+    /// The open connection's handle, opening one if there's none.
+    async fn sender(&self, timeout: Duration) -> anyhow::Result<SendRequest<Full<Bytes>>> {
+        let open = self.sender.lock().unwrap().clone();
+        if let Some(sender) = open.filter(|sender| !sender.is_closed()) {
+            return Ok(sender);
+        }
+
+        let connect = async {
+            let socket = tokio::net::TcpStream::connect(self.address).await?;
+            socket.set_nodelay(true)?;
+            let connector = tokio_rustls::TlsConnector::from(Arc::clone(&self.config));
+            let stream = connector.connect(self.server_name.clone(), socket).await?;
+            let (sender, connection) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                    .await?;
+            anyhow::Ok((sender, connection))
+        };
+        let (sender, connection) = tokio::time::timeout(timeout, connect)
+            .await
+            .with_context(|| format!("connecting to {}", self.address))??;
+        let uri = self.uri.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("Connection to {uri} failed: {e}");
+            }
+        });
+        *self.sender.lock().unwrap() = Some(sender.clone());
+
+        Ok(sender)
+    }
+}
+
+/// A DNS over QUIC upstream (RFC 9250), each query going on a stream of its
+/// own over one connection kept open. Connections resuming a session send
+/// their first queries as 0-RTT data.
+#[derive(Debug)]
+pub struct QuicUpstream {
+    address: SocketAddr,
+    server_name: String,
+    endpoint: quinn::Endpoint,
+    connection: Mutex<Option<quinn::Connection>>,
+}
+
+impl QuicUpstream {
+    pub fn new(
+        address: SocketAddr,
+        server_name: &str,
+        clients: &TlsClients,
+    ) -> anyhow::Result<Self> {
+        ServerName::try_from(server_name)
+            .with_context(|| format!("invalid server name {server_name}"))?;
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(Arc::clone(&clients.quic))
+            .context("setting up QUIC")?;
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let _runtime = runtime().enter();
+        let mut endpoint = quinn::Endpoint::client(local).context("binding QUIC socket")?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+
+        Ok(QuicUpstream {
+            address,
+            server_name: server_name.to_string(),
+            endpoint,
+            connection: Mutex::new(None),
+        })
+    }
+
+    pub fn forward(self: &Arc<Self>, request: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        if request.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "short message"));
+        }
+        let (sender, receiver) = mpsc::channel();
+        let upstream = Arc::clone(self);
+        let request = request.to_vec();
+        runtime().spawn(async move {
+            let _ = sender.send(upstream.exchange(request, timeout).await);
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(response) => response.map_err(io::Error::other),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "upstream timed out",
+            )),
+        }
+    }
+
+    /// Asks again once when the connection was closed in the meantime or the
+    /// upstream turned down its 0-RTT data.
+    async fn exchange(&self, request: Vec<u8>, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        let (connection, retry) = self.connection(timeout).await?;
+        let mut response = match query(&connection, &request).await {
+            Err(_) if retry => {
+                let (connection, _) = self.connection(timeout).await?;
+                query(&connection, &request).await?
+            }
+            response => response?,
+        };
+        response[..2].copy_from_slice(&request[..2]);
+
+        Ok(response)
+    }
+
+    /// The open connection, opening one if there's none, and whether it was
+    /// opened before or is still in 0-RTT, where queries may fail for reasons
+    /// of the connection's own.
+    async fn connection(&self, timeout: Duration) -> anyhow::Result<(quinn::Connection, bool)> {
+        let open = self.connection.lock().unwrap().clone();
+        if let Some(connection) = open.filter(|c| c.close_reason().is_none()) {
+            return Ok((connection, true));
+        }
+
+        let connecting = self.endpoint.connect(self.address, &self.server_name)?;
+        let (connection, zero_rtt) = match connecting.into_0rtt() {
+            Ok((connection, _)) => (connection, true),
+            Err(connecting) => {
+                let connection = tokio::time::timeout(timeout, connecting)
+                    .await
+                    .with_context(|| format!("connecting to {}", self.address))??;
+                (connection, false)
+            }
+        };
+        *self.connection.lock().unwrap() = Some(connection.clone());
+
+        Ok((connection, zero_rtt))
+    }
+}
+
+/// Sends `request` on a stream of its own with the id 0 DNS over QUIC
+/// requires, and reads the response off it.
+async fn query(connection: &quinn::Connection, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut framed = Vec::with_capacity(request.len() + 2);
+    framed.extend_from_slice(&(request.len() as u16).to_be_bytes());
+    framed.extend_from_slice(&[0, 0]);
+    framed.extend_from_slice(&request[2..]);
+    send.write_all(&framed).await?;
+    send.finish()?;
+
+    let message = recv.read_to_end(crate::server::TCP_MAX_SIZE + 2).await?;
+    let Some((len, response)) = message.split_first_chunk::<2>() else {
+        anyhow::bail!("stream closed without a response");
+    };
+    let len = u16::from_be_bytes(*len) as usize;
+    if len < 12 {
+        anyhow::bail!("response too short for a DNS header");
+    }
+    if response.len() < len {
+        anyhow::bail!("truncated response");
+    }
+
+    Ok(response[..len].to_vec())
+}
+
+#[test]
+fn test_tls_upstream() {
+    use crate::server;
+    use rustls::{ServerConnection, StreamOwned};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["dns.test".into()]).unwrap();
+    let dir = std::env::temp_dir();
+    let roots = dir.join(format!("test_upstream_roots_{}", std::process::id()));
+    let key = dir.join(format!("test_upstream_key_{}", std::process::id()));
+    std::fs::write(&roots, cert.cert.pem()).unwrap();
+    std::fs::write(&key, cert.key_pair.serialize_pem()).unwrap();
+    let config = crate::tls::server_config(&roots, &key, &[]).unwrap();
+
+    // answers each pair of messages with themselves, the second one first
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let connection = ServerConnection::new(Arc::clone(&config)).unwrap();
+            let mut stream = StreamOwned::new(connection, stream.unwrap());
+            std::thread::spawn(move || {
+                let mut messages = vec![];
+                while let Ok(Some(message)) = server::read_message(&mut stream) {
+                    messages.push(message);
+                    if messages.len() == 2 {
+                        for message in messages.drain(..).rev() {
+                            server::write_message(&mut stream, &message).unwrap();
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let pin = |digest: &[u8; 32]| format!("dns.test={}", BASE64_STANDARD.encode(digest));
+    let digest = spki_digest(cert.cert.der()).unwrap();
+    let clients = TlsClients::new(Some(&roots), &[pin(&digest).parse().unwrap()]).unwrap();
+    let wrong_pin = TlsClients::new(Some(&roots), &[pin(&[0; 32]).parse().unwrap()]).unwrap();
+    let default_roots = TlsClients::new(None, &[]).unwrap();
+    std::fs::remove_file(&roots).unwrap();
+    std::fs::remove_file(&key).unwrap();
+    let timeout = Duration::from_secs(2);
+
+    // both queries go out on one connection before the first is answered
+    let upstream = Arc::new(TlsUpstream::new(address, "dns.test", &clients).unwrap());
+    let queries: Vec<_> = [[0, 7, 1], [0, 7, 2]]
+        .into_iter()
+        .map(|query| {
+            let upstream = Arc::clone(&upstream);
+            std::thread::spawn(move || upstream.forward(&query, timeout).unwrap())
+        })
+        .collect();
+    let responses: Vec<Vec<u8>> = queries.into_iter().map(|q| q.join().unwrap()).collect();
+    assert!(responses.contains(&vec![0, 7, 1]) && responses.contains(&vec![0, 7, 2]));
+
+    for clients in [&wrong_pin, &default_roots] {
+        let upstream = TlsUpstream::new(address, "dns.test", clients).unwrap();
+        assert!(upstream.forward(&[0, 1, 1], timeout).is_err());
+    }
+    let other_name = TlsUpstream::new(address, "other.test", &clients).unwrap();
+    assert!(other_name.forward(&[0, 1, 1], timeout).is_err());
+    assert!("dns.test=AAAA".parse::<SpkiPin>().is_err());
+}
@@ -0,0 +1,741 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::acl::Grantee;
+use crate::dns_header::{DnsHeader, ResponseCode, QR};
+use crate::dns_record::{deserialize_name, is_well_formed, DnsClass, DnsRecord, DnsType};
+use crate::journal::{same_record, Change, Journal};
+use crate::tsig::Key;
+use crate::update::UpdateRule;
+use crate::DnsMsg;
+
+/// The most CNAME records followed for a single answer.
+pub const MAX_CNAME_CHAIN: usize = 8;
+
+/// The records an authoritative answer puts in each section of the response.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ZoneAnswer {
+    pub response_code: ResponseCode,
+    pub authoritative: bool,
+    pub answers: Vec<DnsRecord>,
+    pub authority: Vec<DnsRecord>,
+    pub additional: Vec<DnsRecord>,
+}
+
+/// Settings of a served zone that outlive any version of its data.
+#[derive(Debug, Clone, Default)]
+pub struct ZoneOptions {
+    /// Who may transfer the zone, nobody by default.
+    pub allow_transfer: Vec<Grantee>,
+    /// The secondaries sent a NOTIFY when the zone changes (RFC 1996).
+    pub notify: Vec<SocketAddr>,
+    /// Who may change the zone with dynamic updates, nobody by default.
+    pub allow_update: Vec<UpdateRule>,
+    /// The key signing the NOTIFYs, SOA checks and transfers exchanged with
+    /// the zone's primary or secondaries.
+    pub tsig_key: Option<Key>,
+}
+
+/// An authoritative zone: every record at or below `origin`, grouped by owner.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: String,
+    pub options: ZoneOptions,
+    /// The master file the zone was loaded from, rewritten by dynamic updates.
+    pub path: Option<PathBuf>,
+    /// The latest changes, for incremental transfers.
+    pub journal: Journal,
+    /// Keyed by the `node_key` of the owner name, so that the names below
+    /// any name sort right after it.
+    nodes: BTreeMap<String, Vec<DnsRecord>>,
+}
+
+impl Zone {
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> anyhow::Result<Zone> {
+        let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+        let mut nodes: BTreeMap<String, Vec<DnsRecord>> = BTreeMap::new();
+
+        for record in records {
+            let owner = record.name.to_ascii_lowercase();
+            if !is_subdomain(&owner, &origin) {
+                bail!("{} is outside of zone {origin}", record.name);
+            }
+            nodes.entry(node_key(&owner)).or_default().push(record);
+        }
+
+        let soas: Vec<&DnsRecord> = nodes
+            .get(&node_key(&origin))
+            .map(|rrs| rrs.iter().filter(|r| r.dns_type == DnsType::Soa).collect())
+            .unwrap_or_default();
+        let [soa] = soas.as_slice() else {
+            bail!(
+                "zone {origin} must have exactly one SOA at its apex, found {}",
+                soas.len()
+            );
+        };
+        if !is_well_formed(DnsType::Soa, &soa.rd_data) {
+            bail!("the SOA of zone {origin} is malformed");
+        }
+
+        Ok(Zone {
+            origin,
+            options: ZoneOptions::default(),
+            path: None,
+            journal: Journal::default(),
+            nodes,
+        })
+    }
+
+    pub fn load(origin: &str, path: &Path) -> anyhow::Result<Zone> {
+        let records = crate::zone_file::parse_zone_file(path, origin)?;
+        let mut zone =
+            Zone::new(origin, records).with_context(|| format!("loading {}", path.display()))?;
+        zone.path = Some(path.to_path_buf());
+        Ok(zone)
+    }
+
+    /// Applies `change`, the next version of the zone. The journal isn't touched.
+    pub fn apply(&mut self, change: &Change) -> anyhow::Result<()> {
+        let serial = self.serial();
+        match change.old_serial() {
+            Some(old_serial) if old_serial == serial => {}
+            Some(old_serial) => bail!(
+                "change from serial {old_serial} doesn't apply to {} at serial {serial}",
+                self.origin
+            ),
+            None => bail!("change to {} has a malformed SOA", self.origin),
+        }
+
+        for record in &change.deleted {
+            let owner = node_key(&record.name);
+            if let Some(rrs) = self.nodes.get_mut(&owner) {
+                rrs.retain(|r| !same_record(r, record));
+                if rrs.is_empty() {
+                    self.nodes.remove(&owner);
+                }
+            }
+        }
+        for record in &change.added {
+            let owner = record.name.to_ascii_lowercase();
+            if !is_subdomain(&owner, &self.origin) {
+                bail!("{} is outside of zone {}", record.name, self.origin);
+            }
+            let rrs = self.nodes.entry(node_key(&owner)).or_default();
+            if !rrs.iter().any(|r| same_record(r, record)) {
+                rrs.push(record.clone());
+            }
+        }
+
+        let soas: Vec<&DnsRecord> = self.rrset(&self.origin, DnsType::Soa).collect();
+        let [soa] = soas.as_slice() else {
+            bail!(
+                "zone {} ends up with {} SOA records",
+                self.origin,
+                soas.len()
+            );
+        };
+        if !is_well_formed(DnsType::Soa, &soa.rd_data) {
+            bail!("zone {} ends up with a malformed SOA", self.origin);
+        }
+
+        Ok(())
+    }
+
+    pub fn soa(&self) -> &DnsRecord {
+        self.rrset(&self.origin, DnsType::Soa)
+            .next()
+            .expect("zones always have an SOA")
+    }
+
+    pub fn serial(&self) -> u32 {
+        soa_serial(self.soa()).expect("zones always have a well-formed SOA")
+    }
+
+    pub fn timers(&self) -> (Duration, Duration, Duration) {
+        soa_timers(self.soa()).expect("zones always have a well-formed SOA")
+    }
+
+    /// Every record of the zone, SOA first.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        let soa = self.soa();
+        std::iter::once(soa).chain(
+            self.nodes
+                .values()
+                .flatten()
+                .filter(move |record| !std::ptr::eq(*record, soa)),
+        )
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        is_subdomain(&name.to_ascii_lowercase(), &self.origin)
+    }
+
+    fn rrset<'a>(&'a self, name: &str, dns_type: DnsType) -> impl Iterator<Item = &'a DnsRecord> {
+        self.nodes
+            .get(&node_key(name))
+            .into_iter()
+            .flatten()
+            .filter(move |record| record.dns_type == dns_type)
+    }
+
+    /// Answers `qname`/`qtype` from the zone data (RFC 1034 section 4.3.2).
+    pub fn lookup(&self, qname: &str, qtype: DnsType) -> ZoneAnswer {
+        let owner = qname.to_ascii_lowercase();
+        let mut answer = ZoneAnswer {
+            response_code: ResponseCode::NoError,
+            authoritative: true,
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
+        };
+
+        if let Some(cut) = self.delegation(&owner) {
+            // the data at and below a zone cut belongs to the child zone
+            answer.authoritative = false;
+            answer.authority = self.rrset(cut, DnsType::NS).cloned().collect();
+            answer.additional = self.glue(&answer.authority);
+            return answer;
+        }
+
+        let rrs = match self.nodes.get(&node_key(&owner)) {
+            Some(rrs) => rrs,
+            // a name that only owns names below it still exists (empty non-terminal)
+            None if self.has_descendants(&owner) => {
+                answer.authority.push(self.negative_soa());
+                return answer;
+            }
+            None => match self.wildcard(&owner) {
+                Some(rrs) => rrs,
+                None => {
+                    answer.response_code = ResponseCode::NameError;
+                    answer.authority.push(self.negative_soa());
+                    return answer;
+                }
+            },
+        };
+
+        let matching: Vec<&DnsRecord> = rrs
+            .iter()
+            .filter(|r| r.dns_type == qtype || qtype == DnsType::AllRecords)
+            .collect();
+        let cname = rrs.iter().find(|r| r.dns_type == DnsType::Cname);
+
+        let found: Vec<&DnsRecord> = match (matching.is_empty(), cname) {
+            (false, _) => matching,
+            (true, Some(cname)) => vec![cname],
+            (true, None) => {
+                answer.authority.push(self.negative_soa());
+                return answer;
+            }
+        };
+
+        answer.answers = found.into_iter().map(|r| with_owner(r, qname)).collect();
+        answer.authority = self.rrset(&self.origin, DnsType::NS).cloned().collect();
+        answer.additional = self.glue(&answer.authority);
+
+        answer
+    }
+
+    /// The highest zone cut at or above `name`: a name below the apex owning NS records.
+    fn delegation<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let mut cut = None;
+        let mut current = name;
+        while current != self.origin && is_subdomain(current, &self.origin) {
+            if self.rrset(current, DnsType::NS).next().is_some() {
+                cut = Some(current);
+            }
+            current = parent(current);
+        }
+
+        cut
+    }
+
+    /// The records of the wildcard that covers `name`, a name that doesn't exist.
+    /// Only the wildcard child of the closest encloser applies (RFC 4592 section 3.3.1),
+    /// so a wildcard never matches past a name that exists in between.
+    fn wildcard(&self, name: &str) -> Option<&Vec<DnsRecord>> {
+        let mut ancestor = name;
+        while ancestor != self.origin {
+            ancestor = parent(ancestor);
+            if self.nodes.contains_key(&node_key(ancestor)) || self.has_descendants(ancestor) {
+                return self.nodes.get(&node_key(&format!("*.{ancestor}")));
+            }
+        }
+
+        None
+    }
+
+    fn has_descendants(&self, name: &str) -> bool {
+        let key = node_key(name);
+        let prefix = if key.is_empty() {
+            key.clone()
+        } else {
+            format!("{key}.")
+        };
+        // the names below `name` are the first keys from `prefix` on, bar the root itself
+        self.nodes
+            .range(prefix.clone()..)
+            .map(|(owner, _)| owner)
+            .find(|owner| owner.len() > key.len())
+            .is_some_and(|owner| owner.starts_with(&prefix))
+    }
+
+    /// The SOA for negative answers, with the TTL capped by its MINIMUM field (RFC 2308).
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().clone();
+        if let Some(minimum) = soa_minimum(&soa) {
+            soa.time_to_live = soa.time_to_live.min(minimum);
+        }
+        soa
+    }
+
+    /// A and AAAA records held in this zone for the targets of `ns_records`.
+    fn glue(&self, ns_records: &[DnsRecord]) -> Vec<DnsRecord> {
+        let mut glue = Vec::new();
+        for ns in ns_records {
+            let Ok((target, _)) = deserialize_name(&ns.rd_data, 0) else {
+                continue;
+            };
+            for dns_type in [DnsType::A, DnsType::Aaaa] {
+                glue.extend(self.rrset(&target, dns_type).cloned());
+            }
+        }
+
+        glue
+    }
+}
+
+/// The authoritative zones served, looked up by closest enclosing origin.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    zones: Vec<Zone>,
+}
+
+impl Catalog {
+    pub fn new(zones: Vec<Zone>) -> Catalog {
+        Catalog { zones }
+    }
+
+    /// The zone whose origin is exactly `origin`.
+    pub fn get(&self, origin: &str) -> Option<&Zone> {
+        let origin = origin.trim_end_matches('.');
+        self.zones
+            .iter()
+            .find(|zone| zone.origin.eq_ignore_ascii_case(origin))
+    }
+
+    /// Adds `zone`, replacing the zone with the same origin if there's one.
+    pub fn insert(&mut self, zone: Zone) {
+        self.remove(&zone.origin);
+        self.zones.push(zone);
+    }
+
+    pub fn remove(&mut self, origin: &str) -> Option<Zone> {
+        let origin = origin.trim_end_matches('.');
+        let index = self
+            .zones
+            .iter()
+            .position(|zone| zone.origin.eq_ignore_ascii_case(origin))?;
+
+        Some(self.zones.remove(index))
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| zone.contains(name))
+            .max_by_key(|zone| zone.origin.len())
+    }
+
+    /// The A and AAAA records we serve authoritatively for `name`.
+    pub fn addresses(&self, name: &str) -> Vec<DnsRecord> {
+        let Some(zone) = self.find(name) else {
+            return vec![];
+        };
+
+        [DnsType::A, DnsType::Aaaa]
+            .into_iter()
+            .flat_map(|dns_type| zone.lookup(name, dns_type).answers)
+            .filter(|record| matches!(record.dns_type, DnsType::A | DnsType::Aaaa))
+            .collect()
+    }
+
+    /// Appends the records the CNAME ending `answer` leads to, for as long as
+    /// the targets are in our zones. A loop or a chain longer than
+    /// `MAX_CNAME_CHAIN` is a server failure.
+    fn follow_cname_chain(&self, answer: &mut ZoneAnswer, qtype: DnsType) {
+        let mut seen: Vec<String> = Vec::new();
+
+        while let Some(cname) = answer
+            .answers
+            .last()
+            .filter(|r| r.dns_type == DnsType::Cname)
+        {
+            let owner = cname.name.to_ascii_lowercase();
+            let Ok((target, _)) = deserialize_name(&cname.rd_data, 0) else {
+                return;
+            };
+            seen.push(owner);
+
+            if seen.contains(&target.to_ascii_lowercase()) || seen.len() > MAX_CNAME_CHAIN {
+                answer.response_code = ResponseCode::ServerFailure;
+                return;
+            }
+
+            let Some(zone) = self.find(&target) else {
+                return;
+            };
+            let next = zone.lookup(&target, qtype);
+            if !next.authoritative {
+                // delegated away, leave it to the upstream
+                return;
+            }
+
+            answer.response_code = next.response_code;
+            answer.authority = next.authority;
+            for record in next.additional {
+                if !answer.additional.contains(&record) {
+                    answer.additional.push(record);
+                }
+            }
+            if next.answers.is_empty() {
+                return;
+            }
+            answer.answers.extend(next.answers);
+        }
+    }
+
+    /// Builds the response to `query` if its question falls in one of our zones.
+    pub fn answer(&self, query: &DnsMsg) -> Option<DnsMsg> {
+        let [question] = query.questions.as_slice() else {
+            return None;
+        };
+        if !matches!(question.dns_class, DnsClass::IN | DnsClass::AnyClass) {
+            return None;
+        }
+
+        let zone = self.find(&question.name)?;
+        let mut answer = zone.lookup(&question.name, question.dns_type);
+        if !matches!(question.dns_type, DnsType::Cname | DnsType::AllRecords) {
+            self.follow_cname_chain(&mut answer, question.dns_type);
+        }
+
+        Some(DnsMsg {
+            header: DnsHeader {
+                query: QR::Response,
+                aa: answer.authoritative,
+                tc: false,
+                ra: false,
+                z: 0,
+                response_code: answer.response_code,
+                answers_count: answer.answers.len() as u16,
+                authority_count: answer.authority.len() as u16,
+                additional_count: answer.additional.len() as u16,
+                ..query.header
+            },
+            questions: query.questions.clone(),
+            answers: answer.answers,
+            authority: answer.authority,
+            additional: answer.additional,
+        })
+    }
+}
+
+/// Whether `name` equals `parent` or sits below it. Both must be lowercase.
+pub fn is_subdomain(name: &str, parent: &str) -> bool {
+    parent.is_empty()
+        || name == parent
+        || (name.ends_with(parent) && name[..name.len() - parent.len()].ends_with('.'))
+}
+
+/// `name` without its first label, the root being its own parent.
+pub fn parent(name: &str) -> &str {
+    name.split_once('.').map_or("", |(_, parent)| parent)
+}
+
+/// `name` lowercased with its labels reversed, `www.example.com` giving
+/// `com.example.www`.
+fn node_key(name: &str) -> String {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    name.rsplit('.').collect::<Vec<_>>().join(".")
+}
+
+fn with_owner(record: &DnsRecord, owner: &str) -> DnsRecord {
+    DnsRecord {
+        name: owner.to_string(),
+        ..record.clone()
+    }
+}
+
+/// Where the 32 bit field `offset` bytes from the end of the SOA RDATA
+/// starts, none when the RDATA is too short to have its names and fields.
+fn soa_field(soa: &DnsRecord, offset: usize) -> Option<usize> {
+    // the two names take a byte each at the very least
+    soa.rd_data
+        .len()
+        .checked_sub(offset + 2)
+        .map(|start| start + 2)
+}
+
+pub fn soa_serial(soa: &DnsRecord) -> Option<u32> {
+    let start = soa_field(soa, 20)?;
+    Some(crate::utils::bytes_to_i32(&soa.rd_data, start) as u32)
+}
+
+pub fn set_soa_serial(soa: &mut DnsRecord, serial: u32) -> Option<()> {
+    let start = soa_field(soa, 20)?;
+    soa.rd_data[start..start + 4].copy_from_slice(&serial.to_be_bytes());
+    Some(())
+}
+
+/// How often a secondary checks the zone, how soon it retries a failed check
+/// and when it stops serving a zone it couldn't check.
+pub fn soa_timers(soa: &DnsRecord) -> Option<(Duration, Duration, Duration)> {
+    let field = |offset: usize| {
+        let value = crate::utils::bytes_to_i32(&soa.rd_data, soa_field(soa, offset)?);
+        Some(Duration::from_secs(value as u32 as u64))
+    };
+
+    Some((field(16)?, field(12)?, field(8)?))
+}
+
+/// The MINIMUM field, the last 32 bits of the SOA RDATA.
+pub fn soa_minimum(soa: &DnsRecord) -> Option<i32> {
+    Some(crate::utils::bytes_to_i32(&soa.rd_data, soa_field(soa, 4)?))
+}
+
+#[cfg(test)]
+fn test_zone() -> Zone {
+    let text = "$TTL 3600
+@       SOA ns1 hostmaster 1 7200 1800 1209600 300
+        NS  ns1
+ns1     A   192.0.2.1
+www     A   192.0.2.10
+        A   192.0.2.11
+a.b.c   TXT \"deep\"
+alias   CNAME www
+";
+    let records = crate::zone_file::parse_zone(text, "example.com", std::path::Path::new("."));
+    Zone::new("example.com", records.unwrap()).unwrap()
+}
+
+#[test]
+fn test_lookup() {
+    let zone = test_zone();
+
+    let answer = zone.lookup("WWW.example.com", DnsType::A);
+    assert_eq!(answer.response_code, ResponseCode::NoError);
+    assert!(answer.authoritative);
+    assert_eq!(answer.answers.len(), 2);
+    assert_eq!(answer.answers[0].name, "WWW.example.com");
+    assert_eq!(answer.authority[0].dns_type, DnsType::NS);
+    assert_eq!(answer.additional[0].rd_data, vec![192, 0, 2, 1]);
+
+    let answer = zone.lookup("alias.example.com", DnsType::A);
+    assert_eq!(answer.answers[0].dns_type, DnsType::Cname);
+
+    // NODATA and NXDOMAIN carry the SOA with the negative TTL
+    let answer = zone.lookup("www.example.com", DnsType::MX);
+    assert_eq!(answer.response_code, ResponseCode::NoError);
+    assert!(answer.answers.is_empty());
+    assert_eq!(answer.authority[0].dns_type, DnsType::Soa);
+    assert_eq!(answer.authority[0].time_to_live, 300);
+
+    let answer = zone.lookup("nope.example.com", DnsType::A);
+    assert_eq!(answer.response_code, ResponseCode::NameError);
+    assert_eq!(answer.authority[0].dns_type, DnsType::Soa);
+
+    // b.c.example.com is an empty non-terminal
+    let answer = zone.lookup("b.c.example.com", DnsType::A);
+    assert_eq!(answer.response_code, ResponseCode::NoError);
+}
+
+#[test]
+fn test_wildcard() {
+    let text = "$TTL 3600
+@                   SOA ns1 hostmaster 1 7200 1800 1209600 300
+*.dev               A   192.0.2.50
+*.dev               MX  10 mail
+host.dev            A   192.0.2.51
+x.team.dev          A   192.0.2.52
+team-a.dev          A   192.0.2.53
+";
+    let records = crate::zone_file::parse_zone(text, "example.com", std::path::Path::new("."));
+    let zone = Zone::new("example.com", records.unwrap()).unwrap();
+
+    // synthesized answers are owned by the query name
+    let answer = zone.lookup("anything.dev.example.com", DnsType::A);
+    assert_eq!(answer.response_code, ResponseCode::NoError);
+    assert_eq!(answer.answers.len(), 1);
+    assert_eq!(answer.answers[0].name, "anything.dev.example.com");
+    assert_eq!(answer.answers[0].rd_data, vec![192, 0, 2, 50]);
+
+    // the wildcard owns no AAAA: NODATA
+    let answer = zone.lookup("anything.dev.example.com", DnsType::Aaaa);
+    assert_eq!(answer.response_code, ResponseCode::NoError);
+    assert!(answer.answers.is_empty());
+    assert_eq!(answer.authority[0].dns_type, DnsType::Soa);
+
+    // existing names are never overridden by the wildcard
+    let answer = zone.lookup("host.dev.example.com", DnsType::MX);
+    assert!(answer.answers.is_empty());
+
+    // team.dev is an empty non-terminal, so it's the closest encloser and has no
+    // wildcard, even with team-a.dev sorting between it and x.team.dev
+    let answer = zone.lookup("team.dev.example.com", DnsType::A);
+    assert_eq!(answer.response_code, ResponseCode::NoError);
+    assert!(answer.answers.is_empty());
+    let answer = zone.lookup("y.team.dev.example.com", DnsType::A);
+    assert_eq!(answer.response_code, ResponseCode::NameError);
+
+    // names deeper than one label below the wildcard still match
+    let answer = zone.lookup("a.b.dev.example.com", DnsType::A);
+    assert_eq!(answer.answers[0].name, "a.b.dev.example.com");
+
+    let answer = zone.lookup("other.example.com", DnsType::A);
+    assert_eq!(answer.response_code, ResponseCode::NameError);
+}
+
+#[test]
+fn test_delegation() {
+    let text = "$TTL 3600
+@                   SOA ns1 hostmaster 1 7200 1800 1209600 300
+                    NS  ns1
+ns1                 A   192.0.2.1
+team                NS  ns.team
+                    NS  ns.provider.net.
+ns.team             A   192.0.2.80
+ns.team             AAAA 2001:db8::80
+";
+    let records = crate::zone_file::parse_zone(text, "example.com", std::path::Path::new("."));
+    let zone = Zone::new("example.com", records.unwrap()).unwrap();
+
+    for name in [
+        "team.example.com",
+        "www.team.example.com",
+        "ns.team.example.com",
+    ] {
+        let answer = zone.lookup(name, DnsType::A);
+        assert_eq!(answer.response_code, ResponseCode::NoError, "{name}");
+        assert!(!answer.authoritative);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.authority.len(), 2);
+        assert!(answer
+            .authority
+            .iter()
+            .all(|r| r.name == "team.example.com"));
+        assert_eq!(answer.additional.len(), 2);
+    }
+
+    // the apex NS set isn't a delegation
+    let answer = zone.lookup("ns1.example.com", DnsType::A);
+    assert!(answer.authoritative);
+    assert_eq!(answer.answers.len(), 1);
+}
+
+#[test]
+fn test_cname_chain() {
+    let text = "$TTL 3600
+@       SOA ns1 hostmaster 1 7200 1800 1209600 300
+www     A   192.0.2.10
+one     CNAME two
+two     CNAME www
+ext     CNAME cdn.example.net.
+loop1   CNAME loop2
+loop2   CNAME loop1
+dangling CNAME missing
+";
+    let records = crate::zone_file::parse_zone(text, "example.com", std::path::Path::new("."));
+    let other = "$TTL 60\n@ SOA ns hostmaster 1 2 3 4 5\nhop CNAME one.example.com.\n";
+    let other_records =
+        crate::zone_file::parse_zone(other, "example.org", std::path::Path::new("."));
+    let catalog = Catalog::new(vec![
+        Zone::new("example.com", records.unwrap()).unwrap(),
+        Zone::new("example.org", other_records.unwrap()).unwrap(),
+    ]);
+    let mut query =
+        crate::deserialize(&[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1]).unwrap();
+
+    let mut ask = |name: &str| {
+        query.questions[0].name = name.into();
+        catalog.answer(&query).unwrap()
+    };
+
+    // the chain crosses zones and ends with the address
+    let response = ask("hop.example.org");
+    let owners: Vec<&str> = response.answers.iter().map(|r| r.name.as_str()).collect();
+    assert_eq!(
+        owners,
+        [
+            "hop.example.org",
+            "one.example.com",
+            "two.example.com",
+            "www.example.com"
+        ]
+    );
+    assert_eq!(response.answers[3].dns_type, DnsType::A);
+    assert_eq!(response.header.response_code, ResponseCode::NoError);
+
+    // targets outside our zones are left for the upstream
+    let response = ask("ext.example.com");
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.header.response_code, ResponseCode::NoError);
+
+    let response = ask("loop1.example.com");
+    assert_eq!(response.header.response_code, ResponseCode::ServerFailure);
+
+    // the response code is the one of the last name in the chain
+    let response = ask("dangling.example.com");
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.header.response_code, ResponseCode::NameError);
+}
+
+#[test]
+fn test_catalog_answer() {
+    let catalog = Catalog::new(vec![test_zone()]);
+    let query = DnsMsg {
+        header: DnsHeader {
+            id: 7,
+            query: QR::Query,
+            op_code: crate::dns_header::OpCode::StandardQuery,
+            aa: false,
+            tc: false,
+            rd: true,
+            ra: false,
+            z: 0,
+            response_code: ResponseCode::NoError,
+            questions_count: 1,
+            answers_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        },
+        questions: vec![DnsRecord {
+            name: "ns1.example.com".into(),
+            dns_type: DnsType::A,
+            dns_class: DnsClass::IN,
+            time_to_live: 0,
+            rd_length: 0,
+            rd_data: vec![],
+        }],
+        answers: vec![],
+        authority: vec![],
+        additional: vec![],
+    };
+
+    let response = catalog.answer(&query).unwrap();
+    assert_eq!(response.header.id, 7);
+    assert_eq!(response.header.query, QR::Response);
+    assert!(response.header.aa);
+    assert_eq!(response.answers.len(), 1);
+
+    let bytes = crate::serialize(&response);
+    assert_eq!(crate::deserialize(&bytes).unwrap(), response);
+
+    let mut other = query.clone();
+    other.questions[0].name = "example.org".into();
+    assert!(catalog.answer(&other).is_none());
+}
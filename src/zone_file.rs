@@ -0,0 +1,817 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::dns_record::{
+    deserialize_name, is_valid_name, is_well_formed, serialize_name, DnsClass, DnsRecord, DnsType,
+};
+use crate::utils;
+
+/// Nested `$INCLUDE` directives deeper than this are rejected.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum ZoneFileError {
+    #[error("{file}:{line}: {message}")]
+    Syntax {
+        file: String,
+        line: usize,
+        message: String,
+    },
+    #[error("couldn't read {file}: {source}")]
+    Io {
+        file: String,
+        source: std::io::Error,
+    },
+}
+
+/// A single token of a master file entry. Quoted strings keep their
+/// spaces and are never interpreted as names or directives.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// One logical entry: a line, or several lines joined by parentheses.
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    /// Entries starting with blank space reuse the previous owner.
+    has_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// State carried from one entry to the next while reading a file.
+struct Context {
+    origin: String,
+    default_ttl: Option<i32>,
+    last_owner: Option<String>,
+    last_ttl: Option<i32>,
+}
+
+/// Reads the master file at `path` (RFC 1035 section 5) with `origin` as the
+/// initial `$ORIGIN`.
+pub fn parse_zone_file(path: &Path, origin: &str) -> Result<Vec<DnsRecord>, ZoneFileError> {
+    let mut context = Context {
+        origin: normalize(origin),
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+    };
+    let mut records = Vec::new();
+    parse_file(path, &mut context, &mut records, 0)?;
+
+    Ok(records)
+}
+
+/// Parses master file text. `$INCLUDE` paths are relative to `base_dir`.
+pub fn parse_zone(
+    text: &str,
+    origin: &str,
+    base_dir: &Path,
+) -> Result<Vec<DnsRecord>, ZoneFileError> {
+    let mut context = Context {
+        origin: normalize(origin),
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+    };
+    let mut records = Vec::new();
+    parse_text(text, "<zone>", base_dir, &mut context, &mut records, 0)?;
+
+    Ok(records)
+}
+
+fn parse_file(
+    path: &Path,
+    context: &mut Context,
+    records: &mut Vec<DnsRecord>,
+    depth: usize,
+) -> Result<(), ZoneFileError> {
+    let file = path.display().to_string();
+    let text = std::fs::read_to_string(path).map_err(|source| ZoneFileError::Io {
+        file: file.clone(),
+        source,
+    })?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+
+    parse_text(&text, &file, base_dir, context, records, depth)
+}
+
+fn parse_text(
+    text: &str,
+    file: &str,
+    base_dir: &Path,
+    context: &mut Context,
+    records: &mut Vec<DnsRecord>,
+    depth: usize,
+) -> Result<(), ZoneFileError> {
+    let syntax = |line: usize, message: String| ZoneFileError::Syntax {
+        file: file.to_string(),
+        line,
+        message,
+    };
+
+    for entry in tokenize(text).map_err(|(line, message)| syntax(line, message))? {
+        let first = &entry.tokens[0];
+
+        if entry.has_owner && !first.quoted && first.text.starts_with('$') {
+            let argument = |i: usize| {
+                entry
+                    .tokens
+                    .get(i)
+                    .map(|token| token.text.as_str())
+                    .ok_or_else(|| syntax(entry.line, format!("{} needs an argument", first.text)))
+            };
+
+            match first.text.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    context.origin = parse_name(argument(1)?, &context.origin)
+                        .map_err(|e| syntax(entry.line, e))?;
+                }
+                "$TTL" => {
+                    let ttl = parse_ttl(argument(1)?).map_err(|e| syntax(entry.line, e))?;
+                    context.default_ttl = Some(ttl);
+                }
+                "$INCLUDE" => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(syntax(entry.line, "$INCLUDE nested too deeply".into()));
+                    }
+                    let path: PathBuf = base_dir.join(argument(1)?);
+                    // the included file can't change the origin of the parent (RFC 1035 5.1)
+                    let origin = context.origin.clone();
+                    if let Some(token) = entry.tokens.get(2) {
+                        context.origin =
+                            parse_name(&token.text, &origin).map_err(|e| syntax(entry.line, e))?;
+                    }
+                    parse_file(&path, context, records, depth + 1)?;
+                    context.origin = origin;
+                }
+                directive => {
+                    return Err(syntax(entry.line, format!("unknown directive {directive}")))
+                }
+            }
+            continue;
+        }
+
+        let record = parse_record(&entry, context).map_err(|e| syntax(entry.line, e))?;
+        records.push(record);
+    }
+
+    Ok(())
+}
+
+fn parse_record(entry: &Entry, context: &mut Context) -> Result<DnsRecord, String> {
+    let mut tokens = entry.tokens.iter().map(|token| token.text.as_str());
+
+    let owner = if entry.has_owner {
+        let owner = tokens.next().expect("entries are never empty");
+        parse_name(owner, &context.origin)?
+    } else {
+        context
+            .last_owner
+            .clone()
+            .ok_or("the first record must have an owner name")?
+    };
+
+    // TTL and class are both optional and may come in either order
+    let mut ttl = None;
+    let mut dns_class = None;
+    let dns_type = loop {
+        let token = tokens.next().ok_or("missing record type")?;
+        if ttl.is_none() && token.starts_with(|c: char| c.is_ascii_digit()) {
+            ttl = Some(parse_ttl(token)?);
+        } else if dns_class.is_none() && parse_class(token).is_some() {
+            dns_class = parse_class(token);
+        } else {
+            break parse_type(token).ok_or(format!("unknown record type {token}"))?;
+        }
+    };
+
+    let ttl = match ttl.or(context.default_ttl).or(context.last_ttl) {
+        Some(ttl) => ttl,
+        None => return Err("no TTL given and no $TTL in effect".into()),
+    };
+
+    let rdata: Vec<&Token> = entry
+        .tokens
+        .iter()
+        .skip(entry.tokens.len() - tokens.count())
+        .collect();
+    let rd_data = encode_rd_data(dns_type, &rdata, &context.origin)?;
+
+    context.last_owner = Some(owner.clone());
+    context.last_ttl = Some(ttl);
+
+    Ok(DnsRecord {
+        name: owner,
+        dns_type,
+        dns_class: dns_class.unwrap_or(DnsClass::IN),
+        time_to_live: ttl,
+        rd_length: rd_data.len() as u16,
+        rd_data,
+    })
+}
+
+fn encode_rd_data(dns_type: DnsType, rdata: &[&Token], origin: &str) -> Result<Vec<u8>, String> {
+    let field = |i: usize| {
+        rdata
+            .get(i)
+            .map(|token| token.text.as_str())
+            .ok_or(format!("{dns_type:?} record is missing fields"))
+    };
+    let name = |i: usize| field(i).and_then(|text| Ok(serialize_name(&parse_name(text, origin)?)));
+    let number = |i: usize| {
+        field(i).and_then(|text| {
+            text.parse::<u16>()
+                .map_err(|_| format!("invalid number {text}"))
+        })
+    };
+
+    if rdata
+        .first()
+        .is_some_and(|token| !token.quoted && token.text == "\\#")
+    {
+        return decode_generic(dns_type, &rdata[1..]);
+    }
+
+    let mut bytes = Vec::new();
+    match dns_type {
+        DnsType::A => {
+            let address: Ipv4Addr = field(0)?.parse().map_err(|_| "invalid IPv4 address")?;
+            bytes.extend(address.octets());
+        }
+        DnsType::Aaaa => {
+            let address: Ipv6Addr = field(0)?.parse().map_err(|_| "invalid IPv6 address")?;
+            bytes.extend(address.octets());
+        }
+        DnsType::NS
+        | DnsType::MD
+        | DnsType::MF
+        | DnsType::Cname
+        | DnsType::MB
+        | DnsType::MG
+        | DnsType::MR
+        | DnsType::Ptr => bytes.extend(name(0)?),
+        DnsType::MX => {
+            bytes.extend(number(0)?.to_be_bytes());
+            bytes.extend(name(1)?);
+        }
+        DnsType::Srv => {
+            for i in 0..3 {
+                bytes.extend(number(i)?.to_be_bytes());
+            }
+            bytes.extend(name(3)?);
+        }
+        DnsType::Soa => {
+            bytes.extend(name(0)?);
+            bytes.extend(name(1)?);
+            let serial: u32 = field(2)?.parse().map_err(|_| "invalid SOA serial")?;
+            bytes.extend(serial.to_be_bytes());
+            for i in 3..7 {
+                bytes.extend(parse_ttl(field(i)?)?.to_be_bytes());
+            }
+        }
+        DnsType::Txt | DnsType::Hinfo => {
+            if rdata.is_empty() {
+                return Err(format!("{dns_type:?} record is missing fields"));
+            }
+            for token in rdata {
+                let text =
+                    unescape(&token.text).ok_or(format!("invalid escape in {}", token.text))?;
+                if text.len() > 255 {
+                    return Err("character string longer than 255 bytes".into());
+                }
+                bytes.push(text.len() as u8);
+                bytes.extend(text);
+            }
+        }
+        _ => {
+            return Err(format!(
+                "{dns_type:?} records aren't supported in zone files"
+            ))
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// The RDATA in the generic form of RFC 3597, `\# length hex`, which any
+/// type can be written in.
+fn decode_generic(dns_type: DnsType, rdata: &[&Token]) -> Result<Vec<u8>, String> {
+    let (len, hex) = rdata
+        .split_first()
+        .ok_or("generic RDATA is missing its length")?;
+    let len: usize = len
+        .text
+        .parse()
+        .map_err(|_| format!("invalid RDATA length {}", len.text))?;
+    let hex: String = hex.iter().map(|token| token.text.as_str()).collect();
+    if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex RDATA {hex}"));
+    }
+
+    let bytes: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    if bytes.len() != len {
+        return Err(format!("RDATA is {} bytes, not {len}", bytes.len()));
+    }
+    if !is_well_formed(dns_type, &bytes) {
+        return Err(format!("malformed {} RDATA", type_name(dns_type)));
+    }
+
+    Ok(bytes)
+}
+
+/// Splits master file text into entries, dropping comments and joining
+/// the lines inside parentheses.
+fn tokenize(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut depth = 0;
+    let mut line = 1;
+    let mut at_line_start = true;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                line += 1;
+                at_line_start = true;
+                if depth == 0 {
+                    entries.extend(entry.take());
+                }
+                continue;
+            }
+            ';' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err((line, "unbalanced parenthesis".into()));
+                }
+                depth -= 1;
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let current = entry.get_or_insert(Entry {
+                    line,
+                    has_owner: at_line_start,
+                    tokens: Vec::new(),
+                });
+                let token = if c == '"' {
+                    read_quoted(&mut chars).ok_or((line, "unterminated string".to_string()))?
+                } else {
+                    let mut text = String::from(c);
+                    while let Some(&next) = chars.peek() {
+                        if next.is_whitespace() || matches!(next, ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        text.push(next);
+                        chars.next();
+                    }
+                    Token {
+                        text,
+                        quoted: false,
+                    }
+                };
+                current.tokens.push(token);
+            }
+        }
+        at_line_start = false;
+    }
+
+    if depth != 0 {
+        return Err((line, "unbalanced parenthesis".into()));
+    }
+    entries.extend(entry.take());
+
+    Ok(entries)
+}
+
+/// Reads a quoted string up to its closing quote, leaving its escapes for
+/// `unescape` since not every token can hold any byte.
+fn read_quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<Token> {
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            '\\' => {
+                text.push('\\');
+                text.push(chars.next()?);
+            }
+            c => text.push(c),
+        }
+    }
+
+    Some(Token { text, quoted: true })
+}
+
+/// The bytes `text` stands for: `\DDD` is a decimal byte value and a
+/// backslash before anything else stands for that character itself.
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        let escaped = chars.next()?;
+        if escaped.is_ascii_digit() {
+            let digits: String = [Some(escaped), chars.next(), chars.next()]
+                .into_iter()
+                .collect::<Option<_>>()?;
+            bytes.push(digits.parse().ok()?);
+        } else {
+            bytes.extend_from_slice(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+
+    Some(bytes)
+}
+
+/// `text` in master file form, escaping what `unescape` would read differently.
+fn escape(text: &[u8]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for &byte in text {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte as char);
+            }
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{byte:03}")),
+        }
+    }
+
+    escaped
+}
+
+/// Writes `records` as a master file with absolute names, replacing `path` atomically.
+pub fn write_zone_file(path: &Path, records: &[DnsRecord]) -> std::io::Result<()> {
+    let mut text = String::new();
+    for record in records {
+        text.push_str(&format_record(record));
+        text.push('\n');
+    }
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, text)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// The master file line for `record`, which `parse_zone_file` reads back unchanged.
+pub fn format_record(record: &DnsRecord) -> String {
+    let rd_data = &record.rd_data;
+    let name = |offset: usize| {
+        let (name, end) = deserialize_name(rd_data, offset).unwrap_or_default();
+        (format!("{name}."), end)
+    };
+    let u16_at = |offset: usize| utils::double_u8_to_u16(rd_data, offset);
+
+    let data = match record.dns_type {
+        // the generic form of RFC 3597, for what has no other or doesn't fit it
+        dns_type if !is_well_formed(dns_type, rd_data) => None,
+        DnsType::A => Some(Ipv4Addr::from(<[u8; 4]>::try_from(&rd_data[..]).unwrap()).to_string()),
+        DnsType::Aaaa => {
+            Some(Ipv6Addr::from(<[u8; 16]>::try_from(&rd_data[..]).unwrap()).to_string())
+        }
+        DnsType::NS
+        | DnsType::MD
+        | DnsType::MF
+        | DnsType::Cname
+        | DnsType::MB
+        | DnsType::MG
+        | DnsType::MR
+        | DnsType::Ptr => Some(name(0).0),
+        DnsType::MX => Some(format!("{} {}", u16_at(0), name(2).0)),
+        DnsType::Srv => Some(format!(
+            "{} {} {} {}",
+            u16_at(0),
+            u16_at(2),
+            u16_at(4),
+            name(6).0
+        )),
+        DnsType::Soa => {
+            let (mname, end) = name(0);
+            let (rname, end) = name(end);
+            let fields: Vec<String> = (0..5)
+                .map(|i| (utils::bytes_to_i32(rd_data, end + 4 * i) as u32).to_string())
+                .collect();
+            Some(format!("{mname} {rname} {}", fields.join(" ")))
+        }
+        DnsType::Txt | DnsType::Hinfo => {
+            // a sequence of length prefixed strings
+            let mut strings = Vec::new();
+            let mut index = 0;
+            while index < rd_data.len() {
+                let len = rd_data[index] as usize;
+                let Some(text) = rd_data.get(index + 1..index + 1 + len) else {
+                    break;
+                };
+                strings.push(format!("\"{}\"", escape(text)));
+                index += 1 + len;
+            }
+            Some(strings.join(" "))
+        }
+        _ => None,
+    };
+    let (dns_type, data) = match data {
+        Some(data) => (type_name(record.dns_type), data),
+        None => {
+            let mut data = format!("\\# {}", rd_data.len());
+            if !rd_data.is_empty() {
+                data.push(' ');
+                data.extend(rd_data.iter().map(|byte| format!("{byte:02x}")));
+            }
+            (format!("TYPE{}", u16::from(record.dns_type)), data)
+        }
+    };
+
+    format!(
+        "{}. {} {} {dns_type} {data}",
+        record.name,
+        record.time_to_live,
+        class_name(record.dns_class),
+    )
+}
+
+/// The mnemonic of `dns_class`, or the generic `CLASSnn` of RFC 3597 for
+/// those without one a master file can hold.
+fn class_name(dns_class: DnsClass) -> String {
+    match dns_class {
+        DnsClass::IN | DnsClass::CS | DnsClass::CH | DnsClass::HS => format!("{dns_class:?}"),
+        other => format!("CLASS{}", u16::from(other)),
+    }
+}
+
+/// The mnemonic of `dns_type`.
+pub fn type_name(dns_type: DnsType) -> String {
+    match dns_type {
+        DnsType::Cname => "CNAME".into(),
+        DnsType::Hinfo => "HINFO".into(),
+        DnsType::AllRecords => "ANY".into(),
+        DnsType::Unknown(value) => format!("TYPE{value}"),
+        other => format!("{other:?}").to_ascii_uppercase(),
+    }
+}
+
+/// The name `text` of a master file, resolved against `origin`. Escapes are
+/// rejected, names can't hold a `\.` within a label.
+fn parse_name(text: &str, origin: &str) -> Result<String, String> {
+    if text.contains('\\') {
+        return Err(format!("escapes in names aren't supported: {text}"));
+    }
+
+    let name = absolute_name(text, origin);
+    if !is_valid_name(&name) {
+        return Err(format!("name too long: {name}"));
+    }
+    Ok(name)
+}
+
+/// Resolves `@` and names without a trailing dot against `origin`.
+pub fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{name}.{origin}")
+    }
+}
+
+/// Parses a TTL in seconds or with BIND style units, e.g. `1h30m`.
+fn parse_ttl(text: &str) -> Result<i32, String> {
+    let invalid = || format!("invalid TTL {text}");
+
+    if let Ok(seconds) = text.parse::<u32>() {
+        return Ok(seconds as i32);
+    }
+
+    let mut total: u32 = 0;
+    let mut value: u32 = 0;
+    let mut has_digits = false;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(digit))
+                .ok_or_else(invalid)?;
+            has_digits = true;
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        if !has_digits {
+            return Err(invalid());
+        }
+        total = value
+            .checked_mul(unit)
+            .and_then(|v| v.checked_add(total))
+            .ok_or_else(invalid)?;
+        value = 0;
+        has_digits = false;
+    }
+    if has_digits {
+        return Err(invalid());
+    }
+
+    Ok(total as i32)
+}
+
+fn parse_class(text: &str) -> Option<DnsClass> {
+    match text.to_ascii_uppercase().as_str() {
+        "IN" => Some(DnsClass::IN),
+        "CS" => Some(DnsClass::CS),
+        "CH" => Some(DnsClass::CH),
+        "HS" => Some(DnsClass::HS),
+        other => other
+            .strip_prefix("CLASS")
+            .and_then(|value| value.parse::<u16>().ok())
+            .map(DnsClass::from),
+    }
+}
+
+pub fn parse_type(text: &str) -> Option<DnsType> {
+    let dns_type = match text.to_ascii_uppercase().as_str() {
+        "A" => DnsType::A,
+        "NS" => DnsType::NS,
+        "MD" => DnsType::MD,
+        "MF" => DnsType::MF,
+        "CNAME" => DnsType::Cname,
+        "SOA" => DnsType::Soa,
+        "MB" => DnsType::MB,
+        "MG" => DnsType::MG,
+        "MR" => DnsType::MR,
+        "PTR" => DnsType::Ptr,
+        "HINFO" => DnsType::Hinfo,
+        "MX" => DnsType::MX,
+        "TXT" => DnsType::Txt,
+        "AAAA" => DnsType::Aaaa,
+        "SRV" => DnsType::Srv,
+        other => {
+            let value = other.strip_prefix("TYPE")?.parse::<u16>().ok()?;
+            DnsType::from(value)
+        }
+    };
+
+    Some(dns_type)
+}
+
+fn normalize(origin: &str) -> String {
+    origin.trim_end_matches('.').to_string()
+}
+
+#[test]
+fn test_parse_zone() {
+    let text = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                7200       ; refresh
+                1800       ; retry
+                1209600    ; expire
+                300 )      ; minimum
+        IN  NS  ns1
+        IN  MX  10 mail.example.com.
+ns1     600 IN A 192.0.2.1
+mail    A   192.0.2.2
+        AAAA 2001:db8::2
+txt     TXT "hello world" "semi;colon"
+"#;
+
+    let records = parse_zone(text, "", Path::new(".")).unwrap();
+    assert_eq!(records.len(), 7);
+
+    let soa = &records[0];
+    assert_eq!(soa.name, "example.com");
+    assert_eq!(soa.dns_type, DnsType::Soa);
+    assert_eq!(soa.time_to_live, 3600);
+    let mut expected = serialize_name("ns1.example.com");
+    expected.extend(serialize_name("hostmaster.example.com"));
+    expected.extend(2024010101u32.to_be_bytes());
+    for value in [7200i32, 1800, 1209600, 300] {
+        expected.extend(value.to_be_bytes());
+    }
+    assert_eq!(soa.rd_data, expected);
+
+    assert_eq!(records[1].name, "example.com");
+    assert_eq!(records[1].rd_data, serialize_name("ns1.example.com"));
+    assert_eq!(records[3].name, "ns1.example.com");
+    assert_eq!(records[3].time_to_live, 600);
+    assert_eq!(records[3].rd_data, vec![192, 0, 2, 1]);
+    // blank owner inherits the previous one
+    assert_eq!(records[5].name, "mail.example.com");
+    assert_eq!(records[5].dns_type, DnsType::Aaaa);
+    assert_eq!(
+        records[6].rd_data,
+        b"\x0bhello world\x0asemi;colon".to_vec()
+    );
+}
+
+#[test]
+fn test_parse_zone_include() {
+    let dir = std::env::temp_dir().join(format!("zone-include-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("hosts.inc"), "www A 192.0.2.10\n").unwrap();
+    std::fs::write(
+        dir.join("example.zone"),
+        "$TTL 300\n$INCLUDE hosts.inc sub\napi A 192.0.2.20\n",
+    )
+    .unwrap();
+
+    let records = parse_zone_file(&dir.join("example.zone"), "example.com.").unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(records[0].name, "www.sub.example.com");
+    // the origin is restored after the include
+    assert_eq!(records[1].name, "api.example.com");
+
+    let error = parse_zone("www A 192.0.2.1\n", "example.com", Path::new(".")).unwrap_err();
+    assert!(error.to_string().contains("no TTL"), "{error}");
+}
+
+#[test]
+fn test_format_record() {
+    let text = r#"$TTL 300
+@       SOA ns1 hostmaster 2024010101 7200 1800 1209600 300
+        NS  ns1
+        MX  10 mail
+ns1     A   192.0.2.1
+        AAAA 2001:db8::1
+_sip._tcp SRV 0 5 5060 ns1
+txt     TXT "a \"quoted\" string" "two" "caf\233"
+"#;
+    let records = parse_zone(text, "example.com", Path::new(".")).unwrap();
+
+    assert_eq!(
+        format_record(&records[0]),
+        "example.com. 300 IN SOA ns1.example.com. hostmaster.example.com. 2024010101 7200 1800 1209600 300"
+    );
+    assert_eq!(
+        format_record(&records[2]),
+        "example.com. 300 IN MX 10 mail.example.com."
+    );
+
+    assert!(records[6].rd_data.ends_with(&[4, b'c', b'a', b'f', 233]));
+    assert_eq!(
+        format_record(&records[6]),
+        r#"txt.example.com. 300 IN TXT "a \"quoted\" string" "two" "caf\233""#
+    );
+
+    let formatted: Vec<String> = records.iter().map(format_record).collect();
+    let reparsed = parse_zone(&formatted.join("\n"), "", Path::new(".")).unwrap();
+    assert_eq!(reparsed, records);
+
+    let error = parse_zone("a\\.b 300 A 192.0.2.1\n", "example.com", Path::new(".")).unwrap_err();
+    assert!(error.to_string().contains("escapes in names"), "{error}");
+    let text = format!("{} 300 A 192.0.2.1\n", "a".repeat(64));
+    let error = parse_zone(&text, "example.com", Path::new(".")).unwrap_err();
+    assert!(error.to_string().contains("name too long"), "{error}");
+
+    // what has no form of its own is written and read back in the generic one
+    let record = |dns_type: DnsType, dns_class: DnsClass, rd_data: Vec<u8>| DnsRecord {
+        name: "x.example.com".into(),
+        dns_type,
+        dns_class,
+        time_to_live: 300,
+        rd_length: rd_data.len() as u16,
+        rd_data,
+    };
+    let mut minfo = serialize_name("a.example.com");
+    minfo.extend(serialize_name("b.example.com"));
+    let records = vec![
+        record(DnsType::Unknown(65280), DnsClass::IN, vec![0xde, 0xad]),
+        record(DnsType::Null, DnsClass::IN, vec![]),
+        record(DnsType::Minfo, DnsClass::IN, minfo),
+        record(DnsType::A, DnsClass::Unknown(65000), vec![192, 0, 2, 1]),
+    ];
+    assert_eq!(
+        format_record(&records[0]),
+        r"x.example.com. 300 IN TYPE65280 \# 2 dead"
+    );
+    let formatted: Vec<String> = records.iter().map(format_record).collect();
+    let reparsed = parse_zone(&formatted.join("\n"), "", Path::new(".")).unwrap();
+    assert_eq!(reparsed, records);
+
+    let error = parse_zone("x 300 A \\# 3 c00002\n", "example.com", Path::new(".")).unwrap_err();
+    assert!(error.to_string().contains("malformed A"), "{error}");
+}